@@ -0,0 +1,181 @@
+//! The app compose file describing what a CVM runs, shared by teepod, tdxctl and tappd.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// Manifest versions this crate understands
+pub const SUPPORTED_MANIFEST_VERSIONS: &[u32] = &[1, 2];
+
+/// Runners the guest knows how to start
+pub const SUPPORTED_RUNNERS: &[&str] = &["docker-compose"];
+
+/// Env names the host may not set through the plain env, since they change how the app is run.
+/// Teepod rejects them up front and the guest refuses them again.
+pub const RESERVED_ENV_KEYS: &[&str] = &[
+    "PATH",
+    "HOME",
+    "SHELL",
+    "LD_PRELOAD",
+    "LD_LIBRARY_PATH",
+    "LD_AUDIT",
+    "DOCKER_HOST",
+    "DOCKER_CONFIG",
+];
+
+/// The hex SHA-256 of an app compose file, as measured into the CVM.
+pub fn compose_hash(compose_file: &str) -> String {
+    hex::encode(Sha256::digest(compose_file.as_bytes()))
+}
+
+/// The app id of a compose file when none is given: the first 40 hex chars of its compose hash.
+pub fn app_id_of(compose_file: &str) -> String {
+    let mut app_id = compose_hash(compose_file);
+    app_id.truncate(40);
+    app_id
+}
+
+#[derive(Deserialize)]
+pub struct AppCompose {
+    pub manifest_version: u32,
+    pub name: String,
+    // Deprecated
+    #[serde(default)]
+    pub features: Vec<String>,
+    pub runner: String,
+    pub docker_compose_file: Option<String>,
+    #[serde(default)]
+    pub docker_config: DockerConfig,
+    #[serde(default)]
+    pub public_logs: bool,
+    #[serde(default)]
+    pub public_sysinfo: bool,
+    #[serde(default)]
+    pub kms_enabled: bool,
+    #[serde(default)]
+    pub tproxy_enabled: bool,
+    /// Accept the non-secret env vars given by the host. They are not authenticated, so the app
+    /// has to opt in, and this flag is covered by the measured compose hash.
+    #[serde(default)]
+    pub allow_plain_env: bool,
+    /// Step the guest clock forward to the launch time given by the host. It is not
+    /// authenticated either, so the app has to opt in.
+    #[serde(default)]
+    pub allow_host_time: bool,
+    /// The furthest, in seconds, the host time may step the guest clock forward. Default: 1 day
+    #[serde(default = "default_max_host_time_step")]
+    pub max_host_time_step: u64,
+}
+
+fn default_max_host_time_step() -> u64 {
+    24 * 60 * 60
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct DockerConfig {
+    /// The URL of the Docker registry.
+    pub registry: Option<String>,
+    /// The username of the registry account.
+    pub username: Option<String>,
+    /// The key of the encrypted environment variables for registry account token.
+    pub token_key: Option<String>,
+}
+
+impl AppCompose {
+    /// Parse and validate an app compose file.
+    pub fn parse(compose_file: &str) -> Result<Self> {
+        let app_compose: Self =
+            serde_json::from_str(compose_file).context("Invalid compose file")?;
+        app_compose.validate().context("Invalid compose file")?;
+        Ok(app_compose)
+    }
+
+    /// Check the fields serde can not: the manifest version, the runner and what it needs.
+    pub fn validate(&self) -> Result<()> {
+        if !SUPPORTED_MANIFEST_VERSIONS.contains(&self.manifest_version) {
+            bail!(
+                "unsupported manifest_version {}, expected one of {SUPPORTED_MANIFEST_VERSIONS:?}",
+                self.manifest_version
+            );
+        }
+        if !SUPPORTED_RUNNERS.contains(&self.runner.as_str()) {
+            bail!(
+                "unsupported runner {:?}, expected one of {SUPPORTED_RUNNERS:?}",
+                self.runner
+            );
+        }
+        if self.runner == "docker-compose"
+            && self
+                .docker_compose_file
+                .as_deref()
+                .map_or(true, |file| file.trim().is_empty())
+        {
+            bail!("docker_compose_file cannot be empty with the docker-compose runner");
+        }
+        Ok(())
+    }
+
+    fn feature_enabled(&self, feature: &str) -> bool {
+        self.features.contains(&feature.to_string())
+    }
+
+    pub fn tproxy_enabled(&self) -> bool {
+        self.tproxy_enabled || self.feature_enabled("tproxy-net")
+    }
+
+    pub fn kms_enabled(&self) -> bool {
+        self.kms_enabled || self.feature_enabled("kms")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compose(manifest_version: u32, runner: &str, docker_compose_file: &str) -> String {
+        serde_json::json!({
+            "manifest_version": manifest_version,
+            "name": "app",
+            "runner": runner,
+            "docker_compose_file": docker_compose_file,
+        })
+        .to_string()
+    }
+
+    fn error_of(compose_file: &str) -> String {
+        format!("{:#}", AppCompose::parse(compose_file).err().unwrap())
+    }
+
+    #[test]
+    fn test_parse_valid() {
+        let app_compose = AppCompose::parse(&compose(2, "docker-compose", "services: {}")).unwrap();
+        assert_eq!(app_compose.name, "app");
+        assert!(!app_compose.kms_enabled());
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert_eq!(
+            error_of(&compose(3, "docker-compose", "services: {}")),
+            "Invalid compose file: unsupported manifest_version 3, expected one of [1, 2]"
+        );
+        assert_eq!(
+            error_of(&compose(2, "bash", "services: {}")),
+            "Invalid compose file: unsupported runner \"bash\", expected one of [\"docker-compose\"]"
+        );
+        assert_eq!(
+            error_of(&compose(2, "docker-compose", " ")),
+            "Invalid compose file: docker_compose_file cannot be empty with the docker-compose runner"
+        );
+        assert!(error_of("{}").starts_with("Invalid compose file: missing field"));
+    }
+
+    #[test]
+    fn test_app_id_of() {
+        assert_eq!(
+            compose_hash("{}"),
+            "44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+        );
+        assert_eq!(app_id_of("{}"), "44136fa355b3678a1146ad16f7e8649e94fb4fc2");
+    }
+}
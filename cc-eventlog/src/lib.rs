@@ -4,10 +4,13 @@ use scale::Decode;
 use serde::{Deserialize, Serialize};
 use tcg::{TcgDigest, TcgEfiSpecIdEvent};
 
+pub use runtime::{runtime_event_log_config, set_runtime_event_log_config, RuntimeEventLogConfig};
+
 mod codecs;
+mod runtime;
 mod tcg;
 
-/// The path to the userspace TDX event log file.
+/// The default path to the userspace TDX event log file.
 pub const RUNTIME_EVENT_LOG_FILE: &str = "/run/log/tdx_mr3/tdx_events.log";
 /// The path to boottime ccel file.
 const CCEL_FILE: &str = "/sys/firmware/acpi/tables/data/CCEL";
@@ -252,32 +255,10 @@ fn parse_spec_id_event_log<I: scale::Input>(
     Ok((spec_id_header, spec_id_event))
 }
 
-fn read_runtime_event_logs() -> Result<Vec<TdxEventLog>> {
-    let data = match fs_err::read_to_string(RUNTIME_EVENT_LOG_FILE) {
-        Ok(data) => data,
-        Err(e) => {
-            if e.kind() == std::io::ErrorKind::NotFound {
-                return Ok(vec![]);
-            }
-            return Err(e).context("Failed to read user event log");
-        }
-    };
-    let mut event_logs = vec![];
-    for line in data.lines() {
-        if line.trim().is_empty() {
-            continue;
-        }
-        let event_log =
-            serde_json::from_str::<TdxEventLog>(line).context("Failed to decode user event log")?;
-        event_logs.push(event_log);
-    }
-    Ok(event_logs)
-}
-
 /// Read both boottime and runtime event logs.
 pub fn read_event_logs() -> Result<Vec<TdxEventLog>> {
     let mut event_logs = EventLogs::decode_from_ccel_file()?.to_tdx_event_logs()?;
-    event_logs.extend(read_runtime_event_logs()?);
+    event_logs.extend(runtime_event_log_config().read()?);
     Ok(event_logs)
 }
 
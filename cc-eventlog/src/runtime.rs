@@ -0,0 +1,227 @@
+//! Storage of the runtime (RTMR3) event log.
+//!
+//! The log is a file of JSON lines, one [`TdxEventLog`] per line. When rotation is enabled, a log
+//! file that would grow beyond `max_size` is renamed to `<path>.<n>` with `n` increasing from 1
+//! and a new file is started. Rotated segments are never deleted: RTMR3 can only be reproduced by
+//! replaying every event extended since boot, so concatenating the segments in order followed by
+//! the current file must always yield the complete log.
+
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::{bail, Context, Result};
+use fs_err as fs;
+
+use crate::{TdxEventLog, RUNTIME_EVENT_LOG_FILE};
+
+/// Location and rotation policy of the runtime event log.
+#[derive(Debug, Clone)]
+pub struct RuntimeEventLogConfig {
+    /// Path of the current log file
+    pub path: PathBuf,
+    /// Rotate the log file before it grows beyond this size in bytes. 0 disables rotation.
+    pub max_size: u64,
+}
+
+impl Default for RuntimeEventLogConfig {
+    fn default() -> Self {
+        Self {
+            path: RUNTIME_EVENT_LOG_FILE.into(),
+            max_size: 0,
+        }
+    }
+}
+
+static CONFIG: OnceLock<RuntimeEventLogConfig> = OnceLock::new();
+
+/// Set the process wide runtime event log config. Can only be called once, before any event log
+/// is read or written.
+pub fn set_runtime_event_log_config(config: RuntimeEventLogConfig) -> Result<()> {
+    if CONFIG.set(config).is_err() {
+        bail!("runtime event log config already set");
+    }
+    Ok(())
+}
+
+/// The process wide runtime event log config.
+pub fn runtime_event_log_config() -> &'static RuntimeEventLogConfig {
+    CONFIG.get_or_init(Default::default)
+}
+
+impl RuntimeEventLogConfig {
+    /// Rotated segments of the log, oldest first.
+    pub fn segments(&self) -> Result<Vec<PathBuf>> {
+        let Some(dir) = self.path.parent() else {
+            return Ok(vec![]);
+        };
+        let Some(name) = self.path.file_name().and_then(|n| n.to_str()) else {
+            bail!("invalid event log path: {}", self.path.display());
+        };
+        let prefix = format!("{name}.");
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e).context("Failed to read event log directory"),
+        };
+        let mut segments = vec![];
+        for entry in entries {
+            let entry = entry.context("Failed to read event log directory")?;
+            let file_name = entry.file_name();
+            let Some(index) = file_name
+                .to_str()
+                .and_then(|n| n.strip_prefix(&prefix))
+                .and_then(|n| n.parse::<u32>().ok())
+            else {
+                continue;
+            };
+            segments.push((index, entry.path()));
+        }
+        segments.sort();
+        Ok(segments.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// Read the complete log, including rotated segments.
+    pub fn read(&self) -> Result<Vec<TdxEventLog>> {
+        self.read_limited(u64::MAX)
+    }
+
+    /// Read the complete log like [`Self::read`], but fail instead of reading more than
+    /// `max_size` bytes from the files.
+    pub fn read_limited(&self, max_size: u64) -> Result<Vec<TdxEventLog>> {
+        let mut remaining = max_size;
+        let mut event_logs = vec![];
+        for path in self.segments()?.iter().chain([&self.path]) {
+            event_logs.extend(read_log_file(path, &mut remaining)?);
+        }
+        Ok(event_logs)
+    }
+
+    /// Append an event to the log, rotating the current file first if needed.
+    pub fn append(&self, log: &TdxEventLog) -> Result<()> {
+        let mut logline = serde_json::to_string(log).context("Failed to serialize event log")?;
+        logline.push('\n');
+
+        let logfile_dir = self
+            .path
+            .parent()
+            .context("Failed to get event log directory")?;
+        fs::create_dir_all(logfile_dir).context("Failed to create event log directory")?;
+
+        if self.max_size > 0 {
+            let size = match fs::metadata(&self.path) {
+                Ok(meta) => meta.len(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(e).context("Failed to stat event log file"),
+            };
+            if size > 0 && size + logline.len() as u64 > self.max_size {
+                self.rotate()?;
+            }
+        }
+
+        let mut logfile = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .context("Failed to open event log file")?;
+        logfile
+            .write_all(logline.as_bytes())
+            .context("Failed to write to event log file")?;
+        Ok(())
+    }
+
+    fn rotate(&self) -> Result<()> {
+        let next = match self.segments()?.last() {
+            Some(last) => {
+                let index = last
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .and_then(|ext| ext.parse::<u32>().ok())
+                    .context("Invalid event log segment")?;
+                index
+                    .checked_add(1)
+                    .context("Too many event log segments")?
+            }
+            None => 1,
+        };
+        let mut segment = self.path.clone().into_os_string();
+        segment.push(format!(".{next}"));
+        fs::rename(&self.path, &segment).context("Failed to rotate event log")?;
+        Ok(())
+    }
+}
+
+/// Read a log file of at most `remaining` bytes, and deduct its size from `remaining`.
+fn read_log_file(path: &Path, remaining: &mut u64) -> Result<Vec<TdxEventLog>> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                return Ok(vec![]);
+            }
+            return Err(e).context("Failed to read user event log");
+        }
+    };
+    let mut data = String::new();
+    // Read one byte past the limit to tell a file that fits exactly from a larger one
+    file.take(remaining.saturating_add(1))
+        .read_to_string(&mut data)
+        .context("Failed to read user event log")?;
+    *remaining = remaining
+        .checked_sub(data.len() as u64)
+        .context("event log exceeds the size limit")?;
+    let mut event_logs = vec![];
+    for line in data.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event_log =
+            serde_json::from_str::<TdxEventLog>(line).context("Failed to decode user event log")?;
+        event_logs.push(event_log);
+    }
+    Ok(event_logs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotated_log_replays_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = RuntimeEventLogConfig {
+            path: dir.path().join("events.log"),
+            max_size: 300,
+        };
+        let events = (0..20)
+            .map(|i| TdxEventLog::new_str(3, 1, "test", &i.to_string()))
+            .collect::<Vec<_>>();
+        for event in &events {
+            config.append(event).unwrap();
+        }
+        assert!(config.segments().unwrap().len() > 1);
+        let read = config.read().unwrap();
+        let payloads = |logs: &[TdxEventLog]| {
+            logs.iter()
+                .map(|l| l.event_payload.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(payloads(&read), payloads(&events));
+
+        let size: u64 = config
+            .segments()
+            .unwrap()
+            .iter()
+            .chain([&config.path])
+            .map(|path| fs::metadata(path).unwrap().len())
+            .sum();
+        assert_eq!(
+            payloads(&config.read_limited(size).unwrap()),
+            payloads(&events)
+        );
+        let err = config.read_limited(size - 1).unwrap_err();
+        assert_eq!(err.to_string(), "event log exceeds the size limit");
+    }
+}
@@ -1,12 +1,19 @@
-use std::{path::PathBuf, time::Duration};
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc, time::Duration};
 
-use anyhow::{Context, Result};
-use certbot::{CertBotConfig, WorkDir};
+use anyhow::{bail, Context, Result};
+use certbot::{
+    find_zone, read_cert_info, CertBot, CertBotConfig, ChallengeKind, EabCredentials, WorkDir,
+};
 use clap::Parser;
 use documented::DocumentedFields;
 use fs_err as fs;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
 use toml_edit::ser::to_document;
+use tracing::{info, warn};
 
 #[derive(Parser)]
 enum Command {
@@ -18,6 +25,10 @@ enum Command {
         /// Run only once and exit
         #[arg(long)]
         once: bool,
+        /// Check the configuration and whether a renewal is due, without issuing or writing
+        /// anything
+        #[arg(long)]
+        dry_run: bool,
     },
     /// Initialize the configuration file
     Init {
@@ -25,12 +36,18 @@ enum Command {
         #[arg(short, long, default_value = "certbot.toml")]
         config: PathBuf,
     },
-    /// Set CAA record for the domain
+    /// Set CAA records for the domains
     SetCaa {
         /// Path to the configuration file
         #[arg(short, long, default_value = "certbot.toml")]
         config: PathBuf,
     },
+    /// Show the subject and expiry of the current certificate. Fails if it is missing or expired
+    Status {
+        /// Path to the configuration file
+        #[arg(short, long, default_value = "certbot.toml")]
+        config: PathBuf,
+    },
     /// Generate configuration template
     Cfg {
         /// Write to file
@@ -51,20 +68,51 @@ struct Config {
     workdir: PathBuf,
     /// ACME server URL
     acme_url: String,
-    /// Cloudflare API token
+    /// Key ID for external account binding, required by some CAs. Set together with eab_hmac_key
+    #[serde(default)]
+    eab_kid: String,
+    /// Base64url encoded HMAC key for external account binding
+    #[serde(default)]
+    eab_hmac_key: String,
+    /// Cloudflare API token, or "file:<path>" / "env:<name>" to read it from a file or an environment variable
     cf_api_token: String,
     /// Cloudflare zone ID
     cf_zone_id: String,
+    /// Cloudflare zone IDs by domain suffix, for domains that are not in cf_zone_id
+    #[serde(default)]
+    zones: BTreeMap<String, String>,
+    /// Preferred challenge, "dns-01" or "http-01". With dns-01, domains without a Cloudflare zone fall back to http-01
+    #[serde(default)]
+    challenge: ChallengeKind,
+    /// Directory to write http-01 tokens to, relative to workdir. Defaults to workdir/webroot
+    #[serde(default)]
+    webroot: PathBuf,
     /// Auto set CAA record
     auto_set_caa: bool,
-    /// Domain to issue certificates for
-    domain: String,
+    /// Domains to issue the certificate for, all put into its subject alt names
+    #[serde(alias = "domain", deserialize_with = "string_or_list")]
+    domains: Vec<String>,
     /// Renew interval in seconds
     renew_interval: u64,
     /// Number of days before expiration to trigger renewal
     renew_days_before: u64,
     /// Renew timeout in seconds
     renew_timeout: u64,
+    /// Renew up to this many days earlier than renew_days_before, picked per domain to spread renewals
+    #[serde(default)]
+    renew_days_jitter: u64,
+    /// Back off from renewing if a newly issued certificate is valid for fewer days than this, 0 to use renew_days_before
+    #[serde(default)]
+    min_issued_validity_days: u64,
+    /// URL to POST {"domain", "not_after", "serial"} to after a renewal, empty to disable
+    #[serde(default)]
+    renew_hook_url: String,
+    /// Number of renewed certificates to keep in workdir/backup, 0 to keep all
+    #[serde(default)]
+    max_backups: usize,
+    /// Address to serve Prometheus metrics on while renewing, e.g. "127.0.0.1:9100", empty to disable
+    #[serde(default)]
+    metrics_listen: String,
 }
 
 impl Default for Config {
@@ -72,17 +120,41 @@ impl Default for Config {
         Self {
             workdir: ".".into(),
             acme_url: "https://acme-staging-v02.api.letsencrypt.org/directory".into(),
+            eab_kid: "".into(),
+            eab_hmac_key: "".into(),
             cf_api_token: "".into(),
             cf_zone_id: "".into(),
+            zones: BTreeMap::new(),
+            challenge: ChallengeKind::Dns01,
+            webroot: "".into(),
             auto_set_caa: true,
-            domain: "example.com".into(),
+            domains: vec!["example.com".into(), "www.example.com".into()],
             renew_interval: 3600,
             renew_days_before: 10,
             renew_timeout: 120,
+            renew_days_jitter: 0,
+            min_issued_validity_days: 0,
+            renew_hook_url: "".into(),
+            max_backups: 0,
+            metrics_listen: "".into(),
         }
     }
 }
 
+/// Accept a bare string for configs written when only a single domain was supported.
+fn string_or_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+    Ok(match StringOrList::deserialize(deserializer)? {
+        StringOrList::String(domain) => vec![domain],
+        StringOrList::List(domains) => domains,
+    })
+}
+
 impl Config {
     fn to_commented_toml(&self) -> Result<String> {
         let mut doc = to_document(self)?;
@@ -106,24 +178,83 @@ impl Config {
     }
 }
 
+/// Resolve a `file:<path>` or `env:<name>` reference. Other values are taken literally.
+fn resolve_secret(value: &str) -> Result<String> {
+    if let Some(path) = value.strip_prefix("file:") {
+        let secret = fs::read_to_string(path).context("Failed to read secret file")?;
+        return Ok(secret.trim_end().to_string());
+    }
+    if let Some(name) = value.strip_prefix("env:") {
+        return std::env::var(name)
+            .with_context(|| format!("Environment variable {name} is not set"));
+    }
+    Ok(value.to_string())
+}
+
+fn read_config(config: &PathBuf) -> Result<Config> {
+    Ok(toml_edit::de::from_str(&fs::read_to_string(config)?)?)
+}
+
 fn load_config(config: &PathBuf) -> Result<CertBotConfig> {
-    let config: Config = toml_edit::de::from_str(&fs::read_to_string(config)?)?;
+    let config = read_config(config)?;
+    if config.domains.is_empty() {
+        bail!("no domains configured");
+    }
+    for domain in &config.domains {
+        if find_zone(&config.zones, domain).is_some() {
+            continue;
+        }
+        if !config.cf_zone_id.is_empty() {
+            if !config.zones.is_empty() {
+                warn!("no zone configured for {domain}, falling back to cf_zone_id");
+            }
+        } else if config.challenge == ChallengeKind::Dns01 {
+            warn!("no zone configured for {domain}, validating it with http-01");
+        }
+    }
+    let cf_api_token = resolve_secret(&config.cf_api_token).context("Invalid cf_api_token")?;
     let workdir = WorkDir::new(&config.workdir);
+    let webroot = if config.webroot.as_os_str().is_empty() {
+        workdir.webroot_dir()
+    } else {
+        workdir.workdir().join(&config.webroot)
+    };
     let renew_interval = Duration::from_secs(config.renew_interval);
     let renew_expires_in = Duration::from_secs(config.renew_days_before * 24 * 60 * 60);
     let renew_timeout = Duration::from_secs(config.renew_timeout);
+    let renew_expires_in_jitter = Duration::from_secs(config.renew_days_jitter * 24 * 60 * 60);
+    let min_issued_validity = (config.min_issued_validity_days > 0)
+        .then(|| Duration::from_secs(config.min_issued_validity_days * 24 * 60 * 60));
+    let eab = match (config.eab_kid.is_empty(), config.eab_hmac_key.is_empty()) {
+        (true, true) => None,
+        (false, false) => Some(EabCredentials {
+            kid: config.eab_kid,
+            hmac_key: config.eab_hmac_key,
+        }),
+        _ => bail!("eab_kid and eab_hmac_key must be set together"),
+    };
+    let renew_hook_url = (!config.renew_hook_url.is_empty()).then_some(config.renew_hook_url);
+    let max_backups = (config.max_backups > 0).then_some(config.max_backups);
     let bot_config = CertBotConfig::builder()
         .acme_url(config.acme_url)
         .cert_dir(workdir.backup_dir())
         .cert_file(workdir.cert_path())
         .key_file(workdir.key_path())
         .auto_create_account(true)
-        .cert_subject_alt_names(vec![config.domain])
+        .cert_subject_alt_names(config.domains)
         .cf_zone_id(config.cf_zone_id)
-        .cf_api_token(config.cf_api_token)
+        .cf_zones(config.zones)
+        .cf_api_token(cf_api_token)
+        .challenge(config.challenge)
+        .http01_webroot(webroot)
         .renew_interval(renew_interval)
         .renew_timeout(renew_timeout)
         .renew_expires_in(renew_expires_in)
+        .renew_expires_in_jitter(renew_expires_in_jitter)
+        .maybe_min_issued_validity(min_issued_validity)
+        .maybe_renew_hook_url(renew_hook_url)
+        .maybe_max_backups(max_backups)
+        .maybe_eab(eab)
         .credentials_file(workdir.account_credentials_path())
         .auto_set_caa(config.auto_set_caa)
         .build();
@@ -131,11 +262,18 @@ fn load_config(config: &PathBuf) -> Result<CertBotConfig> {
 }
 
 async fn renew(config: &PathBuf, once: bool) -> Result<()> {
+    let metrics_listen = read_config(config)
+        .context("Failed to load configuration")?
+        .metrics_listen;
     let bot_config = load_config(config).context("Failed to load configuration")?;
     let bot = bot_config
         .build_bot()
         .await
         .context("Failed to build bot")?;
+    let bot = Arc::new(bot);
+    if !once && !metrics_listen.is_empty() {
+        serve_metrics(&metrics_listen, bot.clone()).await?;
+    }
     if once {
         bot.run_once().await?;
     } else {
@@ -144,6 +282,87 @@ async fn renew(config: &PathBuf, once: bool) -> Result<()> {
     Ok(())
 }
 
+async fn renew_dry_run(config: &PathBuf) -> Result<()> {
+    let bot_config = load_config(config).context("Failed to load configuration")?;
+    let checks = bot_config.dry_run().await;
+    for check in &checks {
+        match &check.result {
+            Ok(summary) => println!("[ok]   {}: {summary}", check.name),
+            Err(err) => println!("[fail] {}: {err:#}", check.name),
+        }
+    }
+    let failed = checks.iter().filter(|check| check.result.is_err()).count();
+    if failed > 0 {
+        bail!("{failed} of {} checks failed", checks.len());
+    }
+    Ok(())
+}
+
+/// Answer every HTTP request on `listen` with the bot metrics, whatever the path.
+async fn serve_metrics(listen: &str, bot: Arc<CertBot>) -> Result<()> {
+    const ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
+    const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+    let listener = TcpListener::bind(listen)
+        .await
+        .context("Failed to bind metrics address")?;
+    info!("serving metrics on {listen}");
+    tokio::spawn(async move {
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    // Errors such as running out of fds persist for a while, don't spin on them
+                    warn!("failed to accept metrics connection: {err}");
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            let body = bot.metrics();
+            tokio::spawn(async move {
+                let serve = async {
+                    let mut request = [0u8; 1024];
+                    let _ = stream.read(&mut request).await;
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\n\
+                         Content-Type: text/plain; version=0.0.4\r\n\
+                         Content-Length: {}\r\n\
+                         Connection: close\r\n\r\n{body}",
+                        body.len()
+                    );
+                    stream.write_all(response.as_bytes()).await
+                };
+                match tokio::time::timeout(CONNECTION_TIMEOUT, serve).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => warn!("failed to write metrics: {err}"),
+                    Err(_) => warn!("metrics connection timed out"),
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn status(config: &PathBuf) -> Result<()> {
+    let config = read_config(config).context("Failed to load configuration")?;
+    let cert_path = WorkDir::new(&config.workdir).cert_path();
+    let cert_pem = fs::read_to_string(&cert_path).context("Failed to read certificate")?;
+    let info = read_cert_info(&cert_pem).context("Failed to parse certificate")?;
+    println!("Certificate: {}", cert_path.display());
+    println!("Subject:     {}", info.subject);
+    println!("SANs:        {}", info.subject_alt_names.join(", "));
+    println!("Issuer:      {}", info.issuer);
+    println!("Not before:  {}", info.not_before);
+    println!("Not after:   {}", info.not_after);
+    println!(
+        "Expires in:  {} days",
+        info.expires_in_secs / (24 * 60 * 60)
+    );
+    if info.expires_in_secs <= 0 {
+        bail!("certificate has expired");
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     {
@@ -157,9 +376,20 @@ async fn main() -> Result<()> {
 
     let args = Args::parse();
     match args.command {
-        Command::Renew { config, once } => {
+        Command::Renew {
+            config,
+            once,
+            dry_run: false,
+        } => {
             renew(&config, once).await?;
         }
+        Command::Renew {
+            config,
+            dry_run: true,
+            ..
+        } => {
+            renew_dry_run(&config).await?;
+        }
         Command::Init { config } => {
             let config = load_config(&config).context("Failed to load configuration")?;
             // The build_bot() will trigger the initialization and create Account if not exists
@@ -173,6 +403,9 @@ async fn main() -> Result<()> {
                 .context("Failed to build bot")?;
             bot.set_caa().await?;
         }
+        Command::Status { config } => {
+            status(&config)?;
+        }
         Command::Cfg { write_to } => {
             let toml_str = Config::default().to_commented_toml()?;
             match write_to {
@@ -1,9 +1,10 @@
 use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
 use fs_err as fs;
 use hickory_resolver::error::ResolveErrorKind;
 use instant_acme::{
-    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount,
-    NewOrder, Order, OrderStatus,
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, ExternalAccountKey,
+    Identifier, NewAccount, NewOrder, Order, OrderStatus,
 };
 use rcgen::{CertificateParams, DistinguishedName, KeyPair};
 use serde::{Deserialize, Serialize};
@@ -23,14 +24,56 @@ pub struct AcmeClient {
     account: Account,
     credentials: Credentials,
     dns01_client: Dns01Client,
+    challenge: ChallengeKind,
+    http01_webroot: Option<PathBuf>,
+}
+
+/// The ACME challenge used to prove control over the domains.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChallengeKind {
+    /// Publish a TXT record through the DNS API. Domains outside of the zones managed by the
+    /// DNS client fall back to HTTP-01 if a webroot is configured.
+    #[default]
+    #[serde(rename = "dns-01")]
+    Dns01,
+    /// Serve the key authorization from the webroot, for all domains
+    #[serde(rename = "http-01")]
+    Http01,
 }
 
 #[derive(Debug, Clone)]
 struct Challenge {
-    id: String,
-    acme_domain: String,
     url: String,
-    dns_value: String,
+    proof: Proof,
+}
+
+#[derive(Debug, Clone)]
+enum Proof {
+    /// A TXT record at `acme_domain`
+    Dns {
+        id: String,
+        acme_domain: String,
+        dns_value: String,
+    },
+    /// A token file in the webroot
+    Http { path: PathBuf },
+}
+
+/// External Account Binding credentials handed out by CAs that require accounts to be linked
+/// to an existing customer account.
+#[derive(Clone)]
+pub struct EabCredentials {
+    pub kid: String,
+    /// The HMAC key, base64url encoded as issued by the CA
+    pub hmac_key: String,
+}
+
+impl std::fmt::Debug for EabCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EabCredentials")
+            .field("kid", &self.kid)
+            .finish_non_exhaustive()
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -48,11 +91,34 @@ impl AcmeClient {
             account,
             dns01_client,
             credentials,
+            challenge: ChallengeKind::Dns01,
+            http01_webroot: None,
         })
     }
 
-    /// Create a new account.
-    pub async fn new_account(acme_url: &str, dns01_client: Dns01Client) -> Result<Self> {
+    /// Create a new account, bound to an external account if `eab` is given.
+    pub async fn new_account(
+        acme_url: &str,
+        dns01_client: Dns01Client,
+        eab: Option<&EabCredentials>,
+    ) -> Result<Self> {
+        let external_account = match eab {
+            Some(eab) => {
+                let key = URL_SAFE_NO_PAD
+                    .decode(eab.hmac_key.trim_end_matches('='))
+                    .context("invalid EAB HMAC key")?;
+                Some(ExternalAccountKey::new(eab.kid.clone(), &key))
+            }
+            None => {
+                if external_account_required(acme_url).await? {
+                    bail!(
+                        "the ACME server requires external account binding, \
+                         but no EAB credentials are configured"
+                    );
+                }
+                None
+            }
+        };
         let (account, credentials) = Account::create(
             &NewAccount {
                 contact: &[],
@@ -60,7 +126,7 @@ impl AcmeClient {
                 only_return_existing: false,
             },
             acme_url,
-            None,
+            external_account.as_ref(),
         )
         .await
         .context("failed to create new account")?;
@@ -72,9 +138,21 @@ impl AcmeClient {
             account,
             dns01_client,
             credentials,
+            challenge: ChallengeKind::Dns01,
+            http01_webroot: None,
         })
     }
 
+    /// Select the challenge type. HTTP-01 tokens are written to `webroot`, whose
+    /// `.well-known/acme-challenge` directory must be served on port 80 of the domains.
+    pub fn with_challenge(self, challenge: ChallengeKind, webroot: Option<PathBuf>) -> Self {
+        Self {
+            challenge,
+            http01_webroot: webroot,
+            ..self
+        }
+    }
+
     /// Dump the account credentials to a JSON string.
     pub fn dump_credentials(&self) -> Result<String> {
         Ok(serde_json::to_string(&self.credentials)?)
@@ -87,10 +165,16 @@ impl AcmeClient {
 
     pub async fn set_caa_records(&self, domains: &[String]) -> Result<()> {
         let account_id = self.account_id();
-        let content = format!("letsencrypt.org;validationmethods=dns-01;accounturi={account_id}");
+        let method = match self.challenge {
+            ChallengeKind::Dns01 => "dns-01",
+            ChallengeKind::Http01 => "http-01",
+        };
+        let content = format!("letsencrypt.org;validationmethods={method};accounturi={account_id}");
+        // Records can only be set in the zones we manage
         let base_names = domains
             .iter()
             .map(|name| name.strip_prefix("*.").unwrap_or(name))
+            .filter(|name| self.dns01_client.manages_domain(name))
             .collect::<BTreeSet<_>>();
 
         for base_name in base_names {
@@ -143,9 +227,19 @@ impl AcmeClient {
             .request_new_certificate_inner(key, domains, &mut challenges)
             .await;
         for challenge in &challenges {
-            debug!("removing dns record {}", challenge.id);
-            if let Err(err) = self.dns01_client.remove_record(&challenge.id).await {
-                error!("failed to remove dns record {}: {err}", challenge.id);
+            match &challenge.proof {
+                Proof::Dns { id, .. } => {
+                    debug!("removing dns record {id}");
+                    if let Err(err) = self.dns01_client.remove_record(id).await {
+                        error!("failed to remove dns record {id}: {err}");
+                    }
+                }
+                Proof::Http { path } => {
+                    debug!("removing http-01 token {}", path.display());
+                    if let Err(err) = fs::remove_file(path) {
+                        error!("failed to remove http-01 token: {err}");
+                    }
+                }
             }
         }
         result
@@ -280,14 +374,36 @@ impl AcmeClient {
                 _ => bail!("unsupported authorization status: {:?}", authz.status),
             }
 
+            let Identifier::Dns(identifier) = &authz.identifier;
+
+            if self.use_http01(identifier) {
+                let challenge = authz
+                    .challenges
+                    .iter()
+                    .find(|c| c.r#type == ChallengeType::Http01)
+                    .with_context(|| format!("no http01 challenge found for {identifier}"))?;
+                let webroot = self
+                    .http01_webroot
+                    .as_ref()
+                    .context("no webroot configured for http-01")?;
+                debug!("writing http-01 token for {identifier}");
+                let key_authorization = order.key_authorization(challenge);
+                let path =
+                    write_http01_token(webroot, &challenge.token, key_authorization.as_str())
+                        .context("failed to write http-01 token")?;
+                challenges.push(Challenge {
+                    url: challenge.url.clone(),
+                    proof: Proof::Http { path },
+                });
+                continue;
+            }
+
             let challenge = authz
                 .challenges
                 .iter()
                 .find(|c| c.r#type == ChallengeType::Dns01)
                 .context("no dns01 challenge found")?;
 
-            let Identifier::Dns(identifier) = &authz.identifier;
-
             let dns_value = order.key_authorization(challenge).dns_value();
             debug!("creating dns record for {}", identifier);
             let acme_domain = format!("_acme-challenge.{identifier}");
@@ -301,21 +417,46 @@ impl AcmeClient {
                 .await
                 .context("failed to create dns record")?;
             challenges.push(Challenge {
-                id,
-                acme_domain,
                 url: challenge.url.clone(),
-                dns_value,
+                proof: Proof::Dns {
+                    id,
+                    acme_domain,
+                    dns_value,
+                },
             });
         }
         Ok(())
     }
 
+    /// Whether `domain` is validated with HTTP-01 rather than DNS-01.
+    fn use_http01(&self, domain: &str) -> bool {
+        match self.challenge {
+            ChallengeKind::Http01 => true,
+            ChallengeKind::Dns01 => {
+                self.http01_webroot.is_some() && !self.dns01_client.manages_domain(domain)
+            }
+        }
+    }
+
     /// Self check the TXT records for the given challenges.
     async fn check_dns(&self, challenges: &[Challenge]) -> Result<()> {
         let mut delay = Duration::from_millis(250);
         let mut tries = 1u8;
 
-        let mut unsettled_challenges = challenges.to_vec();
+        let mut unsettled_challenges = challenges
+            .iter()
+            .filter_map(|challenge| match &challenge.proof {
+                Proof::Dns {
+                    acme_domain,
+                    dns_value,
+                    ..
+                } => Some((acme_domain.clone(), dns_value.clone())),
+                Proof::Http { .. } => None,
+            })
+            .collect::<Vec<_>>();
+        if unsettled_challenges.is_empty() {
+            return Ok(());
+        }
 
         'outer: loop {
             use hickory_resolver::AsyncResolver;
@@ -325,17 +466,12 @@ impl AcmeClient {
             let dns_resolver =
                 AsyncResolver::tokio_from_system_conf().context("failed to create dns resolver")?;
 
-            while let Some(challenge) = unsettled_challenges.pop() {
-                let settled = match dns_resolver.txt_lookup(&challenge.acme_domain).await {
-                    Ok(record) => record
-                        .iter()
-                        .any(|txt| txt.to_string() == challenge.dns_value),
+            while let Some((acme_domain, dns_value)) = unsettled_challenges.pop() {
+                let settled = match dns_resolver.txt_lookup(&acme_domain).await {
+                    Ok(record) => record.iter().any(|txt| txt.to_string() == dns_value),
                     Err(err) => {
                         let ResolveErrorKind::NoRecordsFound { .. } = err.kind() else {
-                            bail!(
-                                "failed to lookup dns record {}: {err}",
-                                challenge.acme_domain
-                            );
+                            bail!("failed to lookup dns record {acme_domain}: {err}");
                         };
                         false
                     }
@@ -346,13 +482,13 @@ impl AcmeClient {
                     if tries < 10 {
                         debug!(
                             tries,
-                            domain = &challenge.acme_domain,
+                            domain = &acme_domain,
                             "challenge not found, waiting {delay:?}"
                         );
                     } else {
                         bail!("dns record not found");
                     }
-                    unsettled_challenges.push(challenge);
+                    unsettled_challenges.push((acme_domain, dns_value));
                     continue 'outer;
                 }
             }
@@ -479,13 +615,85 @@ async fn extract_certificate(mut order: Order) -> Result<String> {
 }
 
 fn need_renew(cert_pem: &str, expires_in: Duration) -> Result<bool> {
+    let remaining = remaining_validity(cert_pem)?;
+    debug!("will expire in {remaining:?}");
+    Ok(remaining < expires_in)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    #[allow(dead_code)]
+    new_order: String,
+    meta: Option<DirectoryMeta>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DirectoryMeta {
+    #[serde(default)]
+    external_account_required: bool,
+}
+
+async fn fetch_directory(acme_url: &str) -> Result<Directory> {
+    reqwest::get(acme_url)
+        .await
+        .context("failed to fetch ACME directory")?
+        .error_for_status()
+        .context("failed to fetch ACME directory")?
+        .json()
+        .await
+        .context("invalid ACME directory")
+}
+
+/// Check that `acme_url` serves an ACME directory.
+pub(crate) async fn check_directory(acme_url: &str) -> Result<()> {
+    fetch_directory(acme_url).await.map(|_| ())
+}
+
+/// Whether the ACME directory at `acme_url` demands external account binding.
+async fn external_account_required(acme_url: &str) -> Result<bool> {
+    let directory = fetch_directory(acme_url).await?;
+    Ok(directory
+        .meta
+        .is_some_and(|meta| meta.external_account_required))
+}
+
+/// How long until the certificate expires, zero if it already has.
+pub(crate) fn remaining_validity(cert_pem: &str) -> Result<Duration> {
     let pem = read_pem(cert_pem)?;
     let cert = pem.parse_x509().context("Invalid x509 certificate")?;
     let not_after = cert.validity().not_after.to_datetime();
     let now = time::OffsetDateTime::now_utc();
-    debug!("will expire in {:?}", not_after - now);
+    Ok((not_after - now).try_into().unwrap_or_default())
+}
 
-    Ok(not_after < now + expires_in)
+/// The fields of a certificate that operators look at.
+#[derive(Debug, Clone)]
+pub struct CertInfo {
+    pub subject: String,
+    pub issuer: String,
+    pub subject_alt_names: Vec<String>,
+    pub not_before: String,
+    pub not_after: String,
+    /// Seconds until the certificate expires, negative if it already has
+    pub expires_in_secs: i64,
+}
+
+/// Parse the leaf certificate of a PEM chain.
+pub fn read_cert_info(cert_pem: &str) -> Result<CertInfo> {
+    let pem = read_pem(cert_pem)?;
+    let cert = pem.parse_x509().context("Invalid x509 certificate")?;
+    let validity = cert.validity();
+    let now = time::OffsetDateTime::now_utc();
+    Ok(CertInfo {
+        subject: cert.subject().to_string(),
+        issuer: cert.issuer().to_string(),
+        subject_alt_names: extract_subject_alt_names(cert_pem).unwrap_or_default(),
+        not_before: validity.not_before.to_string(),
+        not_after: validity.not_after.to_string(),
+        expires_in_secs: (validity.not_after.to_datetime() - now).whole_seconds(),
+    })
 }
 
 pub(crate) fn read_pem(cert_pem: &str) -> Result<Pem> {
@@ -515,6 +723,23 @@ fn extract_subject_alt_names(cert_pem: &str) -> Result<Vec<String>> {
     Ok(domains)
 }
 
+/// Write the HTTP-01 response for `token` to `{webroot}/.well-known/acme-challenge/{token}`.
+fn write_http01_token(webroot: &Path, token: &str, key_authorization: &str) -> Result<PathBuf> {
+    // Tokens are base64url, anything else must not end up in a path
+    let valid = !token.is_empty()
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        bail!("invalid http-01 token: {token:?}");
+    }
+    let dir = webroot.join(".well-known").join("acme-challenge");
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(token);
+    fs::write(&path, key_authorization)?;
+    Ok(path)
+}
+
 fn ln_force(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
     if dst.as_ref().exists() {
         fs::remove_file(dst.as_ref())?;
@@ -1,31 +1,46 @@
-#![cfg(not(test))]
-
 use super::*;
 
-async fn new_acme_client() -> Result<AcmeClient> {
-    let dns01_client = Dns01Client::new_cloudflare(
-        std::env::var("CLOUDFLARE_ZONE_ID").expect("CLOUDFLARE_ZONE_ID not set"),
-        std::env::var("CLOUDFLARE_API_TOKEN").expect("CLOUDFLARE_API_TOKEN not set"),
-    );
-    let credentials =
-        std::env::var("LETSENCRYPT_CREDENTIAL").expect("LETSENCRYPT_CREDENTIAL not set");
-    AcmeClient::load(dns01_client, &credentials).await
+#[test]
+fn test_write_http01_token() {
+    let tmp = tempfile::tempdir().unwrap();
+    let webroot = tmp.path();
+    let path = write_http01_token(webroot, "tok-EN_123", "tok-EN_123.thumbprint").unwrap();
+    assert_eq!(path, webroot.join(".well-known/acme-challenge/tok-EN_123"));
+    assert_eq!(fs::read_to_string(&path).unwrap(), "tok-EN_123.thumbprint");
+    assert!(write_http01_token(webroot, "../escape", "x").is_err());
+    assert!(write_http01_token(webroot, "", "x").is_err());
 }
 
-#[tokio::test]
-async fn test_request_new_certificate() {
-    tracing_subscriber::fmt::try_init().ok();
+// Needs Cloudflare and Let's Encrypt credentials
+#[cfg(not(test))]
+mod live {
+    use super::*;
+
+    async fn new_acme_client() -> Result<AcmeClient> {
+        let dns01_client = Dns01Client::new_cloudflare(
+            std::env::var("CLOUDFLARE_ZONE_ID").expect("CLOUDFLARE_ZONE_ID not set"),
+            std::env::var("CLOUDFLARE_API_TOKEN").expect("CLOUDFLARE_API_TOKEN not set"),
+        );
+        let credentials =
+            std::env::var("LETSENCRYPT_CREDENTIAL").expect("LETSENCRYPT_CREDENTIAL not set");
+        AcmeClient::load(dns01_client, &credentials).await
+    }
+
+    #[tokio::test]
+    async fn test_request_new_certificate() {
+        tracing_subscriber::fmt::try_init().ok();
 
-    let test_domain = std::env::var("TEST_DOMAIN").expect("TEST_DOMAIN not set");
-    let domains = vec![test_domain.clone(), format!("*.{}", test_domain)];
-    let bot = new_acme_client().await.unwrap();
-    println!("account credentials: {}", bot.dump_credentials().unwrap());
-    let key = KeyPair::generate().unwrap();
-    let key_pem = key.serialize_pem();
-    let cert = bot
-        .request_new_certificate(&key_pem, &domains)
-        .await
-        .expect("Failed to get cert");
-    println!("key:\n{}", key_pem);
-    println!("cert:\n{}", cert);
+        let test_domain = std::env::var("TEST_DOMAIN").expect("TEST_DOMAIN not set");
+        let domains = vec![test_domain.clone(), format!("*.{}", test_domain)];
+        let bot = new_acme_client().await.unwrap();
+        println!("account credentials: {}", bot.dump_credentials().unwrap());
+        let key = KeyPair::generate().unwrap();
+        let key_pem = key.serialize_pem();
+        let cert = bot
+            .request_new_certificate(&key_pem, &domains)
+            .await
+            .expect("Failed to get cert");
+        println!("key:\n{}", key_pem);
+        println!("cert:\n{}", cert);
+    }
 }
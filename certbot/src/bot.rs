@@ -1,16 +1,24 @@
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet},
     io::ErrorKind,
     path::{Path, PathBuf},
-    time::Duration,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::{Context, Result};
 use fs_err as fs;
+use sha2::{Digest, Sha256};
 use tokio::time::sleep;
 use tracing::{error, info};
 
-use crate::acme_client::read_pem;
+use crate::acme_client::{
+    check_directory, read_pem, remaining_validity, ChallengeKind, Credentials, EabCredentials,
+};
+use crate::dns01_client::Dns01Api;
 
 use super::{AcmeClient, Dns01Client};
 
@@ -24,7 +32,16 @@ pub struct CertBotConfig {
     credentials_file: PathBuf,
     auto_create_account: bool,
     cf_zone_id: String,
+    /// Cloudflare zone IDs by domain suffix, for domains that are not in `cf_zone_id`
+    #[builder(default)]
+    cf_zones: BTreeMap<String, String>,
     cf_api_token: String,
+    /// The preferred challenge type. With DNS-01, domains outside of the Cloudflare zones are
+    /// validated with HTTP-01 if `http01_webroot` is set.
+    #[builder(default)]
+    challenge: ChallengeKind,
+    /// Directory to write HTTP-01 tokens to
+    http01_webroot: Option<PathBuf>,
     cert_file: PathBuf,
     key_file: PathBuf,
     cert_dir: PathBuf,
@@ -32,34 +49,151 @@ pub struct CertBotConfig {
     renew_interval: Duration,
     renew_timeout: Duration,
     renew_expires_in: Duration,
+    /// Renew up to this much earlier than `renew_expires_in`. The actual offset is derived from
+    /// the domain names, so that bots managing different domains renew on different days.
+    #[builder(default)]
+    renew_expires_in_jitter: Duration,
+    /// Minimum validity a newly issued certificate must have left. If the CA returns a shorter
+    /// lived one, renewals back off instead of retrying on every check. Defaults to the renewal
+    /// threshold, below which the new certificate would be renewed again right away.
+    min_issued_validity: Option<Duration>,
+    /// External account binding, for CAs that require it on account creation
+    eab: Option<EabCredentials>,
+    /// URL to POST the domain, expiry (unix time) and serial of a renewed certificate to
+    renew_hook_url: Option<String>,
+    /// Number of certificates to keep in `cert_dir`, unlimited if not set
+    max_backups: Option<usize>,
 }
 
 impl CertBotConfig {
     pub async fn build_bot(&self) -> Result<CertBot> {
         CertBot::build(self.clone()).await
     }
+
+    fn dns01_client(&self) -> Dns01Client {
+        Dns01Client::new_cloudflare_with_zones(
+            self.cf_zone_id.clone(),
+            self.cf_zones.clone(),
+            self.cf_api_token.clone(),
+        )
+    }
+
+    /// The time before expiration at which the certificate is renewed, with jitter applied.
+    fn renew_expires_in(&self) -> Duration {
+        let jitter = self.renew_expires_in_jitter;
+        if jitter.is_zero() {
+            return self.renew_expires_in;
+        }
+        // A stable hash, so the offset of a domain does not move between builds
+        let mut hasher = Sha256::new();
+        for name in &self.cert_subject_alt_names {
+            hasher.update(name);
+            hasher.update([0]);
+        }
+        let digest = hasher.finalize();
+        let seed = u64::from_be_bytes(digest[..8].try_into().expect("digest is 32 bytes"));
+        let offset = seed % (jitter.as_secs() + 1);
+        self.renew_expires_in + Duration::from_secs(offset)
+    }
+
+    /// Check the configuration against the ACME server and the DNS provider, and whether the
+    /// current certificate is due for renewal. Nothing is issued or written.
+    pub async fn dry_run(&self) -> Vec<DryRunCheck> {
+        let acme_directory = check_directory(&self.acme_url)
+            .await
+            .map(|_| format!("{} is reachable", self.acme_url));
+        let account = match fs::read_to_string(&self.credentials_file) {
+            Ok(credentials) => serde_json::from_str::<Credentials>(&credentials)
+                .map(|credentials| format!("using account {}", credentials.account_id))
+                .context("invalid credentials file"),
+            Err(e) if e.kind() == ErrorKind::NotFound && self.auto_create_account => {
+                Ok("no credentials, a new account would be created".into())
+            }
+            Err(e) => Err(e).context("failed to read credentials file"),
+        };
+        let dns = self
+            .dns01_client()
+            .check_access()
+            .await
+            .map(|_| "the API token can list the zone records".to_string());
+        let renewal = match fs::read_to_string(&self.cert_file) {
+            Ok(cert_pem) => remaining_validity(&cert_pem).map(|remaining| {
+                let days = remaining.as_secs() / (24 * 60 * 60);
+                if remaining < self.renew_expires_in() {
+                    format!("expires in {days} days, a renewal would trigger")
+                } else {
+                    format!("expires in {days} days, no renewal needed")
+                }
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Ok("no certificate, a new one would be issued".into())
+            }
+            Err(e) => Err(e).context("failed to read certificate"),
+        };
+        vec![
+            DryRunCheck::new("acme directory", acme_directory),
+            DryRunCheck::new("account", account),
+            DryRunCheck::new("dns", dns),
+            DryRunCheck::new("renewal", renewal),
+        ]
+    }
+}
+
+/// The outcome of a dry-run check.
+#[derive(Debug)]
+pub struct DryRunCheck {
+    pub name: &'static str,
+    /// A summary of what was found, or why the check failed
+    pub result: Result<String>,
+}
+
+impl DryRunCheck {
+    fn new(name: &'static str, result: Result<String>) -> Self {
+        Self { name, result }
+    }
 }
 
 pub struct CertBot {
     acme_client: AcmeClient,
     config: CertBotConfig,
+    /// Renewals are skipped until this time after the CA issued a too short lived certificate
+    renew_backoff: Mutex<RenewBackoff>,
+    metrics: Metrics,
+}
+
+#[derive(Default)]
+struct Metrics {
+    /// Not-after of the live certificate, in unix time
+    cert_expiry: AtomicI64,
+    renew_success: AtomicU64,
+    renew_failure: AtomicU64,
+}
+
+#[derive(Default)]
+struct RenewBackoff {
+    until: Option<Instant>,
+    delay: Duration,
 }
 
 impl CertBot {
     /// Build a new `CertBot` from a `CertBotConfig`.
     pub async fn build(config: CertBotConfig) -> Result<Self> {
-        let dns01_client =
-            Dns01Client::new_cloudflare(config.cf_zone_id.clone(), config.cf_api_token.clone());
+        let dns01_client = config.dns01_client();
+        let webroot = config.http01_webroot.clone();
         let acme_client = match fs::read_to_string(&config.credentials_file) {
-            Ok(credentials) => AcmeClient::load(dns01_client, &credentials).await?,
+            Ok(credentials) => AcmeClient::load(dns01_client, &credentials)
+                .await?
+                .with_challenge(config.challenge, webroot),
             Err(e) if e.kind() == ErrorKind::NotFound => {
                 if !config.auto_create_account {
                     return Err(e).context("credentials file not found");
                 }
                 info!("creating new ACME account");
-                let client = AcmeClient::new_account(&config.acme_url, dns01_client)
-                    .await
-                    .context("failed to create new account")?;
+                let client =
+                    AcmeClient::new_account(&config.acme_url, dns01_client, config.eab.as_ref())
+                        .await
+                        .context("failed to create new account")?
+                        .with_challenge(config.challenge, webroot);
                 let credentials = client
                     .dump_credentials()
                     .context("failed to dump credentials")?;
@@ -85,6 +219,8 @@ impl CertBot {
         Ok(Self {
             acme_client,
             config,
+            renew_backoff: Default::default(),
+            metrics: Default::default(),
         })
     }
 
@@ -110,15 +246,46 @@ impl CertBot {
                 Ok(Ok(_)) => {}
                 Ok(Err(e)) => {
                     error!("failed to run certbot: {e:?}");
+                    self.metrics.renew_failure.fetch_add(1, Ordering::Relaxed);
                 }
                 Err(_) => {
                     error!("certbot timed out");
+                    self.metrics.renew_failure.fetch_add(1, Ordering::Relaxed);
                 }
             }
-            sleep(self.config.renew_interval).await;
+            self.update_cert_expiry();
+            self.wait_next_check().await;
+        }
+    }
+
+    /// Sleep for `renew_interval` on the monotonic clock. Certificate expiry is judged by the
+    /// wall clock, so return early if the wall clock jumps, e.g. when NTP corrects the clock of
+    /// a CVM that booted with a skewed one.
+    async fn wait_next_check(&self) {
+        const TICK: Duration = Duration::from_secs(60);
+        const MAX_CLOCK_DRIFT: Duration = Duration::from_secs(60);
+        let start = Instant::now();
+        loop {
+            let remaining = self.config.renew_interval.saturating_sub(start.elapsed());
+            if remaining.is_zero() {
+                return;
+            }
+            let mono_before = Instant::now();
+            let wall_before = SystemTime::now();
+            sleep(TICK.min(remaining)).await;
+            let drift = clock_drift(mono_before.elapsed(), wall_before, SystemTime::now());
+            if drift > MAX_CLOCK_DRIFT {
+                info!("system clock jumped by {drift:?}, checking certificate now");
+                return;
+            }
         }
     }
 
+    /// The time before expiration at which the certificate is renewed, with jitter applied.
+    pub fn renew_expires_in(&self) -> Duration {
+        self.config.renew_expires_in()
+    }
+
     /// Run the certbot once.
     pub async fn run_once(&self) -> Result<()> {
         self.acme_client
@@ -129,6 +296,12 @@ impl CertBot {
                 &self.config.cert_dir,
             )
             .await?;
+        if let Some(until) = self.lock_backoff().until {
+            if Instant::now() < until {
+                info!("skipping renewal, backing off after a short lived certificate");
+                return Ok(());
+            }
+        }
         info!("checking if certificate needs to be renewed");
         let renewed = self
             .acme_client
@@ -136,7 +309,7 @@ impl CertBot {
                 &self.config.cert_file,
                 &self.config.key_file,
                 &self.config.cert_dir,
-                self.config.renew_expires_in,
+                self.renew_expires_in(),
             )
             .await;
         match renewed {
@@ -145,6 +318,16 @@ impl CertBot {
                     "renewed certificate for {}",
                     self.config.cert_file.display()
                 );
+                self.metrics.renew_success.fetch_add(1, Ordering::Relaxed);
+                if let Some(max_backups) = self.config.max_backups {
+                    if let Err(err) =
+                        prune_backups(&self.config.cert_dir, max_backups, &self.config.cert_file)
+                    {
+                        error!("failed to prune backups: {err:?}");
+                    }
+                }
+                self.check_issued_validity()?;
+                self.notify_renewed().await;
             }
             Ok(false) => {
                 info!(
@@ -159,6 +342,106 @@ impl CertBot {
         Ok(())
     }
 
+    /// Back off from renewing if the CA issued a certificate that expires too soon.
+    fn check_issued_validity(&self) -> Result<()> {
+        const MAX_BACKOFF: Duration = Duration::from_secs(24 * 60 * 60);
+        let cert_pem =
+            fs::read_to_string(&self.config.cert_file).context("failed to read new cert")?;
+        let remaining = remaining_validity(&cert_pem)?;
+        let min_validity = self
+            .config
+            .min_issued_validity
+            .unwrap_or_else(|| self.renew_expires_in());
+        let mut backoff = self.lock_backoff();
+        if remaining >= min_validity {
+            *backoff = RenewBackoff::default();
+            return Ok(());
+        }
+        backoff.delay = if backoff.delay.is_zero() {
+            self.config.renew_interval
+        } else {
+            backoff.delay.saturating_mul(2)
+        }
+        .min(MAX_BACKOFF);
+        backoff.until = Some(Instant::now() + backoff.delay);
+        error!(
+            "the CA issued a certificate valid for only {remaining:?}, less than {min_validity:?}, \
+             next renewal in {:?}",
+            backoff.delay
+        );
+        Ok(())
+    }
+
+    /// POST the renewed certificate to `renew_hook_url`. The request is awaited, so it completes
+    /// before a one-off renewal exits, bounded by `renew_timeout`. Failures are only logged.
+    async fn notify_renewed(&self) {
+        let Some(url) = self.config.renew_hook_url.clone() else {
+            return;
+        };
+        let domain = self
+            .config
+            .cert_subject_alt_names
+            .first()
+            .cloned()
+            .unwrap_or_default();
+        let body = match fs::read_to_string(&self.config.cert_file)
+            .context("failed to read new cert")
+            .and_then(|cert_pem| renew_hook_body(&domain, &cert_pem))
+        {
+            Ok(body) => body,
+            Err(err) => {
+                error!("failed to prepare renew hook: {err:?}");
+                return;
+            }
+        };
+        let result = reqwest::Client::new()
+            .post(&url)
+            .timeout(self.config.renew_timeout)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match result {
+            Ok(_) => info!("called renew hook {url}"),
+            Err(err) => error!("failed to call renew hook {url}: {err}"),
+        }
+    }
+
+    fn update_cert_expiry(&self) {
+        let not_after = fs::read_to_string(&self.config.cert_file)
+            .context("failed to read cert")
+            .and_then(|cert_pem| cert_not_after(&cert_pem));
+        match not_after {
+            Ok(not_after) => self.metrics.cert_expiry.store(not_after, Ordering::Relaxed),
+            Err(err) => error!("failed to read certificate expiry: {err:?}"),
+        }
+    }
+
+    /// Render the renewal metrics in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let metrics = &self.metrics;
+        format!(
+            "# HELP certbot_cert_expiry_seconds Expiry of the live certificate in unix time.\n\
+             # TYPE certbot_cert_expiry_seconds gauge\n\
+             certbot_cert_expiry_seconds {}\n\
+             # HELP certbot_renew_success_total Number of renewed certificates.\n\
+             # TYPE certbot_renew_success_total counter\n\
+             certbot_renew_success_total {}\n\
+             # HELP certbot_renew_failure_total Number of failed renewal checks.\n\
+             # TYPE certbot_renew_failure_total counter\n\
+             certbot_renew_failure_total {}\n",
+            metrics.cert_expiry.load(Ordering::Relaxed),
+            metrics.renew_success.load(Ordering::Relaxed),
+            metrics.renew_failure.load(Ordering::Relaxed),
+        )
+    }
+
+    fn lock_backoff(&self) -> MutexGuard<'_, RenewBackoff> {
+        self.renew_backoff
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
     /// Set CAA record for the domain.
     pub async fn set_caa(&self) -> Result<()> {
         self.acme_client
@@ -167,6 +450,31 @@ impl CertBot {
     }
 }
 
+/// How far the wall clock moved differently from the monotonic clock over the same period.
+fn clock_drift(mono_elapsed: Duration, wall_before: SystemTime, wall_now: SystemTime) -> Duration {
+    match wall_now.duration_since(wall_before) {
+        Ok(wall_elapsed) => wall_elapsed.abs_diff(mono_elapsed),
+        // The wall clock went backwards
+        Err(err) => err.duration() + mono_elapsed,
+    }
+}
+
+fn cert_not_after(cert_pem: &str) -> Result<i64> {
+    let pem = read_pem(cert_pem)?;
+    let cert = pem.parse_x509().context("failed to parse x509 cert")?;
+    Ok(cert.validity().not_after.timestamp())
+}
+
+fn renew_hook_body(domain: &str, cert_pem: &str) -> Result<serde_json::Value> {
+    let pem = read_pem(cert_pem)?;
+    let cert = pem.parse_x509().context("failed to parse x509 cert")?;
+    Ok(serde_json::json!({
+        "domain": domain,
+        "not_after": cert.validity().not_after.timestamp(),
+        "serial": cert.tbs_certificate.raw_serial_as_string(),
+    }))
+}
+
 fn read_pubkey(cert_pem: &str) -> Result<Vec<u8>> {
     let cert = read_pem(cert_pem)?;
     let public_key = cert.parse_x509().context("failed to parse x509 cert")?;
@@ -187,6 +495,42 @@ pub fn list_certs(workdir: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
     Ok(certs)
 }
 
+/// Remove the oldest certificate directories in `backup_dir` by modification time, keeping
+/// `keep` of them. The directory `live_cert` points into is never removed.
+///
+/// Returns the removed directories.
+pub(crate) fn prune_backups(
+    backup_dir: impl AsRef<Path>,
+    keep: usize,
+    live_cert: impl AsRef<Path>,
+) -> Result<Vec<PathBuf>> {
+    let active_dir = fs::canonicalize(live_cert.as_ref())
+        .ok()
+        .and_then(|cert| cert.parent().map(Path::to_path_buf));
+    let mut backups = vec![];
+    for entry in fs::read_dir(backup_dir.as_ref())? {
+        let entry = entry?;
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let modified = entry.metadata()?.modified()?;
+        backups.push((modified, path));
+    }
+    // Newest first
+    backups.sort_by(|a, b| b.0.cmp(&a.0));
+    let mut removed = vec![];
+    for (_, path) in backups.into_iter().skip(keep) {
+        if active_dir.is_some() && fs::canonicalize(&path).ok() == active_dir {
+            continue;
+        }
+        info!("removing old certificate backup {}", path.display());
+        fs::remove_dir_all(&path)?;
+        removed.push(path);
+    }
+    Ok(removed)
+}
+
 pub fn list_cert_public_keys(workdir: impl AsRef<Path>) -> Result<BTreeSet<Vec<u8>>> {
     list_certs(workdir)?
         .into_iter()
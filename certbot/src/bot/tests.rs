@@ -1,35 +1,107 @@
-#![cfg(not(test))]
+use super::*;
 
-use instant_acme::LetsEncrypt;
+#[test]
+fn test_prune_backups() {
+    let tmp = tempfile::tempdir().unwrap();
+    let workdir = tmp.path();
+    let backup_dir = workdir.join("backup");
+    let live_cert = workdir.join("live/cert.pem");
+    fs::create_dir_all(live_cert.parent().unwrap()).unwrap();
 
-use super::*;
+    // Simulate renewals, each one minute apart
+    let start = SystemTime::now() - Duration::from_secs(3600);
+    let mut dirs = vec![];
+    for i in 0..5 {
+        let dir = backup_dir.join(format!("renewal-{i}"));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("cert.pem"), "cert").unwrap();
+        std::fs::File::open(&dir)
+            .unwrap()
+            .set_modified(start + Duration::from_secs(60 * i))
+            .unwrap();
+        dirs.push(dir);
+    }
+    // The live cert is the oldest one, e.g. after a rollback
+    std::os::unix::fs::symlink(dirs[0].join("cert.pem"), &live_cert).unwrap();
+
+    let removed = prune_backups(&backup_dir, 2, &live_cert).unwrap();
+    assert_eq!(removed.len(), 2);
+    assert!(dirs[0].exists());
+    assert!(!dirs[1].exists());
+    assert!(!dirs[2].exists());
+    assert!(dirs[3].exists());
+    assert!(dirs[4].exists());
+}
 
-async fn new_certbot() -> Result<CertBot> {
-    let cf_zone_id = std::env::var("CLOUDFLARE_ZONE_ID").expect("CLOUDFLARE_ZONE_ID not set");
-    let cf_api_token = std::env::var("CLOUDFLARE_API_TOKEN").expect("CLOUDFLARE_API_TOKEN not set");
-    let domains = vec![std::env::var("TEST_DOMAIN").expect("TEST_DOMAIN not set")];
-    let config = CertBotConfig::builder()
-        .acme_url(LetsEncrypt::Staging.url())
-        .auto_create_account(true)
-        .credentials_file("./test-workdir/credentials.json")
-        .cf_zone_id(cf_zone_id)
-        .cf_api_token(cf_api_token)
-        .cert_dir("./test-workdir/backup")
-        .cert_file("./test-workdir/live/cert.pem")
-        .key_file("./test-workdir/live/key.pem")
-        .cert_subject_alt_names(domains)
-        .renew_interval(Duration::from_secs(30))
-        .renew_timeout(Duration::from_secs(120))
-        .renew_expires_in(Duration::from_secs(7772187))
-        .auto_set_caa(false)
-        .build();
-    config.build_bot().await
+#[test]
+fn test_renew_expires_in_jitter() {
+    const DAY: u64 = 24 * 60 * 60;
+    let config = |domains: &[&str], jitter: u64| {
+        CertBotConfig::builder()
+            .acme_url("https://acme.example.com/directory")
+            .auto_set_caa(false)
+            .credentials_file("credentials.json")
+            .auto_create_account(false)
+            .cf_zone_id("zone")
+            .cf_api_token("token")
+            .cert_file("cert.pem")
+            .key_file("key.pem")
+            .cert_dir("backup")
+            .cert_subject_alt_names(domains.iter().map(|d| d.to_string()).collect())
+            .renew_interval(Duration::from_secs(3600))
+            .renew_timeout(Duration::from_secs(120))
+            .renew_expires_in(Duration::from_secs(30 * DAY))
+            .renew_expires_in_jitter(Duration::from_secs(jitter))
+            .build()
+    };
+    let base = Duration::from_secs(30 * DAY);
+    assert_eq!(config(&["example.com"], 0).renew_expires_in(), base);
+    // Pinned, so a change of the derivation does not go unnoticed
+    assert_eq!(
+        config(&["example.com"], 7 * DAY).renew_expires_in(),
+        base + Duration::from_secs(428255)
+    );
+    assert_eq!(
+        config(&["example.com", "*.example.com"], 7 * DAY).renew_expires_in(),
+        base + Duration::from_secs(91187)
+    );
 }
 
-#[tokio::test]
-async fn test_certbot() {
-    tracing_subscriber::fmt::try_init().ok();
+// Needs Cloudflare and Let's Encrypt credentials
+#[cfg(not(test))]
+mod live {
+    use instant_acme::LetsEncrypt;
+
+    use super::*;
+
+    async fn new_certbot() -> Result<CertBot> {
+        let cf_zone_id = std::env::var("CLOUDFLARE_ZONE_ID").expect("CLOUDFLARE_ZONE_ID not set");
+        let cf_api_token =
+            std::env::var("CLOUDFLARE_API_TOKEN").expect("CLOUDFLARE_API_TOKEN not set");
+        let domains = vec![std::env::var("TEST_DOMAIN").expect("TEST_DOMAIN not set")];
+        let config = CertBotConfig::builder()
+            .acme_url(LetsEncrypt::Staging.url())
+            .auto_create_account(true)
+            .credentials_file("./test-workdir/credentials.json")
+            .cf_zone_id(cf_zone_id)
+            .cf_api_token(cf_api_token)
+            .cert_dir("./test-workdir/backup")
+            .cert_file("./test-workdir/live/cert.pem")
+            .key_file("./test-workdir/live/key.pem")
+            .cert_subject_alt_names(domains)
+            .renew_interval(Duration::from_secs(30))
+            .renew_timeout(Duration::from_secs(120))
+            .renew_expires_in(Duration::from_secs(7772187))
+            .auto_set_caa(false)
+            .build();
+        config.build_bot().await
+    }
+
+    #[tokio::test]
+    async fn test_certbot() {
+        tracing_subscriber::fmt::try_init().ok();
 
-    let bot = new_certbot().await.unwrap();
-    bot.run().await;
+        let bot = new_certbot().await.unwrap();
+        bot.run().await;
+    }
 }
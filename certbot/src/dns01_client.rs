@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use anyhow::Result;
 use cloudflare::CloudflareClient;
 use enum_dispatch::enum_dispatch;
@@ -39,6 +41,14 @@ pub(crate) trait Dns01Api {
     /// Deletes a DNS record using its unique identifier.
     async fn remove_record(&self, record_id: &str) -> Result<()>;
 
+    /// Whether the records of `domain` can be managed by this client.
+    fn manages_domain(&self, _domain: &str) -> bool {
+        true
+    }
+
+    /// Check that the credentials can list the records of every configured zone.
+    async fn check_access(&self) -> Result<()>;
+
     /// Get all records for a domain.
     async fn get_records(&self, domain: &str) -> Result<Vec<Record>>;
 
@@ -67,4 +77,52 @@ impl Dns01Client {
     pub fn new_cloudflare(zone_id: String, api_token: String) -> Self {
         Self::Cloudflare(CloudflareClient::new(zone_id, api_token))
     }
+
+    /// A Cloudflare client managing records of several zones. `zones` maps domain suffixes to
+    /// zone IDs, domains matching none of them are handled in `zone_id`.
+    pub fn new_cloudflare_with_zones(
+        zone_id: String,
+        zones: BTreeMap<String, String>,
+        api_token: String,
+    ) -> Self {
+        Self::Cloudflare(CloudflareClient::new(zone_id, api_token).with_zones(zones))
+    }
+}
+
+/// Find the zone of `domain` in a map of domain suffixes to zone IDs. The longest matching
+/// suffix wins. A leading wildcard label is ignored.
+pub fn find_zone<'a>(zones: &'a BTreeMap<String, String>, domain: &str) -> Option<&'a str> {
+    let domain = domain.strip_prefix("*.").unwrap_or(domain);
+    zones
+        .iter()
+        .filter(|(suffix, _)| {
+            domain == suffix.as_str()
+                || domain
+                    .strip_suffix(suffix.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        })
+        .max_by_key(|(suffix, _)| suffix.len())
+        .map(|(_, zone_id)| zone_id.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_zone() {
+        let zones = BTreeMap::from([
+            ("example.com".to_string(), "zone-a".to_string()),
+            ("dev.example.com".to_string(), "zone-b".to_string()),
+        ]);
+        assert_eq!(find_zone(&zones, "example.com"), Some("zone-a"));
+        assert_eq!(find_zone(&zones, "*.example.com"), Some("zone-a"));
+        assert_eq!(
+            find_zone(&zones, "_acme-challenge.www.example.com"),
+            Some("zone-a")
+        );
+        assert_eq!(find_zone(&zones, "app.dev.example.com"), Some("zone-b"));
+        assert_eq!(find_zone(&zones, "notexample.com"), None);
+        assert_eq!(find_zone(&zones, "example.org"), None);
+    }
 }
@@ -1,9 +1,11 @@
+use std::collections::BTreeMap;
+
 use anyhow::{Context, Result};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::dns01_client::Record;
+use crate::dns01_client::{find_zone, Record};
 
 use super::Dns01Api;
 
@@ -13,18 +15,54 @@ const CLOUDFLARE_API_URL: &str = "https://api.cloudflare.com/client/v4";
 pub struct CloudflareClient {
     zone_id: String,
     api_token: String,
+    /// Zone IDs by domain suffix, for domains outside of `zone_id`
+    #[serde(default)]
+    zones: BTreeMap<String, String>,
 }
 
 impl CloudflareClient {
     pub fn new(zone_id: String, api_token: String) -> Self {
-        Self { zone_id, api_token }
+        Self {
+            zone_id,
+            api_token,
+            zones: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_zones(self, zones: BTreeMap<String, String>) -> Self {
+        Self { zones, ..self }
+    }
+
+    fn zone_for(&self, domain: &str) -> &str {
+        find_zone(&self.zones, domain).unwrap_or(&self.zone_id)
+    }
+
+    /// Records outside of the default zone get their zone prefixed to the id, so that they can
+    /// be removed by id alone.
+    fn encode_record_id(&self, zone_id: &str, record_id: String) -> String {
+        if zone_id == self.zone_id {
+            record_id
+        } else {
+            format!("{zone_id}:{record_id}")
+        }
+    }
+
+    fn decode_record_id<'a>(&'a self, record_id: &'a str) -> (&'a str, &'a str) {
+        record_id
+            .split_once(':')
+            .unwrap_or((self.zone_id.as_str(), record_id))
     }
 }
 
 impl Dns01Api for CloudflareClient {
+    fn manages_domain(&self, domain: &str) -> bool {
+        !self.zone_id.is_empty() || find_zone(&self.zones, domain).is_some()
+    }
+
     async fn add_txt_record(&self, domain: &str, content: &str) -> Result<String> {
         let client = Client::new();
-        let url = format!("{}/zones/{}/dns_records", CLOUDFLARE_API_URL, self.zone_id);
+        let zone_id = self.zone_for(domain);
+        let url = format!("{}/zones/{}/dns_records", CLOUDFLARE_API_URL, zone_id);
         let response = client
             .post(&url)
             .header("Authorization", format!("Bearer {}", self.api_token))
@@ -57,14 +95,15 @@ impl Dns01Api for CloudflareClient {
 
         let response: Response = response.json().await.context("failed to parse response")?;
 
-        Ok(response.result.id)
+        Ok(self.encode_record_id(zone_id, response.result.id))
     }
 
     async fn remove_record(&self, record_id: &str) -> Result<()> {
         let client = Client::new();
+        let (zone_id, record_id) = self.decode_record_id(record_id);
         let url = format!(
             "{}/zones/{}/dns_records/{}",
-            CLOUDFLARE_API_URL, self.zone_id, record_id
+            CLOUDFLARE_API_URL, zone_id, record_id
         );
 
         let response = client
@@ -91,7 +130,8 @@ impl Dns01Api for CloudflareClient {
         value: &str,
     ) -> Result<String> {
         let client = Client::new();
-        let url = format!("{}/zones/{}/dns_records", CLOUDFLARE_API_URL, self.zone_id);
+        let zone_id = self.zone_for(domain);
+        let url = format!("{}/zones/{}/dns_records", CLOUDFLARE_API_URL, zone_id);
         let response = client
             .post(&url)
             .header("Authorization", format!("Bearer {}", self.api_token))
@@ -127,12 +167,42 @@ impl Dns01Api for CloudflareClient {
 
         let response: Response = response.json().await.context("failed to parse response")?;
 
-        Ok(response.result.id)
+        Ok(self.encode_record_id(zone_id, response.result.id))
+    }
+
+    async fn check_access(&self) -> Result<()> {
+        let client = Client::new();
+        let zone_ids = std::iter::once(&self.zone_id)
+            .chain(self.zones.values())
+            .filter(|zone_id| !zone_id.is_empty())
+            .collect::<std::collections::BTreeSet<_>>();
+        if zone_ids.is_empty() {
+            anyhow::bail!("no zone configured");
+        }
+        for zone_id in zone_ids {
+            let url = format!(
+                "{}/zones/{}/dns_records?per_page=1",
+                CLOUDFLARE_API_URL, zone_id
+            );
+            let response = client
+                .get(&url)
+                .header("Authorization", format!("Bearer {}", self.api_token))
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                anyhow::bail!(
+                    "failed to list records of zone {zone_id}: {}",
+                    response.text().await?
+                );
+            }
+        }
+        Ok(())
     }
 
     async fn get_records(&self, domain: &str) -> Result<Vec<Record>> {
         let client = Client::new();
-        let url = format!("{}/zones/{}/dns_records", CLOUDFLARE_API_URL, self.zone_id);
+        let zone_id = self.zone_for(domain);
+        let url = format!("{}/zones/{}/dns_records", CLOUDFLARE_API_URL, zone_id);
 
         let response = client
             .get(&url)
@@ -156,6 +226,10 @@ impl Dns01Api for CloudflareClient {
             .result
             .into_iter()
             .filter(|record| record.name == domain)
+            .map(|record| Record {
+                id: self.encode_record_id(zone_id, record.id),
+                ..record
+            })
             .collect();
         Ok(records)
     }
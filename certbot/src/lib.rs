@@ -7,14 +7,15 @@
 //!
 //! - Automatic certificate issuance and renewal
 //! - DNS-01 challenge support (currently implemented for Cloudflare)
+//! - HTTP-01 challenge support through a webroot directory
 //! - Easy integration with existing Rust applications
 //!
 //! For more detailed information on the available methods and their usage, please refer
 //! to the documentation of individual structs and functions.
 
-pub use acme_client::AcmeClient;
-pub use bot::{CertBot, CertBotConfig};
-pub use dns01_client::Dns01Client;
+pub use acme_client::{read_cert_info, AcmeClient, CertInfo, ChallengeKind, EabCredentials};
+pub use bot::{CertBot, CertBotConfig, DryRunCheck};
+pub use dns01_client::{find_zone, Dns01Client};
 pub use workdir::WorkDir;
 
 mod acme_client;
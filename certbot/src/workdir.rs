@@ -35,6 +35,11 @@ impl WorkDir {
         self.workdir.join("live")
     }
 
+    /// Default webroot for HTTP-01 challenge tokens
+    pub fn webroot_dir(&self) -> PathBuf {
+        self.workdir.join("webroot")
+    }
+
     pub fn cert_path(&self) -> PathBuf {
         self.live_dir().join("cert.pem")
     }
@@ -0,0 +1,96 @@
+//! Config driven logging setup shared by the dstack services.
+//!
+//! The log filter is built from the config alone, so `RUST_LOG` in the environment of the
+//! launching process has no effect on the services.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::{fmt, EnvFilter};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LoggingConfig {
+    /// The default log level for all targets
+    #[serde(default = "default_level")]
+    pub level: String,
+    /// Per-target log levels, e.g. `rocket = "warn"`
+    #[serde(default)]
+    pub targets: BTreeMap<String, String>,
+    /// The output format
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+fn default_level() -> String {
+    "info".into()
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: default_level(),
+            targets: Default::default(),
+            format: Default::default(),
+        }
+    }
+}
+
+impl LoggingConfig {
+    /// Build the log filter from the configured levels.
+    pub fn filter(&self) -> Result<EnvFilter> {
+        let directives = std::iter::once(self.level.clone())
+            .chain(
+                self.targets
+                    .iter()
+                    .map(|(target, level)| format!("{target}={level}")),
+            )
+            .collect::<Vec<_>>()
+            .join(",");
+        EnvFilter::builder()
+            .parse(&directives)
+            .with_context(|| format!("invalid log filter: {directives}"))
+    }
+
+    /// Install the global tracing subscriber.
+    pub fn init(&self) -> Result<()> {
+        let builder = fmt().with_env_filter(self.filter()?);
+        match self.format {
+            LogFormat::Text => builder.try_init(),
+            LogFormat::Json => builder.json().try_init(),
+        }
+        .map_err(|err| anyhow!("failed to init logging: {err}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let config = LoggingConfig {
+            level: "warn".into(),
+            targets: [("teepod".to_string(), "debug".to_string())].into(),
+            format: LogFormat::Json,
+        };
+        assert!(config.filter().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_filter() {
+        let config = LoggingConfig {
+            targets: [("teepod".to_string(), "loud".to_string())].into(),
+            ..Default::default()
+        };
+        assert!(config.filter().is_err());
+    }
+}
@@ -1,28 +1,109 @@
-use std::time::Duration;
+use std::{collections::BTreeSet, path::Path, sync::Arc, time::Duration};
 
 use anyhow::{Context, Result};
+use fs_err as fs;
 use prpc::{
     client::{Error, RequestClient},
     server::ProtoError,
     Message,
 };
 use reqwest::{Certificate, Client, Identity};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    client::WebPkiServerVerifier,
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    CertificateError, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+const SEND_FAILED: &str = "failed to send request";
+const READ_FAILED: &str = "failed to read response";
+const REQUEST_FAILED: &str = "request failed with status=";
+
+/// Whether a request error is worth retrying: the peer could not be reached, the connection
+/// broke, or the server answered with a 5xx status. Errors returned by the RPC method itself,
+/// like an unknown app id, are not transient.
+pub fn is_transient_error(err: &Error) -> bool {
+    let Error::RpcError(msg) = err else {
+        return false;
+    };
+    msg.starts_with(SEND_FAILED)
+        || msg.starts_with(READ_FAILED)
+        || msg
+            .strip_prefix(REQUEST_FAILED)
+            .is_some_and(|status| status.starts_with('5'))
+}
+
+/// SHA-256 fingerprints of revoked peer certificates.
+#[derive(Debug, Clone, Default)]
+pub struct RevocationList {
+    fingerprints: BTreeSet<[u8; 32]>,
+}
+
+impl RevocationList {
+    /// Parse a revocation list with one hex encoded SHA-256 fingerprint of a DER certificate per
+    /// line. Empty lines and lines starting with `#` are ignored.
+    pub fn parse(text: &str) -> Result<Self> {
+        let fingerprints = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let bytes = hex::decode(line.replace(':', ""))
+                    .with_context(|| format!("invalid fingerprint: {line}"))?;
+                bytes
+                    .try_into()
+                    .ok()
+                    .with_context(|| format!("invalid fingerprint length: {line}"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { fingerprints })
+    }
+
+    /// Load a revocation list from a file, see [`RevocationList::parse`].
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let text = fs::read_to_string(path).context("Failed to read revocation list")?;
+        Self::parse(&text)
+    }
+
+    /// Check whether a DER encoded certificate is revoked.
+    pub fn is_revoked(&self, cert_der: &[u8]) -> bool {
+        let fingerprint: [u8; 32] = Sha256::digest(cert_der).into();
+        self.fingerprints.contains(&fingerprint)
+    }
+}
+
+/// How a [`RaClient`] authenticates the server and itself.
+#[derive(Clone)]
+enum TlsSettings {
+    /// Verify the server against the web PKI roots, or accept any server certificate
+    Public { no_check: bool },
+    /// Verify the server against a private CA and present a client certificate
+    Mtls {
+        ca_cert: String,
+        identity_pem: String,
+    },
+}
 
 pub struct RaClient {
     remote_uri: String,
     client: Client,
+    tls: TlsSettings,
 }
 
 impl RaClient {
     pub fn new(remote_uri: String, tls_no_check: bool) -> Self {
-        let client = Client::builder()
-            .tls_sni(true)
-            .danger_accept_invalid_certs(tls_no_check)
-            .connect_timeout(Duration::from_secs(5))
-            .timeout(Duration::from_secs(60))
-            .build()
-            .expect("failed to create client");
-        Self { remote_uri, client }
+        let tls = TlsSettings::Public {
+            no_check: tls_no_check,
+        };
+        let client = build_client(&tls, None).expect("failed to create client");
+        Self {
+            remote_uri,
+            client,
+            tls,
+        }
     }
     pub fn new_mtls(
         remote_uri: String,
@@ -30,48 +111,307 @@ impl RaClient {
         cert_pem: String,
         key_pem: String,
     ) -> Result<Self> {
-        let root_ca =
-            Certificate::from_pem(ca_cert.as_bytes()).context("Failed to parse CA cert")?;
-        let identity_pem = format!("{cert_pem}\n{key_pem}");
-        let identity =
-            Identity::from_pem(identity_pem.as_bytes()).context("Failed to parse identity")?;
-        let client = Client::builder()
-            .tls_sni(true)
-            .add_root_certificate(root_ca)
-            .identity(identity)
-            .connect_timeout(Duration::from_secs(5))
-            .timeout(Duration::from_secs(60))
-            .build()
-            .context("failed to create client")?;
-        Ok(Self { remote_uri, client })
+        let tls = TlsSettings::Mtls {
+            ca_cert,
+            identity_pem: format!("{cert_pem}\n{key_pem}"),
+        };
+        let client = build_client(&tls, None)?;
+        Ok(Self {
+            remote_uri,
+            client,
+            tls,
+        })
     }
-}
 
-impl RequestClient for RaClient {
-    async fn request(&self, path: &str, body: Vec<u8>) -> Result<Vec<u8>, Error> {
+    /// Refuse peers whose certificate is in the given revocation list.
+    ///
+    /// The peer is rejected during the TLS handshake, before any request is sent to it.
+    pub fn with_revocation_list(mut self, revocation_list: RevocationList) -> Result<Self> {
+        self.client = build_client(&self.tls, Some(revocation_list))?;
+        Ok(self)
+    }
+
+    /// Send a request, without looking at the response status.
+    async fn send(&self, path: &str, body: Vec<u8>) -> Result<reqwest::Response, Error> {
         let url = format!("{}/{}", self.remote_uri, path);
-        let response = self
-            .client
+        self.client
             .post(url)
             .body(body)
             .send()
             .await
-            .map_err(|err| Error::RpcError(format!("failed to send request: {:?}", err)))?;
-        let status = response.status();
-        if !status.is_success() {
-            let body = response.bytes().await.unwrap_or_default();
-            let error = ProtoError::decode(body.as_ref())
-                .unwrap_or_default()
-                .message;
-            return Err(Error::RpcError(format!(
-                "request failed with status={status}, error={error}",
-            )));
+            .map_err(|err| Error::RpcError(format!("{SEND_FAILED}: {err:?}")))
+    }
+}
+
+fn build_client(tls: &TlsSettings, revocation_list: Option<RevocationList>) -> Result<Client> {
+    let builder = Client::builder()
+        .tls_sni(true)
+        .connect_timeout(Duration::from_secs(5))
+        .timeout(Duration::from_secs(60));
+    let builder = match (tls, revocation_list) {
+        (tls, Some(revocation_list)) => {
+            builder.use_preconfigured_tls(revocation_tls_config(tls, revocation_list)?)
         }
-        let body = response
-            .bytes()
-            .await
-            .map_err(|err| Error::RpcError(format!("failed to read response: {:?}", err)))?
-            .to_vec();
-        Ok(body)
+        (TlsSettings::Public { no_check }, None) => builder.danger_accept_invalid_certs(*no_check),
+        (
+            TlsSettings::Mtls {
+                ca_cert,
+                identity_pem,
+            },
+            None,
+        ) => {
+            let root_ca =
+                Certificate::from_pem(ca_cert.as_bytes()).context("Failed to parse CA cert")?;
+            let identity =
+                Identity::from_pem(identity_pem.as_bytes()).context("Failed to parse identity")?;
+            builder.add_root_certificate(root_ca).identity(identity)
+        }
+    };
+    builder.build().context("failed to create client")
+}
+
+/// A rustls config for `tls` that also rejects servers whose certificate is revoked.
+fn revocation_tls_config(
+    tls: &TlsSettings,
+    revocation_list: RevocationList,
+) -> Result<rustls::ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let webpki_verifier = |roots: RootCertStore| -> Result<Arc<dyn ServerCertVerifier>> {
+        let verifier: Arc<dyn ServerCertVerifier> =
+            WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .build()
+                .context("Failed to create certificate verifier")?;
+        Ok(verifier)
+    };
+    let inner: Arc<dyn ServerCertVerifier> = match tls {
+        TlsSettings::Public { no_check: true } => Arc::new(AcceptAnyServerCert(provider.clone())),
+        TlsSettings::Public { no_check: false } => webpki_verifier(RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        })?,
+        TlsSettings::Mtls { ca_cert, .. } => {
+            let mut roots = RootCertStore::empty();
+            for cert in rustls_pemfile::certs(&mut ca_cert.as_bytes()) {
+                roots
+                    .add(cert.context("Failed to parse CA cert")?)
+                    .context("Failed to add CA cert")?;
+            }
+            webpki_verifier(roots)?
+        }
+    };
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .context("Failed to create TLS config")?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(RevocationVerifier {
+            inner,
+            revocation_list,
+        }));
+    let config = match tls {
+        TlsSettings::Public { .. } => builder.with_no_client_auth(),
+        TlsSettings::Mtls { identity_pem, .. } => {
+            let certs = rustls_pemfile::certs(&mut identity_pem.as_bytes())
+                .collect::<Result<Vec<_>, _>>()
+                .context("Failed to parse identity")?;
+            let key = rustls_pemfile::private_key(&mut identity_pem.as_bytes())
+                .context("Failed to parse identity")?
+                .context("No private key in identity")?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .context("Failed to set client certificate")?
+        }
+    };
+    Ok(config)
+}
+
+/// Rejects revoked server certificates and passes the others on to `inner`.
+#[derive(Debug)]
+struct RevocationVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    revocation_list: RevocationList,
+}
+
+impl ServerCertVerifier for RevocationVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.revocation_list.is_revoked(end_entity) {
+            return Err(rustls::Error::InvalidCertificate(CertificateError::Revoked));
+        }
+        self.inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Accepts any server certificate, while still checking the handshake is signed by its key.
+#[derive(Debug)]
+struct AcceptAnyServerCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyServerCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+impl RequestClient for RaClient {
+    async fn request(&self, path: &str, body: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let response = self.send(path, body).await?;
+        read_response(response).await
+    }
+}
+
+async fn read_response(response: reqwest::Response) -> Result<Vec<u8>, Error> {
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.bytes().await.unwrap_or_default();
+        let error = ProtoError::decode(body.as_ref())
+            .unwrap_or_default()
+            .message;
+        return Err(Error::RpcError(format!(
+            "{REQUEST_FAILED}{status}, error={error}",
+        )));
+    }
+    let body = response
+        .bytes()
+        .await
+        .map_err(|err| Error::RpcError(format!("{READ_FAILED}: {err:?}")))?
+        .to_vec();
+    Ok(body)
+}
+
+/// Tries a list of endpoints in order, moving to the next one if an endpoint can not be
+/// reached or its certificate is revoked.
+///
+/// Once an endpoint responds, its response is returned as is, including RPC errors.
+pub struct FallbackRaClient {
+    clients: Vec<RaClient>,
+}
+
+impl FallbackRaClient {
+    pub fn new(clients: Vec<RaClient>) -> Self {
+        Self { clients }
+    }
+}
+
+impl RequestClient for FallbackRaClient {
+    async fn request(&self, path: &str, body: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut last_error = Error::RpcError("no endpoint configured".to_string());
+        for client in &self.clients {
+            match client.send(path, body.clone()).await {
+                Ok(response) => return read_response(response).await,
+                Err(err) => {
+                    warn!("endpoint {} is unavailable: {err:?}", client.remote_uri);
+                    last_error = err;
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ra_tls::rcgen::{generate_simple_self_signed, CertifiedKey};
+
+    fn self_signed(name: &str) -> CertificateDer<'static> {
+        let CertifiedKey { cert, .. } = generate_simple_self_signed(vec![name.into()]).unwrap();
+        cert.der().clone()
+    }
+
+    #[test]
+    fn test_revoked_peer_rejected_in_handshake() {
+        let revoked = self_signed("revoked.example");
+        let good = self_signed("good.example");
+        let revocation_list =
+            RevocationList::parse(&hex::encode(Sha256::digest(&revoked))).unwrap();
+        let verifier = RevocationVerifier {
+            inner: Arc::new(AcceptAnyServerCert(Arc::new(
+                rustls::crypto::ring::default_provider(),
+            ))),
+            revocation_list: revocation_list.clone(),
+        };
+        let verify = |cert: &CertificateDer<'_>| {
+            let name = ServerName::try_from("example").unwrap();
+            verifier.verify_server_cert(cert, &[], &name, &[], UnixTime::now())
+        };
+        assert!(matches!(
+            verify(&revoked),
+            Err(rustls::Error::InvalidCertificate(CertificateError::Revoked))
+        ));
+        verify(&good).unwrap();
+
+        // Clients with a revocation list are built with the verifier for every TLS setting
+        RaClient::new("https://localhost".into(), true)
+            .with_revocation_list(revocation_list.clone())
+            .unwrap();
+        RaClient::new("https://localhost".into(), false)
+            .with_revocation_list(revocation_list)
+            .unwrap();
     }
 }
@@ -2,7 +2,10 @@
 
 use anyhow::{anyhow, Context, Result};
 use dcap_qvl::quote::Quote;
-use qvl::{quote::Report, verify::VerifiedReport};
+use qvl::{
+    quote::{Report, TDReport10},
+    verify::VerifiedReport,
+};
 use sha2::{Digest as _, Sha384};
 
 use crate::{oids, traits::CertExt};
@@ -17,6 +20,10 @@ pub enum QuoteContentType {
     RaTlsCert,
     /// App defined data
     AppData,
+    /// The X25519 public key a provisioner encrypts secrets to
+    ProvisioningKey,
+    /// The payload of an exported attestation bundle
+    AttestationBundle,
 }
 
 impl QuoteContentType {
@@ -26,6 +33,8 @@ impl QuoteContentType {
             Self::KmsRootCa => "kms-root-ca",
             Self::RaTlsCert => "ratls-cert",
             Self::AppData => "app-data",
+            Self::ProvisioningKey => "provisioning-key",
+            Self::AttestationBundle => "attestation-bundle",
         }
     }
 
@@ -184,6 +193,22 @@ impl Attestation {
         }
     }
 
+    /// Decode the TD report in the quote
+    pub fn decode_td_report(&self) -> Result<TDReport10> {
+        match self.decode_quote()?.report {
+            Report::SgxEnclave(_) => Err(anyhow!("SGX enclave is not supported")),
+            Report::TD10(report) => Ok(report),
+            Report::TD15(report) => Ok(report.base),
+        }
+    }
+
+    /// Compare the TD measurements of this attestation with another one
+    pub fn diff_measurements(&self, other: &Attestation) -> Result<MeasurementDiff> {
+        let this = td_measurements(&self.decode_td_report()?);
+        let other = td_measurements(&other.decode_td_report()?);
+        Ok(MeasurementDiff::between(&this, &other))
+    }
+
     /// Ensure the quote is for the RA-TLS public key
     pub fn ensure_quote_for_ra_tls_pubkey(&self, pubkey: &[u8]) -> Result<()> {
         let report_data = self.decode_report_data()?;
@@ -195,6 +220,70 @@ impl Attestation {
     }
 }
 
+/// A measurement register of a TD
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Measurement {
+    MrTd,
+    MrConfigId,
+    MrOwner,
+    MrOwnerConfig,
+    Rtmr0,
+    Rtmr1,
+    Rtmr2,
+    Rtmr3,
+}
+
+/// The result of comparing the measurements of two attestations
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MeasurementDiff {
+    /// Measurements equal in both attestations
+    pub matching: Vec<Measurement>,
+    /// Measurements that differ between the attestations
+    pub differing: Vec<Measurement>,
+}
+
+impl MeasurementDiff {
+    fn between(a: &[(Measurement, [u8; 48])], b: &[(Measurement, [u8; 48])]) -> Self {
+        let mut diff = Self::default();
+        for ((m, va), (_, vb)) in a.iter().zip(b) {
+            if va == vb {
+                diff.matching.push(*m);
+            } else {
+                diff.differing.push(*m);
+            }
+        }
+        diff
+    }
+
+    /// Return true if all measurements are equal
+    pub fn is_identical(&self) -> bool {
+        self.differing.is_empty()
+    }
+
+    /// Return true if exactly the given measurements differ, e.g. only RTMR3 for an app upgrade
+    pub fn differs_exactly_in(&self, expected: &[Measurement]) -> bool {
+        let mut expected = expected.to_vec();
+        expected.sort();
+        expected.dedup();
+        let mut differing = self.differing.clone();
+        differing.sort();
+        differing == expected
+    }
+}
+
+fn td_measurements(report: &TDReport10) -> [(Measurement, [u8; 48]); 8] {
+    [
+        (Measurement::MrTd, report.mr_td),
+        (Measurement::MrConfigId, report.mr_config_id),
+        (Measurement::MrOwner, report.mr_owner),
+        (Measurement::MrOwnerConfig, report.mr_owner_config),
+        (Measurement::Rtmr0, report.rt_mr0),
+        (Measurement::Rtmr1, report.rt_mr1),
+        (Measurement::Rtmr2, report.rt_mr2),
+        (Measurement::Rtmr3, report.rt_mr3),
+    ]
+}
+
 /// Replay event logs
 pub fn replay_event_logs(eventlog: &[EventLog]) -> Result<[[u8; 48]; 4]> {
     let mut rtmrs = [[0u8; 48]; 4];
@@ -216,10 +305,98 @@ pub fn replay_event_logs(eventlog: &[EventLog]) -> Result<[[u8; 48]; 4]> {
     Ok(rtmrs)
 }
 
+/// The outcome of checking an event log against the RTMRs reported in a quote.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventLogVerification {
+    /// RTMRs replayed from the event log
+    pub replayed: [[u8; 48]; 4],
+    /// Index of the first event whose digest does not match its content
+    pub invalid_event: Option<usize>,
+    /// Index of the first RTMR the replay does not reproduce
+    pub mismatched_rtmr: Option<usize>,
+}
+
+impl EventLogVerification {
+    /// Return true if every event is intact and the replay reproduces all RTMRs
+    pub fn is_valid(&self) -> bool {
+        self.invalid_event.is_none() && self.mismatched_rtmr.is_none()
+    }
+}
+
+/// Replay the event log and compare the result with the RTMRs of a quote.
+pub fn verify_event_logs(
+    eventlog: &[EventLog],
+    rtmrs: &[[u8; 48]; 4],
+) -> Result<EventLogVerification> {
+    let replayed = replay_event_logs(eventlog)?;
+    Ok(EventLogVerification {
+        replayed,
+        invalid_event: eventlog.iter().position(|event| event.validate().is_err()),
+        mismatched_rtmr: replayed
+            .iter()
+            .zip(rtmrs.iter())
+            .position(|(replayed, rtmr)| replayed != rtmr),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_measurement_diff() {
+        let old = [
+            (Measurement::MrTd, [1u8; 48]),
+            (Measurement::Rtmr0, [2u8; 48]),
+            (Measurement::Rtmr3, [3u8; 48]),
+        ];
+        let mut new = old;
+        new[2].1 = [4u8; 48];
+
+        let diff = MeasurementDiff::between(&old, &new);
+        assert_eq!(diff.matching, [Measurement::MrTd, Measurement::Rtmr0]);
+        assert_eq!(diff.differing, [Measurement::Rtmr3]);
+        assert!(!diff.is_identical());
+        assert!(diff.differs_exactly_in(&[Measurement::Rtmr3]));
+        assert!(!diff.differs_exactly_in(&[Measurement::Rtmr3, Measurement::MrTd]));
+        assert!(MeasurementDiff::between(&old, &old).is_identical());
+    }
+
+    #[test]
+    fn test_verify_event_logs() {
+        let events = vec![
+            EventLog::new_str(3, 0x08000001, "app-id", "app"),
+            EventLog::new_str(3, 0x08000001, "instance-id", "instance"),
+            EventLog::new_str(2, 0x08000001, "boot", "kernel"),
+        ];
+        // Extend the RTMRs the way the guest does on the write side
+        let mut rtmrs = [[0u8; 48]; 4];
+        for event in &events {
+            let mr = &mut rtmrs[event.imr as usize];
+            *mr = Sha384::new_with_prefix(*mr)
+                .chain_update(event.digest)
+                .finalize()
+                .into();
+        }
+
+        let verification = verify_event_logs(&events, &rtmrs).unwrap();
+        assert!(verification.is_valid());
+        assert_eq!(verification.replayed, rtmrs);
+
+        let mut tampered = events.clone();
+        tampered[1].event_payload = b"other".to_vec();
+        let verification = verify_event_logs(&tampered, &rtmrs).unwrap();
+        assert_eq!(verification.invalid_event, Some(1));
+        assert_eq!(verification.mismatched_rtmr, None);
+
+        let mut quoted = rtmrs;
+        quoted[2] = [0u8; 48];
+        let verification = verify_event_logs(&events, &quoted).unwrap();
+        assert_eq!(verification.invalid_event, None);
+        assert_eq!(verification.mismatched_rtmr, Some(2));
+        assert!(!verification.is_valid());
+    }
+
     #[test]
     fn test_to_report_data_with_hash() {
         let content_type = QuoteContentType::AppData;
@@ -1,12 +1,13 @@
 //! Key derivation functions.
 use anyhow::{anyhow, Context, Result};
 use p256::pkcs8::{DecodePrivateKey, EncodePrivateKey};
-use rcgen::{KeyPair, PKCS_ECDSA_P256_SHA256};
+use rcgen::{KeyPair, PKCS_ECDSA_P256_SHA256, PKCS_ED25519};
 use ring::{
     error::Unspecified,
     hkdf::{KeyType, Okm, Prk, Salt, HKDF_SHA256},
 };
-use rustls_pki_types::PrivateKeyDer;
+use rustls_pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+use zeroize::Zeroizing;
 
 struct AnySizeKey(usize);
 impl KeyType for AnySizeKey {
@@ -21,7 +22,20 @@ pub fn derive_ecdsa_key(
     context_data: &[&[u8]],
     key_size: usize,
 ) -> Result<Vec<u8>, Unspecified> {
-    let salt = Salt::new(HKDF_SHA256, b"RATLS");
+    derive_key_with_salt(input_key_material, b"RATLS", context_data, key_size)
+}
+
+/// Derives a key using HKDF-SHA256 with the given salt.
+///
+/// HKDF concatenates the context data, so keys for different purposes must be told apart by
+/// the salt rather than by a context prefix.
+fn derive_key_with_salt(
+    input_key_material: &[u8],
+    salt: &[u8],
+    context_data: &[&[u8]],
+    key_size: usize,
+) -> Result<Vec<u8>, Unspecified> {
+    let salt = Salt::new(HKDF_SHA256, salt);
     let pseudo_rand_key: Prk = salt.extract(input_key_material);
     let output_key_material: Okm<AnySizeKey> =
         pseudo_rand_key.expand(context_data, AnySizeKey(key_size))?;
@@ -30,13 +44,19 @@ pub fn derive_ecdsa_key(
     Ok(result)
 }
 
-/// Derives a key pair from a given key pair.
-pub fn derive_ecdsa_key_pair(from: &KeyPair, context_data: &[&[u8]]) -> Result<KeyPair> {
+/// The raw P-256 secret of a key pair.
+fn p256_secret_bytes(from: &KeyPair) -> Result<impl AsRef<[u8]>> {
     let der_bytes = from.serialized_der();
     let sk = p256::SecretKey::from_pkcs8_der(der_bytes).context("failed to decode secret key")?;
-    let sk_bytes = sk.as_scalar_primitive().to_bytes();
+    Ok(sk.as_scalar_primitive().to_bytes())
+}
+
+/// Derives a key pair from a given key pair.
+pub fn derive_ecdsa_key_pair(from: &KeyPair, context_data: &[&[u8]]) -> Result<KeyPair> {
+    let sk_bytes = p256_secret_bytes(from)?;
+    let sk_bytes = sk_bytes.as_ref();
     let derived_sk_bytes =
-        derive_ecdsa_key(&sk_bytes, context_data, 32).or(Err(anyhow!("failed to derive key")))?;
+        derive_ecdsa_key(sk_bytes, context_data, 32).or(Err(anyhow!("failed to derive key")))?;
     let derived_sk = p256::SecretKey::from_slice(&derived_sk_bytes)
         .context("failed to decode derived secret key")?;
     let derived_sk_der = derived_sk
@@ -49,6 +69,42 @@ pub fn derive_ecdsa_key_pair(from: &KeyPair, context_data: &[&[u8]]) -> Result<K
     Ok(key)
 }
 
+/// Derives an Ed25519 key pair from a given P-256 key pair.
+///
+/// The seed is derived with a salt of its own, so it differs from any ECDSA key derived from
+/// the same key pair.
+pub fn derive_ed25519_key_pair(from: &KeyPair, context_data: &[&[u8]]) -> Result<KeyPair> {
+    // PKCS#8 v1 encoding of an Ed25519 private key, followed by the 32 byte seed (RFC 8410)
+    const PKCS8_PREFIX: [u8; 16] = [
+        0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04,
+        0x20,
+    ];
+    let sk_bytes = p256_secret_bytes(from)?;
+    let seed = derive_key_with_salt(sk_bytes.as_ref(), b"RATLS-ed25519", context_data, 32)
+        .or(Err(anyhow!("failed to derive key")))?;
+    let der = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+        [&PKCS8_PREFIX[..], &seed].concat(),
+    ));
+    let key = KeyPair::from_der_and_sign_algo(&der, &PKCS_ED25519)
+        .context("failed to create derived key pair")?;
+    Ok(key)
+}
+
+/// Derives a X25519 secret from a given P-256 key pair.
+///
+/// Unlike [`derive_dh_secret`], the secret is derived with a salt of its own, so it can not be
+/// recomputed from an ECDSA key derived from the same key pair. The secret is wiped on drop.
+pub fn derive_x25519_secret(from: &KeyPair, context_data: &[&[u8]]) -> Result<Zeroizing<[u8; 32]>> {
+    let sk_bytes = p256_secret_bytes(from)?;
+    let okm = Zeroizing::new(
+        derive_key_with_salt(sk_bytes.as_ref(), b"RATLS-x25519", context_data, 32)
+            .or(Err(anyhow!("failed to derive key")))?,
+    );
+    let mut secret = Zeroizing::new([0u8; 32]);
+    secret.copy_from_slice(&okm);
+    Ok(secret)
+}
+
 fn sha256(data: &[u8]) -> [u8; 32] {
     use sha2::{Digest, Sha256};
     let mut hasher = Sha256::new();
@@ -86,4 +142,49 @@ mod tests {
         let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
         let _derived_key = derive_ecdsa_key_pair(&key, &[b"context one"]).unwrap();
     }
+
+    #[test]
+    fn test_derive_ed25519_key_pair() {
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let derived = derive_ed25519_key_pair(&key, &[b"context one"]).unwrap();
+        assert_eq!(derived.algorithm(), &PKCS_ED25519);
+        assert_eq!(derived.public_key_raw().len(), 32);
+        let again = derive_ed25519_key_pair(&key, &[b"context one"]).unwrap();
+        assert_eq!(derived.serialize_pem(), again.serialize_pem());
+        let other = derive_ed25519_key_pair(&key, &[b"context two"]).unwrap();
+        assert_ne!(derived.public_key_raw(), other.public_key_raw());
+    }
+
+    #[test]
+    fn test_ed25519_seed_differs_from_ecdsa_key() {
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let ed25519 = derive_ed25519_key_pair(&key, &[b"foo"]).unwrap();
+        // The seed is the tail of the PKCS#8 encoding
+        let der = ed25519.serialized_der();
+        let seed = &der[der.len() - 32..];
+        for context in [
+            &[b"ed25519foo".as_slice()][..],
+            &[b"ed25519", b"foo"],
+            &[b"foo"],
+        ] {
+            let ecdsa = derive_ecdsa_key_pair(&key, context).unwrap();
+            assert_ne!(p256_secret_bytes(&ecdsa).unwrap().as_ref(), seed);
+        }
+    }
+
+    #[test]
+    fn test_x25519_secret_differs_from_ecdsa_key() {
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let secret = derive_x25519_secret(&key, &[b"provisioning-key", b"foo"]).unwrap();
+        let again = derive_x25519_secret(&key, &[b"provisioning-key", b"foo"]).unwrap();
+        assert_eq!(secret, again);
+        // derive_key with the single path "provisioning-keyfoo" yields the same HKDF info
+        let ecdsa = derive_ecdsa_key_pair(&key, &[b"provisioning-keyfoo"]).unwrap();
+        assert_ne!(sha256(ecdsa.serialized_der()), *secret);
+        assert_ne!(p256_secret_bytes(&ecdsa).unwrap().as_ref(), *secret);
+        assert_ne!(
+            derive_dh_secret(&key, &[b"provisioning-key", b"foo"]).unwrap(),
+            *secret
+        );
+    }
 }
@@ -9,3 +9,4 @@ pub mod cert;
 pub mod kdf;
 pub mod oids;
 pub mod traits;
+pub mod verify;
@@ -0,0 +1,257 @@
+//! Offline verification of certificate chains issued by an app CA.
+
+use anyhow::{anyhow, bail, Context, Result};
+use rcgen::KeyPair;
+use x509_parser::{
+    certificate::X509Certificate,
+    der_parser::Oid,
+    pem::{parse_x509_pem, Pem},
+};
+
+use crate::attestation::Attestation;
+
+/// A certificate chain that passed [`verify_cert_chain`].
+#[derive(Debug, Clone)]
+pub struct VerifiedChain {
+    /// DER encoded SubjectPublicKeyInfo of the leaf certificate.
+    pub leaf_public_key: Vec<u8>,
+    /// App id decoded from the CA attestation.
+    pub app_id: String,
+    /// Attestation embedded in the CA certificate.
+    ///
+    /// The quote is checked to be bound to the CA key and consistent with the event log, but its
+    /// signature is not verified as that requires fetching collateral.
+    pub ca_attestation: Attestation,
+}
+
+/// Verify a certificate chain returned by `derive_key`, ordered from the leaf to the CA.
+///
+/// Checks that each certificate is signed by the next one and currently valid, that the last one is
+/// exactly `ca_pem` and carries an attestation bound to its key, and that `key_pem`, if given,
+/// matches the leaf certificate.
+///
+/// The CA must be pinned rather than matched by the app id in its attestation: the quote
+/// signature is not verified here, so anyone could self-sign a CA claiming any app id.
+pub fn verify_cert_chain(
+    chain: &[String],
+    key_pem: Option<&str>,
+    ca_pem: &str,
+) -> Result<VerifiedChain> {
+    if chain.len() < 2 {
+        bail!("certificate chain too short");
+    }
+    let pems = chain
+        .iter()
+        .map(|pem| {
+            parse_x509_pem(pem.as_bytes())
+                .map(|(_, pem)| pem)
+                .map_err(|err| anyhow!("invalid pem: {err}"))
+        })
+        .collect::<Result<Vec<Pem>>>()?;
+    let certs = pems
+        .iter()
+        .map(|pem| pem.parse_x509().context("invalid x509 certificate"))
+        .collect::<Result<Vec<_>>>()?;
+
+    for (i, cert) in certs.iter().enumerate() {
+        let issuer = certs.get(i + 1).unwrap_or(cert);
+        cert.verify_signature(Some(issuer.public_key()))
+            .map_err(|err| anyhow!("invalid signature of certificate {i}: {err}"))?;
+        if !cert.validity().is_valid() {
+            bail!("certificate {i} is expired or not yet valid");
+        }
+        if i > 0 && !cert.is_ca() {
+            bail!("certificate {i} is not a CA");
+        }
+    }
+
+    let (_, expected) =
+        parse_x509_pem(ca_pem.as_bytes()).map_err(|err| anyhow!("invalid pem: {err}"))?;
+    if pems.last().context("empty certificate chain")?.contents != expected.contents {
+        bail!("unexpected CA certificate");
+    }
+    let ca = certs.last().context("empty certificate chain")?;
+    let ca_attestation = attested_ca(ca)?;
+    let app_id = ca_attestation.decode_app_id()?;
+
+    let leaf_public_key = certs[0].public_key().raw.to_vec();
+    if let Some(key_pem) = key_pem {
+        let key = KeyPair::from_pem(key_pem).context("invalid private key")?;
+        if key.public_key_der() != leaf_public_key {
+            bail!("private key does not match the leaf certificate");
+        }
+    }
+
+    Ok(VerifiedChain {
+        leaf_public_key,
+        app_id,
+        ca_attestation,
+    })
+}
+
+/// Verify an app CA certificate, such as the `app_cert` reported by tappd, and return its
+/// attestation.
+///
+/// With `kms_root_pem`, the certificate must be issued by that KMS root CA. The KMS verifies the
+/// attestation of a CVM before issuing its app certificate, and the quote in it is bound to the
+/// RA-TLS key the CVM requested it with rather than to the app key. Without it, the certificate
+/// must be a self-signed CA whose quote is bound to its own key, as made by the local key
+/// provider.
+///
+/// Either way the event log is checked against the quote, but the quote signature is not
+/// verified as that requires fetching collateral.
+pub fn verify_app_ca(ca_pem: &str, kms_root_pem: Option<&str>) -> Result<Attestation> {
+    let (_, pem) =
+        parse_x509_pem(ca_pem.as_bytes()).map_err(|err| anyhow!("invalid pem: {err}"))?;
+    let ca = pem.parse_x509().context("invalid x509 certificate")?;
+    if !ca.validity().is_valid() {
+        bail!("CA certificate is expired or not yet valid");
+    }
+    let Some(kms_root_pem) = kms_root_pem else {
+        ca.verify_signature(None)
+            .map_err(|err| anyhow!("invalid signature of the CA certificate: {err}"))?;
+        return attested_ca(&ca);
+    };
+    let (_, root_pem) =
+        parse_x509_pem(kms_root_pem.as_bytes()).map_err(|err| anyhow!("invalid pem: {err}"))?;
+    let root = root_pem.parse_x509().context("invalid x509 certificate")?;
+    if !root.validity().is_valid() {
+        bail!("KMS root certificate is expired or not yet valid");
+    }
+    ca.verify_signature(Some(root.public_key()))
+        .map_err(|err| anyhow!("CA certificate is not issued by the KMS root: {err}"))?;
+    if !ca.is_ca() {
+        bail!("certificate is not a CA");
+    }
+    let ca_attestation = decode_attestation(&ca)?.context("CA certificate has no attestation")?;
+    check_event_log(&ca_attestation)?;
+    Ok(ca_attestation)
+}
+
+/// Decode the attestation of a CA certificate and check it is bound to the CA key and
+/// consistent with its event log.
+fn attested_ca(ca: &X509Certificate) -> Result<Attestation> {
+    let ca_attestation = decode_attestation(ca)?.context("CA certificate has no attestation")?;
+    ca_attestation
+        .ensure_quote_for_ra_tls_pubkey(ca.public_key().raw)
+        .context("CA attestation is not bound to the CA key")?;
+    check_event_log(&ca_attestation)?;
+    Ok(ca_attestation)
+}
+
+/// Check that replaying the event log of an attestation reproduces the RTMRs of its quote.
+fn check_event_log(attestation: &Attestation) -> Result<()> {
+    let quote = attestation.decode_quote()?;
+    let rtmrs = attestation.replay_event_logs()?;
+    let report = match quote.report {
+        qvl::quote::Report::SgxEnclave(_) => bail!("SGX reports are not supported"),
+        qvl::quote::Report::TD10(report) => report,
+        qvl::quote::Report::TD15(report) => report.base,
+    };
+    if rtmrs != [report.rt_mr0, report.rt_mr1, report.rt_mr2, report.rt_mr3] {
+        bail!("CA event log does not match the quote");
+    }
+    Ok(())
+}
+
+fn decode_attestation(cert: &X509Certificate) -> Result<Option<Attestation>> {
+    let extensions = cert.tbs_certificate.extensions();
+    Attestation::from_ext_getter(|oid| {
+        let oid = Oid::from(oid).or(Err(anyhow!("Invalid oid")))?;
+        let Some(ext) = extensions.iter().find(|ext| ext.oid == oid) else {
+            return Ok(None);
+        };
+        Ok(Some(ext.value.to_vec()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cert::CertRequest;
+    use rcgen::PKCS_ECDSA_P256_SHA256;
+
+    fn new_ca() -> (rcgen::Certificate, KeyPair) {
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let cert = CertRequest::builder()
+            .subject("Test CA")
+            .ca_level(1)
+            .key(&key)
+            .build()
+            .self_signed()
+            .unwrap();
+        (cert, key)
+    }
+
+    fn new_leaf(ca: &rcgen::Certificate, ca_key: &KeyPair) -> (rcgen::Certificate, KeyPair) {
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let cert = CertRequest::builder()
+            .subject("Test Leaf")
+            .key(&key)
+            .build()
+            .signed_by(ca, ca_key)
+            .unwrap();
+        (cert, key)
+    }
+
+    #[test]
+    fn rejects_chain_with_wrong_issuer() {
+        let (ca, _) = new_ca();
+        let (other_ca, other_key) = new_ca();
+        let (leaf, _) = new_leaf(&other_ca, &other_key);
+        let chain = vec![leaf.pem(), ca.pem()];
+        let err = verify_cert_chain(&chain, None, &ca.pem()).unwrap_err();
+        assert!(err.to_string().contains("invalid signature"), "{err:?}");
+    }
+
+    #[test]
+    fn rejects_unpinned_ca() {
+        let (ca, _) = new_ca();
+        let (other_ca, other_key) = new_ca();
+        let (leaf, _) = new_leaf(&other_ca, &other_key);
+        let chain = vec![leaf.pem(), other_ca.pem()];
+        let err = verify_cert_chain(&chain, None, &ca.pem()).unwrap_err();
+        assert!(err.to_string().contains("unexpected CA"), "{err:?}");
+    }
+
+    #[test]
+    fn rejects_ca_without_attestation() {
+        let (ca, ca_key) = new_ca();
+        let (leaf, _) = new_leaf(&ca, &ca_key);
+        let chain = vec![leaf.pem(), ca.pem()];
+        let err = verify_cert_chain(&chain, None, &ca.pem()).unwrap_err();
+        assert!(err.to_string().contains("no attestation"), "{err:?}");
+    }
+
+    #[test]
+    fn app_ca_requires_attestation() {
+        let (ca, _) = new_ca();
+        let err = verify_app_ca(&ca.pem(), None).unwrap_err();
+        assert!(err.to_string().contains("no attestation"), "{err:?}");
+    }
+
+    #[test]
+    fn app_ca_issued_by_kms_root() {
+        let (root, root_key) = new_ca();
+        let (other_root, _) = new_ca();
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let app_ca = CertRequest::builder()
+            .subject("Test App CA")
+            .ca_level(1)
+            .key(&key)
+            .build()
+            .signed_by(&root, &root_key)
+            .unwrap();
+
+        let err = verify_app_ca(&app_ca.pem(), Some(&other_root.pem())).unwrap_err();
+        assert!(
+            err.to_string().contains("not issued by the KMS root"),
+            "{err:?}"
+        );
+        let err = verify_app_ca(&app_ca.pem(), Some(&root.pem())).unwrap_err();
+        assert!(err.to_string().contains("no attestation"), "{err:?}");
+        // Without the KMS root, an app CA issued by the KMS is not self-signed
+        let err = verify_app_ca(&app_ca.pem(), None).unwrap_err();
+        assert!(err.to_string().contains("invalid signature"), "{err:?}");
+    }
+}
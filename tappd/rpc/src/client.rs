@@ -0,0 +1,232 @@
+//! High level client for apps running in a CVM to talk to tappd over its local socket.
+
+use anyhow::{bail, Context, Result};
+use prpc::client::{Error, RequestClient};
+
+use crate::tappd_client::TappdClient;
+use crate::{
+    DeriveKeyArgs, DeriveKeyResponse, ProvisioningKeyArgs, ProvisioningKeyResponse, RawQuoteArgs,
+    TdxQuoteArgs, TdxQuoteResponse, WorkerInfo,
+};
+
+/// The socket tappd listens on inside the CVM.
+pub const DEFAULT_ENDPOINT: &str = "unix:/var/run/tappd.sock";
+
+/// Environment variable pointing to a tappd simulator, used when no endpoint is given.
+pub const SIMULATOR_ENDPOINT_ENV: &str = "DSTACK_SIMULATOR_ENDPOINT";
+
+/// Sends prpc requests to tappd, which serves them under `/prpc`.
+pub struct TappdTransport {
+    base_url: String,
+}
+
+impl RequestClient for TappdTransport {
+    async fn request(&self, path: &str, body: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let path = format!("/prpc/{}", path.trim_start_matches('/'));
+        let (status, body) = http_client::http_request("POST", &self.base_url, &path, &body)
+            .await
+            .map_err(|err| Error::RpcError(format!("failed to send request: {err:?}")))?;
+        if status != 200 {
+            return Err(Error::RpcError(format!(
+                "invalid status code: {status}, body: {}",
+                String::from_utf8_lossy(&body)
+            )));
+        }
+        Ok(body)
+    }
+}
+
+/// Hash algorithm applied by tappd to the report data of a quote.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QuoteHashAlgorithm {
+    Sha256,
+    Sha384,
+    #[default]
+    Sha512,
+    Sha3_256,
+    Sha3_384,
+    Sha3_512,
+    Keccak256,
+    Keccak384,
+    Keccak512,
+    /// Pass the report data to the TDX module as is, at most 64 bytes.
+    Raw,
+}
+
+impl QuoteHashAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Sha384 => "sha384",
+            Self::Sha512 => "sha512",
+            Self::Sha3_256 => "sha3-256",
+            Self::Sha3_384 => "sha3-384",
+            Self::Sha3_512 => "sha3-512",
+            Self::Keccak256 => "keccak256",
+            Self::Keccak384 => "keccak384",
+            Self::Keccak512 => "keccak512",
+            Self::Raw => "raw",
+        }
+    }
+}
+
+pub struct GuestClient {
+    client: TappdClient<TappdTransport>,
+}
+
+impl GuestClient {
+    /// Create a client talking to tappd at `endpoint`, e.g. `unix:/var/run/tappd.sock` or
+    /// `http://localhost:8090`. A bare path is taken as a unix socket.
+    pub fn new(endpoint: &str) -> Self {
+        let base_url = if endpoint.starts_with('/') {
+            format!("unix:{endpoint}")
+        } else {
+            endpoint.to_string()
+        };
+        Self {
+            client: TappdClient::new(TappdTransport { base_url }),
+        }
+    }
+
+    /// Create a client for the simulator if `DSTACK_SIMULATOR_ENDPOINT` is set, or for the
+    /// local tappd socket otherwise.
+    pub fn from_env() -> Self {
+        match std::env::var(SIMULATOR_ENDPOINT_ENV) {
+            Ok(endpoint) if !endpoint.is_empty() => Self::new(&endpoint),
+            _ => Self::new(DEFAULT_ENDPOINT),
+        }
+    }
+
+    /// Derive a key for `path`, with a certificate whose subject is the path itself.
+    pub async fn derive_key(&self, path: &str) -> Result<DeriveKeyResponse> {
+        self.derive_key_with_cert(path, path, &[]).await
+    }
+
+    /// Derive a key for `path`, with a certificate for the given subject and alt names.
+    pub async fn derive_key_with_cert(
+        &self,
+        path: &str,
+        subject: &str,
+        alt_names: &[String],
+    ) -> Result<DeriveKeyResponse> {
+        validate_key_path(path)?;
+        self.client
+            .derive_key(DeriveKeyArgs {
+                path: path.to_string(),
+                subject: subject.to_string(),
+                alt_names: alt_names.to_vec(),
+                curve: None,
+                path_components: vec![],
+                public_only: false,
+            })
+            .await
+            .context("Failed to derive key")
+    }
+
+    /// Get a quote of `report_data`, hashed by tappd with the given algorithm.
+    pub async fn tdx_quote(
+        &self,
+        report_data: &[u8],
+        hash_algorithm: QuoteHashAlgorithm,
+    ) -> Result<TdxQuoteResponse> {
+        self.client
+            .tdx_quote(TdxQuoteArgs {
+                report_data: quote_report_data(report_data, hash_algorithm)?,
+                hash_algorithm: hash_algorithm.as_str().to_string(),
+            })
+            .await
+            .context("Failed to get quote")
+    }
+
+    /// Get a quote over exactly `report_data`, with no prefix or hashing applied by tappd.
+    pub async fn raw_quote(&self, report_data: [u8; 64]) -> Result<TdxQuoteResponse> {
+        self.client
+            .raw_quote(RawQuoteArgs {
+                report_data: report_data.to_vec(),
+            })
+            .await
+            .context("Failed to get quote")
+    }
+
+    /// Get an attested X25519 key pair a remote provisioner can encrypt secrets to.
+    pub async fn provisioning_key(&self, path: &str) -> Result<ProvisioningKeyResponse> {
+        validate_key_path(path)?;
+        self.client
+            .get_provisioning_key(ProvisioningKeyArgs {
+                path: path.to_string(),
+            })
+            .await
+            .context("Failed to get provisioning key")
+    }
+
+    /// Get the app id, instance id, app certificate and TCB info of the CVM.
+    pub async fn info(&self) -> Result<WorkerInfo> {
+        self.client.info().await.context("Failed to get info")
+    }
+}
+
+/// The report data to send for a quote. Raw report data is zero padded to 64 bytes and may not
+/// be longer, as it is not hashed; otherwise tappd hashes it, so any length is passed as is.
+fn quote_report_data(report_data: &[u8], hash_algorithm: QuoteHashAlgorithm) -> Result<Vec<u8>> {
+    if report_data.is_empty() {
+        bail!("report data can not be empty");
+    }
+    let mut report_data = report_data.to_vec();
+    if hash_algorithm == QuoteHashAlgorithm::Raw {
+        if report_data.len() > 64 {
+            bail!("report data must be at most 64 bytes when the hash algorithm is raw");
+        }
+        report_data.resize(64, 0);
+    }
+    Ok(report_data)
+}
+
+/// Key paths are `/` separated segments, e.g. `wallet/eth/0`.
+fn validate_key_path(path: &str) -> Result<()> {
+    if path.is_empty() {
+        bail!("key path can not be empty");
+    }
+    if path.split('/').any(str::is_empty) {
+        bail!("key path must not contain empty segments: {path:?}");
+    }
+    if path.chars().any(|c| c.is_control() || c.is_whitespace()) {
+        bail!("key path must not contain whitespace or control characters: {path:?}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_key_path() {
+        for path in ["app", "wallet/eth/0", "a-b_c.d/ü"] {
+            validate_key_path(path).unwrap();
+        }
+        for path in ["", "/app", "app/", "a//b", "a b", "a\tb", "a\0b"] {
+            assert!(validate_key_path(path).is_err(), "{path:?} is accepted");
+        }
+    }
+
+    #[test]
+    fn test_quote_report_data() {
+        let raw = quote_report_data(b"hello", QuoteHashAlgorithm::Raw).unwrap();
+        assert_eq!(raw.len(), 64);
+        assert_eq!(&raw[..5], b"hello");
+        assert!(raw[5..].iter().all(|&b| b == 0));
+        assert_eq!(
+            quote_report_data(&[1; 64], QuoteHashAlgorithm::Raw).unwrap(),
+            [1u8; 64]
+        );
+        // Raw report data is never truncated
+        assert!(quote_report_data(&[1; 65], QuoteHashAlgorithm::Raw).is_err());
+        // Hashed by tappd, so passed through at any length
+        assert_eq!(
+            quote_report_data(&[1; 100], QuoteHashAlgorithm::Sha512).unwrap(),
+            [1u8; 100]
+        );
+        assert!(quote_report_data(b"", QuoteHashAlgorithm::Sha256).is_err());
+        assert!(quote_report_data(b"", QuoteHashAlgorithm::Raw).is_err());
+    }
+}
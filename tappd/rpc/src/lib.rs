@@ -3,3 +3,6 @@ extern crate alloc;
 pub use generated::*;
 
 mod generated;
+
+#[cfg(feature = "client")]
+pub mod client;
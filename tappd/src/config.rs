@@ -1,9 +1,12 @@
+use log_config::LoggingConfig;
 use rocket::figment::{
     providers::{Format, Toml},
     Figment,
 };
 use serde::Deserialize;
 
+use crate::quote_provider::QuoteProviderKind;
+
 pub const CONFIG_FILENAME: &str = "tappd.toml";
 pub const SYSTEM_CONFIG_FILENAME: &str = "/etc/tappd/tappd.toml";
 pub const DEFAULT_CONFIG: &str = include_str!("../tappd.toml");
@@ -25,4 +28,66 @@ pub struct Config {
     pub key_file: String,
     pub public_logs: bool,
     pub public_sysinfo: bool,
+    pub quote: QuoteConfig,
+    pub event_log: EventLogConfig,
+    pub docker: DockerConfig,
+    pub derive_key: DeriveKeyConfig,
+    pub sys_info: SysInfoConfig,
+    /// Path to the runtime event log written by tdxctl
+    pub event_log_file: String,
+    /// Path to the app compose file the CVM was launched with
+    pub compose_file: String,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeriveKeyConfig {
+    /// Max number of key derivations signed concurrently
+    pub max_concurrent: usize,
+    /// How long a call waits for a free slot before failing as busy, in milliseconds
+    pub acquire_timeout_ms: u64,
+    /// Max number of calls per caller per minute, 0 to disable.
+    /// Callers are told apart by remote endpoint, so all clients of the unix socket share
+    /// one budget.
+    pub max_per_minute: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SysInfoConfig {
+    /// Also report pseudo filesystems such as tmpfs and overlay in the disk list
+    pub include_pseudo_fs: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DockerConfig {
+    /// Give up connecting to dockerd after this many milliseconds
+    pub connect_timeout_ms: u64,
+    /// Initial delay between connection attempts in milliseconds, doubled on each retry
+    pub retry_delay_ms: u64,
+    /// Max delay between connection attempts in milliseconds
+    pub max_retry_delay_ms: u64,
+    /// Give up waiting for a sample of container stats after this many milliseconds
+    pub stats_timeout_ms: u64,
+    /// Timeout of the single Docker ping done by the health probe in milliseconds
+    pub ping_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventLogConfig {
+    /// Max size in bytes of the event log returned with a quote
+    pub max_size: u64,
+    /// Timeout for reading the event log in milliseconds
+    pub read_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct QuoteConfig {
+    /// Backend generating the quotes, `tdx` or `mock`
+    #[serde(default)]
+    pub provider: QuoteProviderKind,
+    /// Max number of retries when the TDX device is busy
+    pub max_retries: u32,
+    /// Base delay between retries in milliseconds, doubled on each retry
+    pub retry_delay_ms: u64,
 }
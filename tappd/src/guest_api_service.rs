@@ -1,19 +1,31 @@
-use std::{path::Path, process::Command};
+use std::{
+    collections::HashMap,
+    path::Path,
+    process::Command,
+    time::{Duration, Instant},
+};
 
 use anyhow::{Context, Result};
-use bollard::{container::ListContainersOptions, Docker};
+use bollard::{
+    container::{InspectContainerOptions, ListContainersOptions, StatsOptions},
+    Docker,
+};
 use fs_err as fs;
+use futures::StreamExt;
 use guest_api::{
     guest_api_server::{GuestApiRpc, GuestApiServer},
-    Container, DiskInfo, Gateway, GuestInfo, Interface, IpAddress, ListContainersResponse,
-    NetworkInformation, SystemInfo,
+    Container, DiskInfo, Gateway, GuestInfo, Interface, IpAddress, ListContainersRequest,
+    ListContainersResponse, NetworkInformation, SystemInfo,
 };
 use host_api::Notification;
 use ra_rpc::{CallContext, RpcCall};
 use serde::Deserialize;
-use tappd_rpc::worker_server::WorkerRpc as _;
+use tappd_rpc::{
+    worker_server::WorkerRpc as _, ContainerDetails, ContainerMount, ContainerPort, ContainerStats,
+};
+use tracing::warn;
 
-use crate::{rpc_service::ExternalRpcHandler, AppState};
+use crate::{config::DockerConfig, rpc_service::ExternalRpcHandler, AppState};
 
 #[derive(Deserialize)]
 struct LocalConfig {
@@ -79,21 +91,28 @@ impl GuestApiRpc for GuestApiHandler {
     async fn sys_info(self) -> Result<SystemInfo> {
         use sysinfo::System;
 
-        let system = System::new_all();
+        let mut system = System::new_all();
+        // Usage is computed between two refreshes, which must be far enough apart
+        tokio::time::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL).await;
+        system.refresh_cpu_usage();
+        let (cpu_usage, per_core_usage) = cpu_usage(&system);
         let cpus = system.cpus();
 
         let disks = sysinfo::Disks::new_with_refreshed_list();
         let disks = disks
             .list()
             .iter()
-            .filter(|d| d.mount_point() == Path::new("/"))
             .map(|d| DiskInfo {
                 name: d.name().to_string_lossy().to_string(),
                 mount_point: d.mount_point().to_string_lossy().to_string(),
                 total_size: d.total_space(),
                 free_size: d.available_space(),
+                fs_type: d.file_system().to_string_lossy().to_string(),
+                encrypted: is_dm_crypt(Path::new(d.name())),
+                read_only: d.is_read_only(),
             })
             .collect::<Vec<_>>();
+        let disks = select_disks(disks, self.state.config().sys_info.include_pseudo_fs);
         let avg = System::load_average();
         Ok(SystemInfo {
             os_name: System::name().unwrap_or_default(),
@@ -115,21 +134,129 @@ impl GuestApiRpc for GuestApiHandler {
             loadavg_five: (avg.five * 100.0) as u32,
             loadavg_fifteen: (avg.fifteen * 100.0) as u32,
             disks,
+            per_core_usage,
+            cpu_usage,
         })
     }
 
-    async fn list_containers(self) -> Result<ListContainersResponse> {
-        list_containers().await
+    async fn list_containers(
+        self,
+        request: ListContainersRequest,
+    ) -> Result<ListContainersResponse> {
+        list_containers(&self.state.config().docker, &request).await
+    }
+}
+
+/// The aggregate and per-core CPU usage of a refreshed system, in percent.
+fn cpu_usage(system: &sysinfo::System) -> (f32, Vec<f32>) {
+    let per_core = system.cpus().iter().map(|cpu| cpu.cpu_usage()).collect();
+    (system.global_cpu_usage(), per_core)
+}
+
+/// Filesystems that are not backed by a disk of the CVM
+const PSEUDO_FILESYSTEMS: &[&str] = &[
+    "tmpfs", "devtmpfs", "ramfs", "overlay", "proc", "sysfs", "devpts", "cgroup", "cgroup2",
+];
+
+/// Drop pseudo filesystems unless asked for, and order the disks by mount point with `/` first,
+/// where clients that only look at the first entry expect it.
+fn select_disks(mut disks: Vec<DiskInfo>, include_pseudo_fs: bool) -> Vec<DiskInfo> {
+    if !include_pseudo_fs {
+        disks.retain(|d| !PSEUDO_FILESYSTEMS.contains(&d.fs_type.as_str()));
+    }
+    disks.sort_by(|a, b| {
+        (a.mount_point != "/", &a.mount_point).cmp(&(b.mount_point != "/", &b.mount_point))
+    });
+    disks
+}
+
+/// Check whether a block device is a dm-crypt mapping, e.g. `/dev/mapper/rootfs_crypt`.
+fn is_dm_crypt(device: &Path) -> bool {
+    let Ok(device) = fs::canonicalize(device) else {
+        return false;
+    };
+    let Some(name) = device.file_name() else {
+        return false;
+    };
+    let uuid_path = Path::new("/sys/block").join(name).join("dm/uuid");
+    fs::read_to_string(uuid_path)
+        .map(|uuid| uuid.starts_with("CRYPT-"))
+        .unwrap_or(false)
+}
+
+/// Connect to dockerd, retrying with backoff while it is not up yet, e.g. early at boot.
+async fn connect_docker(config: &DockerConfig) -> Result<Docker> {
+    let deadline = Instant::now() + Duration::from_millis(config.connect_timeout_ms);
+    let mut delay = Duration::from_millis(config.retry_delay_ms);
+    loop {
+        let result = async {
+            let docker = Docker::connect_with_defaults()?;
+            docker.ping().await?;
+            Ok::<_, bollard::errors::Error>(docker)
+        }
+        .await;
+        match result {
+            Ok(docker) => return Ok(docker),
+            Err(err) if Instant::now() + delay < deadline => {
+                warn!("Docker is not available, retrying in {delay:?}: {err}");
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(Duration::from_millis(config.max_retry_delay_ms));
+            }
+            Err(err) => return Err(err).context("Failed to connect to Docker"),
+        }
+    }
+}
+
+/// Ping Docker once, without the retries of connect_docker.
+pub(crate) async fn ping_docker(config: &DockerConfig) -> Result<()> {
+    let timeout = Duration::from_millis(config.ping_timeout_ms);
+    let ping = async {
+        Docker::connect_with_defaults()?.ping().await?;
+        Ok::<_, bollard::errors::Error>(())
+    };
+    tokio::time::timeout(timeout, ping)
+        .await
+        .context("Timed out pinging Docker")?
+        .context("Failed to ping Docker")
+}
+
+/// Escape the characters Go's regexp treats specially, as Docker matches names with it.
+fn escape_regex(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Docker list options for the filters of a ListContainers request.
+fn list_containers_options(request: &ListContainersRequest) -> ListContainersOptions<String> {
+    let mut filters = HashMap::new();
+    if !request.name_prefix.is_empty() {
+        // Docker prefixes container names with a slash
+        let name = format!("^/{}", escape_regex(&request.name_prefix));
+        filters.insert("name".to_string(), vec![name]);
+    }
+    if !request.labels.is_empty() {
+        filters.insert("label".to_string(), request.labels.clone());
+    }
+    ListContainersOptions {
+        all: !request.running_only,
+        filters,
+        ..Default::default()
     }
 }
 
-pub(crate) async fn list_containers() -> Result<ListContainersResponse> {
-    let docker = Docker::connect_with_defaults().context("Failed to connect to Docker")?;
+pub(crate) async fn list_containers(
+    config: &DockerConfig,
+    request: &ListContainersRequest,
+) -> Result<ListContainersResponse> {
+    let docker = connect_docker(config).await?;
     let containers = docker
-        .list_containers::<&str>(Some(ListContainersOptions {
-            all: true,
-            ..Default::default()
-        }))
+        .list_containers(Some(list_containers_options(request)))
         .await
         .context("Failed to list containers")?;
     Ok(ListContainersResponse {
@@ -149,6 +276,138 @@ pub(crate) async fn list_containers() -> Result<ListContainersResponse> {
     })
 }
 
+/// CPU usage between two samples in percent, 100 per fully used core.
+fn cpu_percent(total: u64, pre_total: u64, system: u64, pre_system: u64, online_cpus: u64) -> f64 {
+    let cpu_delta = total.saturating_sub(pre_total);
+    let system_delta = system.saturating_sub(pre_system);
+    if cpu_delta == 0 || system_delta == 0 {
+        return 0.0;
+    }
+    cpu_delta as f64 / system_delta as f64 * online_cpus.max(1) as f64 * 100.0
+}
+
+pub(crate) async fn container_stats(
+    config: &DockerConfig,
+    id_or_name: &str,
+) -> Result<ContainerStats> {
+    let docker = connect_docker(config).await?;
+    let c = docker
+        .inspect_container(id_or_name, None::<InspectContainerOptions>)
+        .await
+        .context("Failed to inspect container")?;
+    if !c.state.and_then(|s| s.running).unwrap_or(false) {
+        return Ok(ContainerStats::default());
+    }
+    // Without streaming, Docker takes two samples so the CPU usage can be computed
+    let options = StatsOptions {
+        stream: false,
+        one_shot: false,
+    };
+    let mut stream = std::pin::pin!(docker.stats(id_or_name, Some(options)));
+    let timeout = Duration::from_millis(config.stats_timeout_ms);
+    let stats = tokio::time::timeout(timeout, stream.next())
+        .await
+        .context("Timed out waiting for container stats")?
+        .context("No stats returned for the container")?
+        .context("Failed to get container stats")?;
+    let (cpu, precpu) = (&stats.cpu_stats, &stats.precpu_stats);
+    let (network_rx_bytes, network_tx_bytes) = stats
+        .networks
+        .unwrap_or_default()
+        .values()
+        .fold((0, 0), |(rx, tx), n| (rx + n.rx_bytes, tx + n.tx_bytes));
+    Ok(ContainerStats {
+        running: true,
+        cpu_percent: cpu_percent(
+            cpu.cpu_usage.total_usage,
+            precpu.cpu_usage.total_usage,
+            cpu.system_cpu_usage.unwrap_or_default(),
+            precpu.system_cpu_usage.unwrap_or_default(),
+            cpu.online_cpus.unwrap_or_default(),
+        ),
+        memory_usage: stats.memory_stats.usage.unwrap_or_default(),
+        memory_limit: stats.memory_stats.limit.unwrap_or_default(),
+        network_rx_bytes,
+        network_tx_bytes,
+    })
+}
+
+pub(crate) async fn inspect_container(
+    config: &DockerConfig,
+    id_or_name: &str,
+) -> Result<ContainerDetails> {
+    let docker = connect_docker(config).await?;
+    let c = docker
+        .inspect_container(id_or_name, None::<InspectContainerOptions>)
+        .await
+        .context("Failed to inspect container")?;
+    let state = c.state.unwrap_or_default();
+    let ports = c
+        .network_settings
+        .as_ref()
+        .and_then(|n| n.ports.clone())
+        .unwrap_or_default()
+        .into_iter()
+        .flat_map(|(port, bindings)| {
+            let (container_port, protocol) = port.split_once('/').unwrap_or((&port, "tcp"));
+            let container_port = container_port.parse().unwrap_or_default();
+            let protocol = protocol.to_string();
+            match bindings.filter(|b| !b.is_empty()) {
+                Some(bindings) => bindings
+                    .into_iter()
+                    .map(|b| ContainerPort {
+                        container_port,
+                        protocol: protocol.clone(),
+                        host_ip: b.host_ip.unwrap_or_default(),
+                        host_port: b.host_port.and_then(|p| p.parse().ok()).unwrap_or_default(),
+                    })
+                    .collect(),
+                None => vec![ContainerPort {
+                    container_port,
+                    protocol,
+                    ..Default::default()
+                }],
+            }
+        })
+        .collect();
+    let networks = c
+        .network_settings
+        .and_then(|n| n.networks)
+        .map(|networks| networks.into_keys().collect())
+        .unwrap_or_default();
+    let mounts = c
+        .mounts
+        .unwrap_or_default()
+        .into_iter()
+        .map(|m| ContainerMount {
+            destination: m.destination.unwrap_or_default(),
+            r#type: m.typ.map(|t| t.to_string()).unwrap_or_default(),
+            rw: m.rw.unwrap_or_default(),
+        })
+        .collect();
+    Ok(ContainerDetails {
+        id: c.id.unwrap_or_default(),
+        name: c
+            .name
+            .map(|name| name.trim_start_matches('/').to_string())
+            .unwrap_or_default(),
+        image: c.config.and_then(|c| c.image).unwrap_or_default(),
+        status: state.status.map(|s| s.to_string()).unwrap_or_default(),
+        health: state
+            .health
+            .and_then(|h| h.status)
+            .map(|s| s.to_string())
+            .unwrap_or_default(),
+        restart_count: c.restart_count.unwrap_or_default(),
+        exit_code: state.exit_code.unwrap_or_default(),
+        started_at: state.started_at.unwrap_or_default(),
+        finished_at: state.finished_at.unwrap_or_default(),
+        ports,
+        networks,
+        mounts,
+    })
+}
+
 fn get_interfaces() -> Vec<Interface> {
     sysinfo::Networks::new_with_refreshed_list()
         .into_iter()
@@ -224,3 +483,79 @@ fn run_command(command: &str) -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpu_usage() {
+        let mut system = sysinfo::System::new_all();
+        std::thread::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
+        system.refresh_cpu_usage();
+        let (total, per_core) = cpu_usage(&system);
+        assert_eq!(per_core.len(), system.cpus().len());
+        assert!((0.0..=100.0).contains(&total), "{total}");
+    }
+
+    #[test]
+    fn test_select_disks() {
+        let disk = |mount_point: &str, fs_type: &str| DiskInfo {
+            mount_point: mount_point.into(),
+            fs_type: fs_type.into(),
+            ..Default::default()
+        };
+        let disks = vec![
+            disk("/data", "ext4"),
+            disk("/run", "tmpfs"),
+            disk("/", "ext4"),
+            disk("/var/lib/docker/overlay2/x/merged", "overlay"),
+            disk("/boot", "vfat"),
+        ];
+        let mount_points = |disks: Vec<DiskInfo>| -> Vec<String> {
+            disks.into_iter().map(|d| d.mount_point).collect()
+        };
+        assert_eq!(
+            mount_points(select_disks(disks.clone(), false)),
+            ["/", "/boot", "/data"]
+        );
+        assert_eq!(
+            mount_points(select_disks(disks, true)),
+            [
+                "/",
+                "/boot",
+                "/data",
+                "/run",
+                "/var/lib/docker/overlay2/x/merged"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cpu_percent() {
+        // Half of one of four cores: 50ms of CPU time in 400ms of system time
+        assert_eq!(cpu_percent(150, 100, 1400, 1000, 4), 50.0);
+        assert_eq!(cpu_percent(100, 100, 1400, 1000, 4), 0.0);
+        // The first sample has no previous one to compare with
+        assert_eq!(cpu_percent(150, 0, 1000, 1000, 4), 0.0);
+    }
+
+    #[test]
+    fn test_list_containers_options() {
+        let options = list_containers_options(&Default::default());
+        assert!(options.all);
+        assert!(options.filters.is_empty());
+
+        let options = list_containers_options(&ListContainersRequest {
+            name_prefix: "app.web".into(),
+            labels: vec!["com.example.role=sidecar".into(), "monitored".into()],
+            running_only: true,
+        });
+        assert!(!options.all);
+        assert_eq!(options.filters["name"], ["^/app\\.web"]);
+        assert_eq!(
+            options.filters["label"],
+            ["com.example.role=sidecar", "monitored"]
+        );
+    }
+}
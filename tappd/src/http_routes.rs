@@ -73,6 +73,7 @@ async fn index(state: &State<AppState>) -> Result<RawHtml<String>, String> {
         instance_id,
         tcb_info,
         app_cert,
+        ..
     } = handler
         .info()
         .await
@@ -82,7 +83,10 @@ async fn index(state: &State<AppState>) -> Result<RawHtml<String>, String> {
         .map_err(|e| format!("Failed to construct RPC handler: {}", e))?;
     let system_info = handler.sys_info().await.unwrap_or_default();
 
-    let containers = list_containers().await.unwrap_or_default().containers;
+    let containers = list_containers(&config.docker, &Default::default())
+        .await
+        .unwrap_or_default()
+        .containers;
     let model = crate::models::Dashboard {
         app_id,
         instance_id,
@@ -18,6 +18,7 @@ mod guest_api_routes;
 mod guest_api_service;
 mod http_routes;
 mod models;
+mod quote_provider;
 mod rpc_service;
 
 fn app_version() -> String {
@@ -148,15 +149,17 @@ async fn run_watchdog() {
 
 #[rocket::main]
 async fn main() -> Result<()> {
-    {
-        use tracing_subscriber::{fmt, EnvFilter};
-        let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
-        fmt().with_env_filter(filter).init();
-    }
     let args = Args::parse();
     let figment = config::load_config_figment(args.config.as_deref());
-    let state =
-        AppState::new(figment.focus("core").extract()?).context("Failed to create app state")?;
+    let config: config::Config = figment.focus("core").extract()?;
+    config.logging.init()?;
+    tdx_attest::eventlog::set_runtime_event_log_config(
+        tdx_attest::eventlog::RuntimeEventLogConfig {
+            path: config.event_log_file.clone().into(),
+            ..Default::default()
+        },
+    )?;
+    let state = AppState::new(config).context("Failed to create app state")?;
     let internal_figment = figment.clone().select("internal");
     let external_figment = figment.clone().select("external");
     let external_https_figment = figment.clone().select("external-https");
@@ -0,0 +1,78 @@
+//! Backends that generate quotes for tappd.
+
+use anyhow::Result;
+use serde::Deserialize;
+use tdx_attest::{TdxAttestError, TdxReportData};
+
+/// Prefix of the quotes produced by [`MockQuoteProvider`]
+pub const MOCK_QUOTE_HEADER: &[u8] = b"DSTACK-MOCK-QUOTE:";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuoteProviderKind {
+    /// Quotes from the TDX attestation device
+    #[default]
+    Tdx,
+    /// Unsigned quotes generated in software, for testing without TEE hardware
+    Mock,
+}
+
+/// A source of quotes binding the given report data.
+pub trait QuoteProvider: Send + Sync + 'static {
+    /// Generate a quote carrying `report_data`.
+    fn get_quote(&self, report_data: &TdxReportData) -> Result<Vec<u8>>;
+
+    /// Whether an error returned by `get_quote` may go away when retried.
+    fn is_transient(&self, _err: &anyhow::Error) -> bool {
+        false
+    }
+}
+
+pub struct TdxQuoteProvider;
+
+impl QuoteProvider for TdxQuoteProvider {
+    fn get_quote(&self, report_data: &TdxReportData) -> Result<Vec<u8>> {
+        let (_, quote) = tdx_attest::get_quote(report_data, None)?;
+        Ok(quote)
+    }
+
+    fn is_transient(&self, err: &anyhow::Error) -> bool {
+        err.downcast_ref::<TdxAttestError>()
+            .is_some_and(TdxAttestError::is_transient)
+    }
+}
+
+/// Produces `MOCK_QUOTE_HEADER || report_data`. These quotes carry no signature and will not
+/// pass verification; they only let the RPC surface run on machines without a TEE.
+pub struct MockQuoteProvider;
+
+impl QuoteProvider for MockQuoteProvider {
+    fn get_quote(&self, report_data: &TdxReportData) -> Result<Vec<u8>> {
+        let mut quote = MOCK_QUOTE_HEADER.to_vec();
+        quote.extend_from_slice(report_data);
+        Ok(quote)
+    }
+}
+
+pub fn create_provider(kind: QuoteProviderKind) -> Box<dyn QuoteProvider> {
+    match kind {
+        QuoteProviderKind::Tdx => Box::new(TdxQuoteProvider),
+        QuoteProviderKind::Mock => Box::new(MockQuoteProvider),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_quote_carries_report_data() {
+        let report_data = [0x5a; 64];
+        let quote = MockQuoteProvider.get_quote(&report_data).unwrap();
+        assert_eq!(
+            quote.strip_prefix(MOCK_QUOTE_HEADER),
+            Some(&report_data[..])
+        );
+        assert!(!MockQuoteProvider.is_transient(&anyhow::anyhow!("busy")));
+    }
+}
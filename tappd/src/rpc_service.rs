@@ -1,22 +1,44 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
 
-use anyhow::{bail, Context, Result};
-use ra_rpc::{CallContext, RpcCall};
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::{write::GzEncoder, Compression};
+use fs_err as fs;
+use ra_rpc::{CallContext, RemoteEndpoint, RpcCall};
 use ra_tls::{
-    attestation::QuoteContentType,
+    attestation::{verify_event_logs, QuoteContentType},
     cert::{CaCert, CertRequest},
-    kdf::derive_ecdsa_key_pair,
-    qvl::quote::Report,
+    kdf::{derive_ecdsa_key_pair, derive_ed25519_key_pair, derive_x25519_secret},
+    qvl::quote::{Quote, Report},
 };
+use serde::Serialize;
 use serde_json::json;
 use tappd_rpc::{
     tappd_server::{TappdRpc, TappdServer},
     worker_server::{WorkerRpc, WorkerServer},
-    DeriveKeyArgs, DeriveKeyResponse, TdxQuoteArgs, TdxQuoteResponse, WorkerInfo, WorkerVersion,
+    AppComposeResponse, AttestationBundle, AttestationBundleArgs, Capabilities, Capability,
+    ContainerDetails, ContainerStats, DeriveKeyArgs, DeriveKeyResponse, InspectContainerArgs,
+    ProvisioningKeyArgs, ProvisioningKeyResponse, RawQuoteArgs, TdxQuoteArgs, TdxQuoteResponse,
+    VerifyEventLogArgs, VerifyEventLogResponse, WorkerHealth, WorkerInfo, WorkerVersion,
+};
+use tdx_attest::{
+    eventlog::{runtime_event_log_config, EventLogs, TdxEventLog},
+    TdxReportData,
 };
-use tdx_attest::eventlog::read_event_logs;
+use tokio::sync::Semaphore;
+use tracing::warn;
+use zeroize::Zeroizing;
 
 use crate::config::Config;
+use crate::guest_api_service::{container_stats, inspect_container, notify_host, ping_docker};
+use crate::quote_provider::{create_provider, QuoteProvider, QuoteProviderKind};
+
+/// Version of the attestation bundle format
+const ATTESTATION_BUNDLE_VERSION: u32 = 1;
 
 #[derive(Clone)]
 pub struct AppState {
@@ -26,6 +48,15 @@ pub struct AppState {
 struct AppStateInner {
     config: Config,
     ca: CaCert,
+    quote_provider: Box<dyn QuoteProvider>,
+    /// Serializes access to the attestation device. Held on a blocking thread only.
+    quote_lock: std::sync::Mutex<()>,
+    /// Bounds the number of concurrent derive_key signing operations
+    derive_key_slots: Semaphore,
+    /// Per-caller derive_key call counts in the current one minute window
+    derive_key_windows: std::sync::Mutex<HashMap<String, (Instant, u32)>>,
+    /// Worker info decoded from the attestation of the CA certificate on first use
+    worker_info: OnceLock<WorkerInfo>,
 }
 
 impl AppState {
@@ -33,55 +64,443 @@ impl AppState {
         let ca = CaCert::load(&config.cert_file, &config.key_file)
             .context("Failed to load CA certificate")?;
         Ok(Self {
-            inner: Arc::new(AppStateInner { config, ca }),
+            inner: Arc::new(AppStateInner {
+                derive_key_slots: Semaphore::new(config.derive_key.max_concurrent.max(1)),
+                derive_key_windows: Default::default(),
+                quote_provider: create_provider(config.quote.provider),
+                config,
+                ca,
+                quote_lock: Default::default(),
+                worker_info: OnceLock::new(),
+            }),
         })
     }
 
     pub fn config(&self) -> &Config {
         &self.inner.config
     }
+
+    /// The worker info, decoded once as the attestation is fixed for the life of the process.
+    fn worker_info(&self) -> &WorkerInfo {
+        self.inner.worker_info.get_or_init(|| {
+            decode_worker_info(&self.inner.ca).unwrap_or_else(|err| {
+                warn!("Failed to decode the attestation: {err:#}");
+                WorkerInfo::default()
+            })
+        })
+    }
+
+    /// Read the event log as JSON, refusing logs larger than `event_log.max_size` or taking
+    /// longer than `event_log.read_timeout_ms`.
+    ///
+    /// A timed out read can not be cancelled, so the read itself is bounded by the size limit
+    /// too, in case the log grows after the size check.
+    async fn read_event_log(&self) -> Result<String> {
+        let cfg = &self.config().event_log;
+        let runtime_log = runtime_event_log_config();
+        let runtime_size: u64 = runtime_log
+            .segments()
+            .context("Failed to list event log files")?
+            .iter()
+            .chain([&runtime_log.path])
+            .map(|path| fs::metadata(path).map(|m| m.len()).unwrap_or(0))
+            .sum();
+        if runtime_size > cfg.max_size {
+            bail!(
+                "event log is too large: {runtime_size} bytes, max is {} bytes",
+                cfg.max_size
+            );
+        }
+        let timeout = Duration::from_millis(cfg.read_timeout_ms);
+        let max_size = cfg.max_size;
+        let event_log = tokio::time::timeout(
+            timeout,
+            tokio::task::spawn_blocking(move || -> Result<String> {
+                let mut event_log = EventLogs::decode_from_ccel_file()
+                    .and_then(|logs| logs.to_tdx_event_logs())
+                    .context("Failed to decode event log")?;
+                event_log.extend(
+                    runtime_log
+                        .read_limited(max_size)
+                        .context("Failed to decode event log")?,
+                );
+                serde_json::to_string(&event_log).context("Failed to serialize event log")
+            }),
+        )
+        .await
+        .map_err(|_| anyhow!("reading the event log timed out after {timeout:?}"))?
+        .context("Failed to read event log")??;
+        if event_log.len() as u64 > cfg.max_size {
+            bail!(
+                "event log is too large: {} bytes, max is {} bytes",
+                event_log.len(),
+                cfg.max_size
+            );
+        }
+        Ok(event_log)
+    }
+
+    /// Count a derive_key call against the caller's budget for the current minute.
+    fn check_derive_key_rate(&self, caller: &str) -> Result<()> {
+        let max_per_minute = self.config().derive_key.max_per_minute;
+        if max_per_minute == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut windows = self
+            .inner
+            .derive_key_windows
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        windows.retain(|_, (start, _)| now.duration_since(*start) < Duration::from_secs(60));
+        let (_, count) = windows.entry(caller.to_string()).or_insert((now, 0));
+        if *count >= max_per_minute {
+            bail!("derive_key rate limited: at most {max_per_minute} calls per minute");
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    /// Derive a key and sign its certificate, bounded by `derive_key.max_concurrent`.
+    async fn derive_key(&self, caller: &str, request: DeriveKeyArgs) -> Result<DeriveKeyResponse> {
+        self.check_derive_key_rate(caller)?;
+        let timeout = Duration::from_millis(self.config().derive_key.acquire_timeout_ms);
+        let _permit = tokio::time::timeout(timeout, self.inner.derive_key_slots.acquire())
+            .await
+            .map_err(|_| anyhow!("derive_key is busy, try again later"))?
+            .context("Failed to acquire derive_key slot")?;
+        let state = self.clone();
+        tokio::task::spawn_blocking(move || derive_key_response(&state.inner.ca, &request))
+            .await
+            .context("Failed to derive key")?
+    }
+
+    /// Get a quote, retrying with backoff if the device is busy.
+    async fn get_quote(&self, report_data: &TdxReportData) -> Result<Vec<u8>> {
+        let cfg = &self.config().quote;
+        let mut delay = Duration::from_millis(cfg.retry_delay_ms);
+        let mut attempt = 0;
+        loop {
+            // The device call blocks, keep it off the async workers
+            let inner = self.inner.clone();
+            let report_data = *report_data;
+            let result = tokio::task::spawn_blocking(move || {
+                let _guard = inner
+                    .quote_lock
+                    .lock()
+                    .unwrap_or_else(|err| err.into_inner());
+                inner.quote_provider.get_quote(&report_data)
+            })
+            .await
+            .context("Quote task failed")?;
+            match result {
+                Ok(quote) => return Ok(quote),
+                Err(err)
+                    if self.inner.quote_provider.is_transient(&err)
+                        && attempt < cfg.max_retries =>
+                {
+                    attempt += 1;
+                    warn!(
+                        "quote device busy, retrying in {delay:?} ({attempt}/{})",
+                        cfg.max_retries
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = delay.saturating_mul(2);
+                }
+                Err(err) => return Err(err.context("Failed to get quote")),
+            }
+        }
+    }
+}
+
+/// Fill `tcb_info` with the measurements of `info` and the event log, for clients that predate
+/// the structured fields.
+fn with_tcb_info(info: WorkerInfo, event_log: &impl Serialize) -> WorkerInfo {
+    let tcb_info = serde_json::to_string_pretty(&json!({
+        "rootfs_hash": info.rootfs_hash,
+        "mrtd": info.mrtd,
+        "rtmr0": info.rtmr0,
+        "rtmr1": info.rtmr1,
+        "rtmr2": info.rtmr2,
+        "rtmr3": info.rtmr3,
+        "event_log": event_log,
+    }))
+    .unwrap_or_default();
+    WorkerInfo { tcb_info, ..info }
+}
+
+/// Decode the worker info from the attestation of the CA certificate.
+fn decode_worker_info(ca: &CaCert) -> Result<WorkerInfo> {
+    let Some(attestation) = ca
+        .decode_attestation()
+        .context("Failed to decode attestation")?
+    else {
+        return Ok(WorkerInfo::default());
+    };
+    let app_id = attestation
+        .decode_app_id()
+        .context("Failed to decode app id")?;
+    let instance_id = attestation
+        .decode_instance_id()
+        .context("Failed to decode instance_id")?;
+    let quote = attestation
+        .decode_quote()
+        .context("Failed to decode quote")?;
+    let rootfs_hash = attestation
+        .decode_rootfs_hash()
+        .context("Failed to decode rootfs hash")?;
+    let report = match &quote.report {
+        Report::SgxEnclave(_) => bail!("SGX reports are not supported"),
+        Report::TD10(tdreport10) => tdreport10,
+        Report::TD15(tdreport15) => &tdreport15.base,
+    };
+    let info = WorkerInfo {
+        app_id,
+        instance_id,
+        app_cert: ca.pem_cert.clone(),
+        mrtd: hex::encode(report.mr_td),
+        rtmr0: hex::encode(report.rt_mr0),
+        rtmr1: hex::encode(report.rt_mr1),
+        rtmr2: hex::encode(report.rt_mr2),
+        rtmr3: hex::encode(report.rt_mr3),
+        rootfs_hash,
+        tcb_info: String::new(),
+    };
+    Ok(with_tcb_info(info, &attestation.event_log))
+}
+
+/// Derive the key of a derive_key request and sign a certificate for it.
+fn derive_key_response(ca: &CaCert, request: &DeriveKeyArgs) -> Result<DeriveKeyResponse> {
+    let context = key_path_context(request)?;
+    let context: Vec<&[u8]> = context.iter().map(Vec::as_slice).collect();
+    // Wipe the serialized private key once done, whether or not it is returned
+    let derived_key = Zeroizing::new(
+        match request.curve.as_deref().unwrap_or("ecdsa-p256") {
+            "ecdsa-p256" => derive_ecdsa_key_pair(&ca.key, &context),
+            "ed25519" => derive_ed25519_key_pair(&ca.key, &context),
+            other => bail!("unsupported curve {other:?}, expected ecdsa-p256 or ed25519"),
+        }
+        .context("Failed to derive key")?,
+    );
+    let req = CertRequest::builder()
+        .subject(&request.subject)
+        .alt_names(&request.alt_names)
+        .key(&derived_key)
+        .build();
+    let cert = ca.sign(req).context("Failed to sign certificate")?;
+    let key = if request.public_only {
+        String::new()
+    } else {
+        derived_key.serialize_pem()
+    };
+    Ok(DeriveKeyResponse {
+        key,
+        certificate_chain: vec![cert.pem(), ca.cert.pem()],
+        public_key: derived_key.public_key_pem(),
+    })
+}
+
+/// The KDF context of a derive_key request. A single path component derives the same key as the
+/// deprecated `path`, several are each prefixed with their length so that the split between
+/// them matters.
+///
+/// The KDF concatenates the context, and that of several components starts with a NUL byte. A
+/// single path may therefore not contain NUL, or it could spell out the context of a
+/// multi-component path.
+fn key_path_context(request: &DeriveKeyArgs) -> Result<Vec<Vec<u8>>> {
+    let single_path = |path: &str| {
+        if path.contains('\0') {
+            bail!("a single key path must not contain NUL");
+        }
+        Ok(vec![path.as_bytes().to_vec()])
+    };
+    match request.path_components.as_slice() {
+        [] => single_path(&request.path),
+        _ if !request.path.is_empty() => {
+            bail!("path and path_components are mutually exclusive")
+        }
+        [component] => single_path(component),
+        components => {
+            let mut context = vec![b"\0path-components".to_vec()];
+            for component in components {
+                context.push((component.len() as u32).to_be_bytes().to_vec());
+                context.push(component.as_bytes().to_vec());
+            }
+            Ok(context)
+        }
+    }
 }
 
 pub struct InternalRpcHandler {
     state: AppState,
+    /// Identifies the caller for rate limiting
+    caller: String,
 }
 
 impl TappdRpc for InternalRpcHandler {
     async fn derive_key(self, request: DeriveKeyArgs) -> Result<DeriveKeyResponse> {
-        let derived_key =
-            derive_ecdsa_key_pair(&self.state.inner.ca.key, &[request.path.as_bytes()])
-                .context("Failed to derive key")?;
-        let req = CertRequest::builder()
-            .subject(&request.subject)
-            .alt_names(&request.alt_names)
-            .key(&derived_key)
-            .build();
-        let cert = self
-            .state
-            .inner
-            .ca
-            .sign(req)
-            .context("Failed to sign certificate")?;
-        Ok(DeriveKeyResponse {
-            key: derived_key.serialize_pem(),
-            certificate_chain: vec![cert.pem(), self.state.inner.ca.cert.pem()],
-        })
+        self.state.derive_key(&self.caller, request).await
     }
 
     async fn tdx_quote(self, request: TdxQuoteArgs) -> Result<TdxQuoteResponse> {
         let report_data = QuoteContentType::AppData
             .to_report_data_with_hash(&request.report_data, &request.hash_algorithm)?;
-        let event_log = read_event_logs().context("Failed to decode event log")?;
-        let event_log =
-            serde_json::to_string(&event_log).context("Failed to serialize event log")?;
-        let (_, quote) =
-            tdx_attest::get_quote(&report_data, None).context("Failed to get quote")?;
+        let event_log = self.state.read_event_log().await?;
+        let quote = self.state.get_quote(&report_data).await?;
         Ok(TdxQuoteResponse { quote, event_log })
     }
 
+    async fn raw_quote(self, request: RawQuoteArgs) -> Result<TdxQuoteResponse> {
+        let report_data: TdxReportData =
+            request.report_data.try_into().map_err(|data: Vec<u8>| {
+                anyhow!("report data must be exactly 64 bytes, got {}", data.len())
+            })?;
+        let event_log = self.state.read_event_log().await?;
+        let quote = self.state.get_quote(&report_data).await?;
+        Ok(TdxQuoteResponse { quote, event_log })
+    }
+
+    async fn verify_event_log(self, request: VerifyEventLogArgs) -> Result<VerifyEventLogResponse> {
+        let quote = Quote::parse(&request.quote).context("Failed to parse quote")?;
+        let report = match &quote.report {
+            Report::SgxEnclave(_) => bail!("SGX reports are not supported"),
+            Report::TD10(tdreport10) => tdreport10,
+            Report::TD15(tdreport15) => &tdreport15.base,
+        };
+        let event_log: Vec<TdxEventLog> =
+            serde_json::from_str(&request.event_log).context("Failed to parse event log")?;
+        let rtmrs = [report.rt_mr0, report.rt_mr1, report.rt_mr2, report.rt_mr3];
+        let verification = verify_event_logs(&event_log, &rtmrs)?;
+        Ok(VerifyEventLogResponse {
+            valid: verification.is_valid(),
+            invalid_event: verification.invalid_event.map(|i| i as u32),
+            mismatched_rtmr: verification.mismatched_rtmr.map(|i| i as u32),
+            replayed_rtmrs: verification.replayed.iter().map(hex::encode).collect(),
+        })
+    }
+
     async fn info(self) -> Result<WorkerInfo> {
         ExternalRpcHandler { state: self.state }.info().await
     }
+
+    async fn get_provisioning_key(
+        self,
+        request: ProvisioningKeyArgs,
+    ) -> Result<ProvisioningKeyResponse> {
+        let secret = derive_x25519_secret(
+            &self.state.inner.ca.key,
+            &[b"provisioning-key", request.path.as_bytes()],
+        )
+        .context("Failed to derive provisioning key")?;
+        // The secret and the StaticSecret copy are wiped on drop. The copy in the response is
+        // owned by the RPC layer once returned.
+        let public_key = x25519_dalek::PublicKey::from(&x25519_dalek::StaticSecret::from(*secret));
+        let report_data = QuoteContentType::ProvisioningKey.to_report_data(public_key.as_bytes());
+        let event_log = self.state.read_event_log().await?;
+        let quote = self.state.get_quote(&report_data).await?;
+        Ok(ProvisioningKeyResponse {
+            private_key: secret.to_vec(),
+            public_key: public_key.as_bytes().to_vec(),
+            quote,
+            event_log,
+        })
+    }
+
+    async fn inspect_container(self, request: InspectContainerArgs) -> Result<ContainerDetails> {
+        inspect_container(&self.state.config().docker, &request.id_or_name).await
+    }
+
+    async fn container_stats(self, request: InspectContainerArgs) -> Result<ContainerStats> {
+        container_stats(&self.state.config().docker, &request.id_or_name).await
+    }
+
+    async fn get_app_compose(self) -> Result<AppComposeResponse> {
+        let compose_file = fs::read_to_string(&self.state.config().compose_file)
+            .context("Failed to read app compose file")?;
+        let compose_hash = app_compose::compose_hash(&compose_file);
+        let recomputed_app_id = app_compose::app_id_of(&compose_file);
+        let attestation = self.state.inner.ca.decode_attestation().ok().flatten();
+        let app_id = attestation
+            .as_ref()
+            .and_then(|a| a.decode_app_id().ok())
+            .unwrap_or_default();
+        let measured_compose_hash = attestation
+            .as_ref()
+            .and_then(|a| a.decode_compose_hash().ok())
+            .unwrap_or_default();
+        let tampered = attestation.is_some() && measured_compose_hash != compose_hash;
+        if tampered {
+            warn!(
+                "app compose file does not match the measured compose hash {measured_compose_hash}"
+            );
+        }
+        Ok(AppComposeResponse {
+            app_id_matches: app_id == recomputed_app_id,
+            compose_file,
+            compose_hash,
+            recomputed_app_id,
+            app_id,
+            measured_compose_hash,
+            tampered,
+        })
+    }
+
+    async fn notify_ready(self) -> Result<()> {
+        notify_host("app.ready", "")
+            .await
+            .context("Failed to notify the host")
+    }
+
+    async fn export_attestation_bundle(
+        self,
+        request: AttestationBundleArgs,
+    ) -> Result<AttestationBundle> {
+        let info = ExternalRpcHandler {
+            state: self.state.clone(),
+        }
+        .info()
+        .await?;
+        let tcb_info: serde_json::Value = if info.tcb_info.is_empty() {
+            serde_json::Value::Null
+        } else {
+            serde_json::from_str(&info.tcb_info).context("Failed to parse tcb info")?
+        };
+        let payload = serde_json::to_string(&json!({
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "app_id": info.app_id,
+            "instance_id": info.instance_id,
+            "tcb_info": tcb_info,
+            "app_cert_chain": [info.app_cert],
+        }))
+        .context("Failed to serialize bundle payload")?;
+        let report_data = QuoteContentType::AttestationBundle.to_report_data(payload.as_bytes());
+        let event_log = self.state.read_event_log().await?;
+        let event_log: serde_json::Value =
+            serde_json::from_str(&event_log).context("Failed to parse event log")?;
+        let quote = self.state.get_quote(&report_data).await?;
+        let content = serde_json::to_vec(&json!({
+            "version": ATTESTATION_BUNDLE_VERSION,
+            "payload": payload,
+            "quote": hex::encode(quote),
+            "event_log": event_log,
+        }))
+        .context("Failed to serialize bundle")?;
+        let (format, content) = if request.compress {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder
+                .write_all(&content)
+                .context("Failed to compress bundle")?;
+            let content = encoder.finish().context("Failed to compress bundle")?;
+            ("json+gzip", content)
+        } else {
+            ("json", content)
+        };
+        Ok(AttestationBundle {
+            version: ATTESTATION_BUNDLE_VERSION,
+            format: format.to_string(),
+            content,
+        })
+    }
 }
 
 impl RpcCall<AppState> for InternalRpcHandler {
@@ -95,12 +514,52 @@ impl RpcCall<AppState> for InternalRpcHandler {
     where
         Self: Sized,
     {
+        let caller = match context.remote_endpoint {
+            Some(RemoteEndpoint::Tcp(addr)) | Some(RemoteEndpoint::Quic(addr)) => {
+                addr.ip().to_string()
+            }
+            Some(RemoteEndpoint::Vsock { cid, .. }) => format!("vsock:{cid}"),
+            Some(endpoint) => format!("{endpoint:?}"),
+            None => "unknown".to_string(),
+        };
         Ok(InternalRpcHandler {
             state: context.state.clone(),
+            caller,
         })
     }
 }
 
+/// Build the health report from the outcome of probing each subsystem.
+fn worker_health(docker: Result<()>, attestation: Result<bool>) -> WorkerHealth {
+    let mut problems = vec![];
+    let docker_reachable = match docker {
+        Ok(()) => true,
+        Err(err) => {
+            problems.push(format!("docker: {err:#}"));
+            false
+        }
+    };
+    let attestation = match attestation {
+        Ok(true) => "ok",
+        Ok(false) => {
+            problems.push("attestation: no attestation in the CA certificate".into());
+            "missing"
+        }
+        Err(err) => {
+            problems.push(format!("attestation: {err:#}"));
+            "invalid"
+        }
+    };
+    WorkerHealth {
+        ok: problems.is_empty(),
+        // AppState can not be created without the CA certificate
+        ca_cert_loaded: true,
+        docker_reachable,
+        attestation: attestation.into(),
+        problems,
+    }
+}
+
 pub struct ExternalRpcHandler {
     state: AppState,
 }
@@ -113,49 +572,77 @@ impl ExternalRpcHandler {
 
 impl WorkerRpc for ExternalRpcHandler {
     async fn info(self) -> Result<WorkerInfo> {
-        let ca = &self.state.inner.ca;
-        let Some(attestation) = ca.decode_attestation().ok().flatten() else {
-            return Ok(WorkerInfo::default());
-        };
-        let app_id = attestation
-            .decode_app_id()
-            .context("Failed to decode app id")?;
-        let instance_id = attestation
-            .decode_instance_id()
-            .context("Failed to decode instance_id")?;
-        let quote = attestation
-            .decode_quote()
-            .context("Failed to decode quote")?;
-        let rootfs_hash = attestation
-            .decode_rootfs_hash()
-            .context("Failed to decode rootfs hash")?;
-        let report = match &quote.report {
-            Report::SgxEnclave(_) => bail!("SGX reports are not supported"),
-            Report::TD10(tdreport10) => tdreport10,
-            Report::TD15(tdreport15) => &tdreport15.base,
-        };
-        let event_log = &attestation.event_log;
-        let mrtd = hex::encode(report.mr_td);
-        let rtmr0 = hex::encode(report.rt_mr0);
-        let rtmr1 = hex::encode(report.rt_mr1);
-        let rtmr2 = hex::encode(report.rt_mr2);
-        let rtmr3 = hex::encode(report.rt_mr3);
-        let tcb_info = serde_json::to_string_pretty(&json!({
-            "rootfs_hash": rootfs_hash,
-            "mrtd": mrtd,
-            "rtmr0": rtmr0,
-            "rtmr1": rtmr1,
-            "rtmr2": rtmr2,
-            "rtmr3": rtmr3,
-            "event_log": event_log,
-        }))
-        .unwrap_or_default();
-        Ok(WorkerInfo {
-            app_id,
-            instance_id,
-            app_cert: ca.pem_cert.clone(),
-            tcb_info,
-        })
+        Ok(self.state.worker_info().clone())
+    }
+
+    async fn health(self) -> Result<WorkerHealth> {
+        let docker = ping_docker(&self.state.config().docker).await;
+        let attestation = self
+            .state
+            .inner
+            .ca
+            .decode_attestation()
+            .map(|attestation| attestation.is_some());
+        Ok(worker_health(docker, attestation))
+    }
+
+    async fn capabilities(self) -> Result<Capabilities> {
+        fn cap(name: &str, enabled: bool, limits: &[(&str, u64)]) -> Capability {
+            Capability {
+                name: name.to_string(),
+                enabled,
+                limits: limits.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+            }
+        }
+        let config = self.state.config();
+        let capabilities = vec![
+            cap(
+                "derive_key",
+                true,
+                &[
+                    ("max_concurrent", config.derive_key.max_concurrent as u64),
+                    ("max_per_minute", config.derive_key.max_per_minute as u64),
+                ],
+            ),
+            cap(
+                "tdx_quote",
+                true,
+                &[
+                    ("max_raw_report_data_size", 64),
+                    ("max_retries", config.quote.max_retries as u64),
+                    ("max_event_log_size", config.event_log.max_size),
+                ],
+            ),
+            cap("raw_quote", true, &[("report_data_size", 64)]),
+            cap("verify_event_log", true, &[]),
+            cap("provisioning_key", true, &[]),
+            cap(
+                "health",
+                true,
+                &[("docker_ping_timeout_ms", config.docker.ping_timeout_ms)],
+            ),
+            cap(
+                "container_stats",
+                true,
+                &[("timeout_ms", config.docker.stats_timeout_ms)],
+            ),
+            cap(
+                "mock_quote",
+                config.quote.provider == QuoteProviderKind::Mock,
+                &[],
+            ),
+            cap("public_logs", config.public_logs, &[]),
+            cap("public_sysinfo", config.public_sysinfo, &[]),
+        ];
+        Ok(Capabilities { capabilities })
+    }
+
+    async fn inspect_container(self, request: InspectContainerArgs) -> Result<ContainerDetails> {
+        let config = self.state.config();
+        if !config.public_logs {
+            bail!("container details are not public");
+        }
+        inspect_container(&config.docker, &request.id_or_name).await
     }
 
     async fn version(self) -> Result<WorkerVersion> {
@@ -181,3 +668,151 @@ impl RpcCall<AppState> for ExternalRpcHandler {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{KeyPair, PKCS_ECDSA_P256_SHA256};
+
+    fn derive(ca_key: &KeyPair, path: &str, components: &[&str]) -> String {
+        let request = DeriveKeyArgs {
+            path: path.into(),
+            path_components: components.iter().map(|c| c.to_string()).collect(),
+            ..Default::default()
+        };
+        let context = key_path_context(&request).unwrap();
+        let context: Vec<&[u8]> = context.iter().map(Vec::as_slice).collect();
+        derive_ecdsa_key_pair(ca_key, &context)
+            .unwrap()
+            .serialize_pem()
+    }
+
+    /// A self-signed CA certificate and its key in PEM, without attestation.
+    fn generate_ca() -> (String, String) {
+        let ca_key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let mut params = rcgen::CertificateParams::new(vec![]).unwrap();
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca_cert = params.self_signed(&ca_key).unwrap();
+        (ca_cert.pem(), ca_key.serialize_pem())
+    }
+
+    #[test]
+    fn test_worker_info_cached() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let (cert, key) = generate_ca();
+        fs::write(dir.join("app-ca.cert"), cert).unwrap();
+        fs::write(dir.join("app-ca.key"), key).unwrap();
+        let config: Config = crate::config::load_config_figment(None)
+            .merge(("core.cert_file", dir.join("app-ca.cert")))
+            .merge(("core.key_file", dir.join("app-ca.key")))
+            .focus("core")
+            .extract()
+            .unwrap();
+        let state = AppState::new(config).unwrap();
+
+        let info = state.worker_info();
+        // Served from the same cached value rather than decoded again
+        assert!(std::ptr::eq(info, state.worker_info()));
+        // Without an attestation the info falls back to the default
+        assert!(info.app_cert.is_empty());
+    }
+
+    #[test]
+    fn test_derive_key_public_only() {
+        let (cert, key) = generate_ca();
+        let ca = CaCert::new(cert, key).unwrap();
+        let mut request = DeriveKeyArgs {
+            path: "app".into(),
+            subject: "app".into(),
+            public_only: true,
+            ..Default::default()
+        };
+
+        let public = derive_key_response(&ca, &request).unwrap();
+        assert!(public.key.is_empty());
+        assert_eq!(public.certificate_chain.len(), 2);
+        request.public_only = false;
+        let full = derive_key_response(&ca, &request).unwrap();
+        let full_key = KeyPair::from_pem(&full.key).unwrap();
+        assert_eq!(public.public_key, full_key.public_key_pem());
+        assert_eq!(full.public_key, public.public_key);
+    }
+
+    #[test]
+    fn test_worker_health() {
+        let health = worker_health(Ok(()), Ok(true));
+        assert!(health.ok && health.docker_reachable);
+        assert_eq!(health.attestation, "ok");
+        assert!(health.problems.is_empty());
+
+        let health = worker_health(Err(anyhow::anyhow!("connection refused")), Ok(false));
+        assert!(!health.ok && !health.docker_reachable && health.ca_cert_loaded);
+        assert_eq!(health.attestation, "missing");
+        assert_eq!(health.problems[0], "docker: connection refused");
+
+        let health = worker_health(Ok(()), Err(anyhow::anyhow!("bad extension")));
+        assert!(!health.ok && health.docker_reachable);
+        assert_eq!(health.attestation, "invalid");
+        assert_eq!(health.problems, ["attestation: bad extension"]);
+    }
+
+    #[test]
+    fn test_tcb_info_fields() {
+        let info = WorkerInfo {
+            mrtd: "aa".repeat(48),
+            rtmr0: "00".repeat(48),
+            rtmr1: "01".repeat(48),
+            rtmr2: "02".repeat(48),
+            rtmr3: "03".repeat(48),
+            rootfs_hash: "ff".repeat(32),
+            ..Default::default()
+        };
+        let info = with_tcb_info(info, &Vec::<u32>::new());
+        let tcb_info: serde_json::Value = serde_json::from_str(&info.tcb_info).unwrap();
+        assert_eq!(tcb_info["mrtd"], info.mrtd);
+        assert_eq!(tcb_info["rtmr0"], info.rtmr0);
+        assert_eq!(tcb_info["rtmr1"], info.rtmr1);
+        assert_eq!(tcb_info["rtmr2"], info.rtmr2);
+        assert_eq!(tcb_info["rtmr3"], info.rtmr3);
+        assert_eq!(tcb_info["rootfs_hash"], info.rootfs_hash);
+        assert_eq!(tcb_info["event_log"], json!([]));
+    }
+
+    #[test]
+    fn test_key_path_components() {
+        let ca_key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let ab = derive(&ca_key, "", &["ab"]);
+        assert_ne!(derive(&ca_key, "", &["a", "b"]), ab);
+        assert_ne!(
+            derive(&ca_key, "", &["a", "b"]),
+            derive(&ca_key, "", &["ab", ""])
+        );
+        assert_eq!(derive(&ca_key, "ab", &[]), ab);
+
+        let request = DeriveKeyArgs {
+            path: "a".into(),
+            path_components: vec!["b".into()],
+            ..Default::default()
+        };
+        assert!(key_path_context(&request).is_err());
+
+        // The concatenated context of ["a", "b"], which must not be reachable with a single path
+        let spelled_out = "\0path-components\0\0\0\x01a\0\0\0\x01b";
+        for (path, components) in [(spelled_out, vec![]), ("", vec![spelled_out.to_string()])] {
+            let request = DeriveKeyArgs {
+                path: path.into(),
+                path_components: components,
+                ..Default::default()
+            };
+            let err = key_path_context(&request).unwrap_err();
+            assert!(err.to_string().contains("NUL"), "{err:?}");
+        }
+        let context = key_path_context(&DeriveKeyArgs {
+            path_components: vec!["a".into(), "b".into()],
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(context.concat(), spelled_out.as_bytes());
+    }
+}
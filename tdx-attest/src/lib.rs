@@ -1,14 +1,11 @@
-use anyhow::Context;
 use eventlog::TdxEventLog;
 pub use tdx_attest_sys as sys;
 
-use std::io::Write;
 use std::ptr;
 use std::slice;
 
 use sys::*;
 
-use fs_err as fs;
 use num_enum::FromPrimitive;
 use thiserror::Error;
 
@@ -56,6 +53,13 @@ pub enum TdxAttestError {
     UnknownError(u32),
 }
 
+impl TdxAttestError {
+    /// Returns true if the error is transient and the call may succeed when retried.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::Busy)
+    }
+}
+
 pub fn get_quote(
     report_data: &TdxReportData,
     att_key_id_list: Option<&[TdxUuid]>,
@@ -109,28 +113,9 @@ pub fn get_report(report_data: &TdxReportData) -> Result<TdxReport> {
     Ok(report)
 }
 
+/// Append an event to the runtime event log, see [`eventlog::runtime_event_log_config`].
 pub fn log_rtmr_event(log: &TdxEventLog) -> anyhow::Result<()> {
-    // Append to event log
-    let logline = serde_json::to_string(&log).context("Failed to serialize event log")?;
-
-    let logfile_path = std::path::Path::new(eventlog::RUNTIME_EVENT_LOG_FILE);
-    let logfile_dir = logfile_path
-        .parent()
-        .context("Failed to get event log directory")?;
-    fs::create_dir_all(logfile_dir).context("Failed to create event log directory")?;
-
-    let mut logfile = fs::OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open(logfile_path)
-        .context("Failed to open event log file")?;
-    logfile
-        .write_all(logline.as_bytes())
-        .context("Failed to write to event log file")?;
-    logfile
-        .write_all(b"\n")
-        .context("Failed to write to event log file")?;
-    Ok(())
+    eventlog::runtime_event_log_config().append(log)
 }
 
 pub fn extend_rtmr(index: u32, event_type: u32, digest: [u8; 48]) -> Result<()> {
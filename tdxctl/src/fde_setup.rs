@@ -3,9 +3,11 @@ use std::{
     io::{Read, Write},
     path::{Path, PathBuf},
     process::{Command, Stdio},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{bail, Context, Result};
+use app_compose::RESERVED_ENV_KEYS;
 use fs_err as fs;
 use kms_rpc::GetAppKeyRequest;
 use ra_rpc::client::RaClient;
@@ -58,6 +60,20 @@ pub struct SetupFdeArgs {
     rootfs_encryption: std::primitive::bool,
 }
 
+#[derive(clap::Parser)]
+/// Decrypt the encrypted env of an app for debugging
+pub struct DecryptEnvArgs {
+    /// The encrypted-env file from the host shared directory
+    #[arg(long)]
+    env_file: PathBuf,
+    /// The app keys file holding env_crypt_key, e.g. appkeys.json in the host shared copy
+    #[arg(long)]
+    app_keys: PathBuf,
+    /// Print the values of the env vars instead of masking them
+    #[arg(long)]
+    show_values: bool,
+}
+
 fn umount(mount_point: &str) -> Result<()> {
     run_command("umount", &[mount_point]).map(|_| ())
 }
@@ -121,6 +137,14 @@ impl HostShareDir {
         self.base_dir.join("encrypted-env")
     }
 
+    fn plain_env_file(&self) -> PathBuf {
+        self.base_dir.join("plain-env")
+    }
+
+    fn host_time_file(&self) -> PathBuf {
+        self.base_dir.join("host-time")
+    }
+
     fn vm_config_file(&self) -> PathBuf {
         self.base_dir.join("config.json")
     }
@@ -147,12 +171,15 @@ struct HostShared {
     vm_config: LocalConfig,
     app_compose: AppCompose,
     encrypted_env: Vec<u8>,
+    plain_env: Vec<u8>,
+    /// Unix time in seconds when the host launched the VM
+    host_time: Option<u64>,
     instance_info: InstanceInfo,
 }
 
 impl HostShared {
     fn load(host_shared_dir: &HostShareDir) -> Result<Self> {
-        let vm_config = deserialize_json_file(host_shared_dir.vm_config_file())?;
+        let vm_config = LocalConfig::load(host_shared_dir.vm_config_file())?;
         let app_compose = deserialize_json_file(host_shared_dir.app_compose_file())?;
         let instance_info_file = host_shared_dir.instance_info_file();
         let instance_info = if instance_info_file.exists() {
@@ -161,16 +188,97 @@ impl HostShared {
             InstanceInfo::default()
         };
         let encrypted_env = fs::read(host_shared_dir.encrypted_env_file()).unwrap_or_default();
+        let plain_env = fs::read(host_shared_dir.plain_env_file()).unwrap_or_default();
+        let host_time = fs::read_to_string(host_shared_dir.host_time_file())
+            .ok()
+            .and_then(|t| t.trim().parse().ok());
         Ok(Self {
             dir: host_shared_dir.clone(),
             vm_config,
             app_compose,
             encrypted_env,
+            plain_env,
+            host_time,
             instance_info,
         })
     }
 }
 
+/// Step the clock forward to the launch time given by the host if the guest clock is behind it.
+/// The host is not trusted, so this only happens if the app compose allows it, the step is
+/// bounded by `max_host_time_step`, and the clock is never moved backwards: that could make
+/// expired certificates look valid again.
+fn sync_clock_from_host(app_compose: &AppCompose, host_time: Option<u64>) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let Some(host_time) = host_time_step(app_compose, host_time, now) else {
+        return;
+    };
+    info!(
+        "Guest clock is {}s behind the host, stepping it forward",
+        host_time - now
+    );
+    if let Err(err) = run_command("date", &["-u", "-s", &format!("@{host_time}")]) {
+        warn!("Failed to set the clock: {err:?}");
+    }
+}
+
+/// The time to step the clock to, if the host time is allowed and ahead of `now` by no more
+/// than `max_host_time_step`.
+fn host_time_step(app_compose: &AppCompose, host_time: Option<u64>, now: u64) -> Option<u64> {
+    let host_time = host_time?;
+    if !app_compose.allow_host_time {
+        warn!("Ignoring the host time, the app compose does not allow it");
+        return None;
+    }
+    if now >= host_time {
+        return None;
+    }
+    if host_time - now > app_compose.max_host_time_step {
+        warn!(
+            "Ignoring the host time, it is {}s ahead of the guest clock, more than the allowed {}s",
+            host_time - now,
+            app_compose.max_host_time_step
+        );
+        return None;
+    }
+    Some(host_time)
+}
+
+/// Parse the non-secret env vars given by the host. They are not authenticated, so they are
+/// ignored unless the app compose allows them, and reserved names are refused.
+fn parse_plain_env(app_compose: &AppCompose, plain_env: &[u8]) -> Result<BTreeMap<String, String>> {
+    if plain_env.is_empty() {
+        return Ok(Default::default());
+    }
+    if !app_compose.allow_plain_env {
+        warn!("Ignoring the plain env from the host, the app compose does not allow it");
+        return Ok(Default::default());
+    }
+    info!("Processing plain env");
+    let env = env_process::parse_env(plain_env).context("Failed to parse plain env")?;
+    if let Some(key) = env
+        .keys()
+        .find(|key| RESERVED_ENV_KEYS.contains(&key.as_str()))
+    {
+        bail!("Reserved key in plain env: {key}");
+    }
+    Ok(env)
+}
+
+/// The env of the app: the plain env from the host, overridden by the decrypted env.
+fn app_env(
+    app_compose: &AppCompose,
+    plain_env: &[u8],
+    decrypted_env: BTreeMap<String, String>,
+) -> Result<BTreeMap<String, String>> {
+    let mut env = parse_plain_env(app_compose, plain_env)?;
+    env.extend(decrypted_env);
+    Ok(env)
+}
+
 fn truncate(s: &[u8], len: usize) -> &[u8] {
     if s.len() > len {
         &s[..len]
@@ -247,13 +355,7 @@ impl SetupFdeArgs {
     fn decrypt_env_vars(&self, key: &[u8], ciphertext: &[u8]) -> Result<BTreeMap<String, String>> {
         let vars = if !key.is_empty() && !ciphertext.is_empty() {
             info!("Processing encrypted env");
-            let env_crypt_key: [u8; 32] = key
-                .try_into()
-                .ok()
-                .context("Invalid env crypt key length")?;
-            let decrypted_json =
-                dh_decrypt(env_crypt_key, ciphertext).context("Failed to decrypt env file")?;
-            env_process::parse_env(&decrypted_json)?
+            decrypt_env(key, ciphertext)?
         } else {
             info!("No encrypted env, using default");
             Default::default()
@@ -498,8 +600,11 @@ impl SetupFdeArgs {
         }
         nc.notify_q("boot.progress", "decrypting env").await;
         // Decrypt env file
-        let decrypted_env =
-            self.decrypt_env_vars(&app_keys.env_crypt_key, &host_shared.encrypted_env)?;
+        let decrypted_env = app_env(
+            &host_shared.app_compose,
+            &host_shared.plain_env,
+            self.decrypt_env_vars(&app_keys.env_crypt_key, &host_shared.encrypted_env)?,
+        )?;
         let disk_crypt_key = format!("{}\n", app_keys.disk_crypt_key);
         if is_bootstrapped {
             nc.notify_q("boot.progress", "mounting rootfs").await;
@@ -515,8 +620,49 @@ impl SetupFdeArgs {
     }
 }
 
+/// Decrypt and parse an encrypted env with the env_crypt_key of the app.
+fn decrypt_env(key: &[u8], ciphertext: &[u8]) -> Result<BTreeMap<String, String>> {
+    if ciphertext.is_empty() {
+        bail!("encrypted env is empty");
+    }
+    let env_crypt_key: [u8; 32] = key.try_into().ok().with_context(|| {
+        format!(
+            "Invalid env crypt key length: expected 32 bytes, got {}",
+            key.len()
+        )
+    })?;
+    let decrypted_json =
+        dh_decrypt(env_crypt_key, ciphertext).context("Failed to decrypt env file")?;
+    env_process::parse_env(&decrypted_json)
+}
+
+/// One `KEY=value` line per env var, with the value masked if `redact` is set.
+fn format_env(env: &BTreeMap<String, String>, redact: bool) -> Vec<String> {
+    env.iter()
+        .map(|(key, value)| {
+            if redact {
+                format!("{key}=<redacted, {} bytes>", value.len())
+            } else {
+                format!("{key}={value}")
+            }
+        })
+        .collect()
+}
+
+pub fn cmd_decrypt_env(args: DecryptEnvArgs) -> Result<()> {
+    let ciphertext = fs::read(&args.env_file).context("Failed to read encrypted env")?;
+    let app_keys: AppKeys =
+        deserialize_json_file(&args.app_keys).context("Failed to decode app keys")?;
+    let env = decrypt_env(&app_keys.env_crypt_key, &ciphertext)?;
+    for line in format_env(&env, !args.show_values) {
+        println!("{line}");
+    }
+    Ok(())
+}
+
 pub async fn cmd_setup_fde(args: SetupFdeArgs) -> Result<()> {
     let host_shared = args.copy_host_shared()?;
+    sync_clock_from_host(&host_shared.app_compose, host_shared.host_time);
     let nc = NotifyClient::new(host_shared.vm_config.host_api_url.clone());
     match args.setup_rootfs(&nc, &host_shared).await {
         Ok(_) => Ok(()),
@@ -526,3 +672,111 @@ pub async fn cmd_setup_fde(args: SetupFdeArgs) -> Result<()> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app_compose_with(extra: serde_json::Value) -> AppCompose {
+        let mut compose = serde_json::json!({
+            "manifest_version": 2,
+            "name": "app",
+            "runner": "docker-compose",
+            "docker_compose_file": "services: {}",
+        });
+        compose
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        AppCompose::parse(&compose.to_string()).unwrap()
+    }
+
+    fn app_compose(allow_plain_env: bool) -> AppCompose {
+        app_compose_with(serde_json::json!({ "allow_plain_env": allow_plain_env }))
+    }
+
+    #[test]
+    fn test_host_time_step() {
+        let now = 1_700_000_000;
+        let denied = app_compose_with(serde_json::json!({}));
+        assert_eq!(host_time_step(&denied, Some(now + 60), now), None);
+
+        let allowed = app_compose_with(serde_json::json!({
+            "allow_host_time": true,
+            "max_host_time_step": 3600,
+        }));
+        assert_eq!(host_time_step(&allowed, None, now), None);
+        assert_eq!(
+            host_time_step(&allowed, Some(now + 60), now),
+            Some(now + 60)
+        );
+        // Never backwards, and never further than allowed
+        assert_eq!(host_time_step(&allowed, Some(now - 60), now), None);
+        assert_eq!(host_time_step(&allowed, Some(now + 3601), now), None);
+    }
+
+    #[test]
+    fn test_plain_env() {
+        let plain_env =
+            br#"{"env": [{"key": "A", "value": "plain"}, {"key": "B", "value": "plain"}]}"#;
+        let decrypted = BTreeMap::from([("B".to_string(), "secret".to_string())]);
+
+        // Ignored unless the app compose opts in
+        let env = app_env(&app_compose(false), plain_env, decrypted.clone()).unwrap();
+        assert_eq!(env, decrypted);
+
+        // The decrypted env takes precedence
+        let env = app_env(&app_compose(true), plain_env, decrypted.clone()).unwrap();
+        assert_eq!(
+            env,
+            BTreeMap::from([
+                ("A".to_string(), "plain".to_string()),
+                ("B".to_string(), "secret".to_string()),
+            ])
+        );
+
+        assert!(parse_plain_env(&app_compose(true), b"").unwrap().is_empty());
+        assert!(parse_plain_env(&app_compose(true), b"{").is_err());
+        let invalid_key = br#"{"env": [{"key": "1A", "value": ""}]}"#;
+        assert!(parse_plain_env(&app_compose(true), invalid_key).is_err());
+    }
+
+    #[test]
+    fn test_plain_env_reserved_keys() {
+        for key in RESERVED_ENV_KEYS {
+            let plain_env = format!(r#"{{"env": [{{"key": "{key}", "value": "/tmp"}}]}}"#);
+            let err = parse_plain_env(&app_compose(true), plain_env.as_bytes()).unwrap_err();
+            assert_eq!(err.to_string(), format!("Reserved key in plain env: {key}"));
+        }
+        // Reserved names may still come from the encrypted env, which the app owner controls
+        let decrypted = BTreeMap::from([("PATH".to_string(), "/opt/bin".to_string())]);
+        let env = app_env(&app_compose(true), b"", decrypted.clone()).unwrap();
+        assert_eq!(env, decrypted);
+    }
+
+    #[test]
+    fn test_decrypt_env_errors() {
+        let err = |key: &[u8], ciphertext: &[u8]| {
+            format!("{:#}", decrypt_env(key, ciphertext).unwrap_err())
+        };
+        assert_eq!(err(&[0u8; 32], &[]), "encrypted env is empty");
+        assert_eq!(
+            err(&[0u8; 16], &[0u8; 64]),
+            "Invalid env crypt key length: expected 32 bytes, got 16"
+        );
+        assert!(err(&[0u8; 32], &[0u8; 64]).starts_with("Failed to decrypt env file"));
+    }
+
+    #[test]
+    fn test_format_env() {
+        let env = BTreeMap::from([
+            ("A".to_string(), "secret".to_string()),
+            ("B".to_string(), String::new()),
+        ]);
+        assert_eq!(
+            format_env(&env, true),
+            ["A=<redacted, 6 bytes>", "B=<redacted, 0 bytes>"]
+        );
+        assert_eq!(format_env(&env, false), ["A=secret", "B="]);
+    }
+}
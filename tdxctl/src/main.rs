@@ -1,11 +1,12 @@
 use anyhow::{bail, Context, Result};
 use clap::{Parser, Subcommand};
-use fde_setup::{cmd_setup_fde, SetupFdeArgs};
+use fde_setup::{cmd_decrypt_env, cmd_setup_fde, DecryptEnvArgs, SetupFdeArgs};
 use fs_err as fs;
 use getrandom::getrandom;
 use notify_client::NotifyClient;
 use ra_tls::{attestation::QuoteContentType, cert::CaCert};
 use scale::Decode;
+use selftest::{cmd_selftest, SelftestArgs};
 use std::{
     io::{self, Read, Write},
     path::PathBuf,
@@ -13,13 +14,16 @@ use std::{
 use tboot::TbootArgs;
 use tdx_attest as att;
 use tracing::error;
-use utils::{extend_rtmr, run_command};
+use utils::{extend_rtmr, extend_rtmr_prehashed, run_command, sha256_dir};
+use verify::{cmd_verify, cmd_verify_quote, VerifyArgs, VerifyQuoteArgs};
 
 mod crypto;
 mod fde_setup;
 mod notify_client;
+mod selftest;
 mod tboot;
 mod utils;
+mod verify;
 
 /// TDX control utility
 #[derive(Parser)]
@@ -27,6 +31,15 @@ mod utils;
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
+
+    /// Path to the runtime event log
+    #[arg(long, global = true, default_value = att::eventlog::RUNTIME_EVENT_LOG_FILE)]
+    event_log: PathBuf,
+
+    /// Rotate the runtime event log before it grows beyond this size in bytes. 0 disables rotation.
+    /// Rotated segments are kept so that RTMR3 can still be replayed from the full log.
+    #[arg(long, global = true, default_value_t = 0)]
+    event_log_max_size: u64,
 }
 
 #[derive(Subcommand)]
@@ -41,6 +54,8 @@ enum Commands {
     Show,
     /// Hex encode data
     Hex(HexCommand),
+    /// Hash a directory tree with SHA-256, printing the digest of each file and of the tree
+    HashDir(HashDirArgs),
     /// Generate a RA-TLS certificate
     GenRaCert(GenRaCertArgs),
     /// Generate a CA certificate
@@ -51,10 +66,18 @@ enum Commands {
     Rand(RandArgs),
     /// Setup Disk Encryption
     SetupFde(SetupFdeArgs),
+    /// Decrypt the encrypted env of an app and print the env vars
+    DecryptEnv(DecryptEnvArgs),
     /// Boot the Tapp
     Tboot(TbootArgs),
     /// Notify the host about the Tapp
     NotifyHost(HostNotifyArgs),
+    /// Check that quoting, event log reading and RTMR extending work
+    Selftest(SelftestArgs),
+    /// Verify that a running CVM runs the expected app
+    Verify(VerifyArgs),
+    /// Verify the measurements of a quote file against expected values, offline
+    VerifyQuote(VerifyQuoteArgs),
 }
 
 #[derive(Parser)]
@@ -65,6 +88,13 @@ struct HexCommand {
     filename: Option<String>,
 }
 
+#[derive(Parser)]
+/// Hash a directory tree
+struct HashDirArgs {
+    /// directory to hash
+    dir: PathBuf,
+}
+
 #[derive(Parser)]
 /// Extend RTMR
 struct ExtendArgs {
@@ -80,9 +110,13 @@ struct ExtendArgs {
     /// event name
     event: String,
 
-    #[clap(short, long)]
+    #[clap(short, long, required_unless_present = "digest")]
     /// hex encoded payload of the event
-    payload: String,
+    payload: Option<String>,
+
+    #[clap(short, long, conflicts_with = "payload")]
+    /// hex encoded SHA-384 digest to extend with instead of hashing a payload, not for RTMR3
+    digest: Option<String>,
 }
 
 #[derive(Parser)]
@@ -185,7 +219,17 @@ fn cmd_quote() -> Result<()> {
 }
 
 fn cmd_extend(extend_args: ExtendArgs) -> Result<()> {
-    let payload = hex::decode(&extend_args.payload).context("Failed to decode payload")?;
+    if let Some(digest) = &extend_args.digest {
+        let digest = hex::decode(digest).context("Failed to decode digest")?;
+        return extend_rtmr_prehashed(
+            extend_args.index,
+            extend_args.event_type,
+            &extend_args.event,
+            &digest,
+        );
+    }
+    let payload = extend_args.payload.unwrap_or_default();
+    let payload = hex::decode(payload).context("Failed to decode payload")?;
     extend_rtmr(
         extend_args.index,
         extend_args.event_type,
@@ -206,6 +250,15 @@ fn cmd_report() -> Result<()> {
     Ok(())
 }
 
+fn cmd_hash_dir(args: HashDirArgs) -> Result<()> {
+    let dir_digest = sha256_dir(&args.dir).context("Failed to hash directory")?;
+    for (path, digest) in &dir_digest.files {
+        println!("{}  {path}", hex::encode(digest));
+    }
+    println!("{}", hex::encode(dir_digest.digest));
+    Ok(())
+}
+
 fn cmd_rand(rand_args: RandArgs) -> Result<()> {
     let mut data = vec![0u8; rand_args.bytes];
     getrandom(&mut data).context("Failed to generate random data")?;
@@ -391,6 +444,10 @@ async fn main() -> Result<()> {
     }
 
     let cli = Cli::parse();
+    att::eventlog::set_runtime_event_log_config(att::eventlog::RuntimeEventLogConfig {
+        path: cli.event_log,
+        max_size: cli.event_log_max_size,
+    })?;
 
     match cli.command {
         Commands::Report => cmd_report()?,
@@ -402,6 +459,9 @@ async fn main() -> Result<()> {
         Commands::Hex(hex_args) => {
             cmd_hex(hex_args)?;
         }
+        Commands::HashDir(args) => {
+            cmd_hash_dir(args)?;
+        }
         Commands::GenRaCert(args) => {
             cmd_gen_ra_cert(args)?;
         }
@@ -417,6 +477,9 @@ async fn main() -> Result<()> {
         Commands::SetupFde(args) => {
             cmd_setup_fde(args).await?;
         }
+        Commands::DecryptEnv(args) => {
+            cmd_decrypt_env(args)?;
+        }
         Commands::Tboot(args) => {
             if let Err(err) = tboot::tboot(&args).await {
                 error!("{:?}", err);
@@ -429,6 +492,15 @@ async fn main() -> Result<()> {
         Commands::NotifyHost(args) => {
             cmd_notify_host(args).await?;
         }
+        Commands::Selftest(args) => {
+            cmd_selftest(args)?;
+        }
+        Commands::Verify(args) => {
+            cmd_verify(args).await?;
+        }
+        Commands::VerifyQuote(args) => {
+            cmd_verify_quote(args)?;
+        }
     }
 
     Ok(())
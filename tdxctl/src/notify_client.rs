@@ -1,4 +1,4 @@
-use crate::utils::{deserialize_json_file, LocalConfig};
+use crate::utils::LocalConfig;
 use anyhow::Result;
 use host_api::{
     client::{new_client, DefaultClient},
@@ -27,7 +27,7 @@ impl NotifyClient {
         let url = match url {
             Some(url) => url,
             None => {
-                let local_config: LocalConfig = deserialize_json_file("/tapp/config.json")?;
+                let local_config = LocalConfig::load("/tapp/config.json")?;
                 local_config.host_api_url.clone()
             }
         };
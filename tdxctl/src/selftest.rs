@@ -0,0 +1,103 @@
+//! Smoke test of the TDX attestation toolchain on the current machine.
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use getrandom::getrandom;
+use ra_tls::{
+    attestation::replay_event_logs,
+    qvl::quote::{Quote, Report},
+};
+use tdx_attest as att;
+
+use crate::utils::extend_rtmr3;
+
+#[derive(Parser)]
+/// Self test the TDX attestation toolchain
+pub struct SelftestArgs {
+    /// Also extend RTMR3 with a test event.
+    /// The event stays in RTMR3 and the event log until the next reboot.
+    #[arg(long)]
+    extend_rtmr3: bool,
+}
+
+#[derive(Default)]
+struct Selftest {
+    passed: usize,
+    failed: usize,
+}
+
+impl Selftest {
+    fn step<T>(&mut self, name: &str, f: impl FnOnce() -> Result<T>) -> Option<T> {
+        match f() {
+            Ok(v) => {
+                self.passed += 1;
+                println!("[PASS] {name}");
+                Some(v)
+            }
+            Err(err) => {
+                self.failed += 1;
+                println!("[FAIL] {name}: {err:?}");
+                None
+            }
+        }
+    }
+}
+
+pub fn cmd_selftest(args: SelftestArgs) -> Result<()> {
+    let mut test = Selftest::default();
+
+    let mut report_data = [0u8; 64];
+    getrandom(&mut report_data).context("Failed to generate report data")?;
+
+    if args.extend_rtmr3 {
+        test.step("extend RTMR3", || {
+            extend_rtmr3("selftest", &report_data[..32])
+        });
+    }
+    let quote = test.step("get quote", || {
+        let (_, quote) = att::get_quote(&report_data, None).context("Failed to get quote")?;
+        Ok(quote)
+    });
+    let event_logs = test.step("read event log", || {
+        att::eventlog::read_event_logs().context("Failed to read event log")
+    });
+    if let Some(quote) = quote {
+        let report = test.step("parse quote", || {
+            let quote = Quote::parse(&quote).context("Failed to parse quote")?;
+            match quote.report {
+                Report::SgxEnclave(_) => bail!("unexpected SGX report"),
+                Report::TD10(report) => Ok(report),
+                Report::TD15(report) => Ok(report.base),
+            }
+        });
+        if let Some(report) = report {
+            test.step("verify report data", || {
+                if report.report_data != report_data {
+                    bail!("report data mismatch");
+                }
+                Ok(())
+            });
+            if let Some(event_logs) = event_logs {
+                test.step("replay event log", || {
+                    let rtmrs = replay_event_logs(&event_logs)?;
+                    let expected = [report.rt_mr0, report.rt_mr1, report.rt_mr2, report.rt_mr3];
+                    for (i, (replayed, expected)) in rtmrs.iter().zip(expected.iter()).enumerate() {
+                        if replayed != expected {
+                            bail!(
+                                "RTMR{i} mismatch, replayed={}, quoted={}",
+                                hex::encode(replayed),
+                                hex::encode(expected)
+                            );
+                        }
+                    }
+                    Ok(())
+                });
+            }
+        }
+    }
+
+    println!("passed: {}, failed: {}", test.passed, test.failed);
+    if test.failed > 0 {
+        bail!("selftest failed");
+    }
+    Ok(())
+}
@@ -46,7 +46,7 @@ impl<'a> Setup<'a> {
     fn load(args: &'a TbootArgs) -> Result<Self> {
         Ok(Self {
             args,
-            local_config: deserialize_json_file(args.resolve("/tapp/config.json"))
+            local_config: LocalConfig::load(args.resolve("/tapp/config.json"))
                 .context("Failed to read config.json")?,
             app_compose: deserialize_json_file(args.resolve("/tapp/app-compose.json"))
                 .context("Failed to read app-compose.json")?,
@@ -1,4 +1,5 @@
 use std::{
+    collections::BTreeMap,
     io::{self, Read, Write},
     path::Path,
     process::{Command, Stdio},
@@ -28,8 +29,66 @@ pub fn sha256(data: &[u8]) -> [u8; 32] {
 }
 
 pub fn sha256_file(path: impl AsRef<Path>) -> Result<[u8; 32]> {
-    let data = fs::read(path).context("Failed to read file")?;
-    Ok(sha256(&data))
+    let file = fs::File::open(path).context("Failed to open file")?;
+    let mut hashing_file = HashingFile::<sha2::Sha256, _>::new(file);
+    io::copy(&mut hashing_file, &mut io::sink()).context("Failed to read file")?;
+    Ok(hashing_file.finalize().into())
+}
+
+/// The digest of a directory tree and of each file in it.
+pub struct DirDigest {
+    /// SHA-256 over the manifest, each entry as `path || 0 || digest`
+    pub digest: [u8; 32],
+    /// SHA-256 of each file by its `/` separated path relative to the root, sorted by path
+    pub files: BTreeMap<String, [u8; 32]>,
+}
+
+/// Hash every file under `root`, streaming their contents.
+///
+/// Entries are keyed by path rather than walk order, so the result does not depend on the
+/// order the filesystem lists them in. Symlinks are not followed, the digest of their target
+/// path is recorded instead. Empty directories do not contribute.
+pub fn sha256_dir(root: impl AsRef<Path>) -> Result<DirDigest> {
+    fn walk(root: &Path, dir: &Path, files: &mut BTreeMap<String, [u8; 32]>) -> Result<()> {
+        for entry in fs::read_dir(dir).context("Failed to read directory")? {
+            let path = entry.context("Failed to read directory entry")?.path();
+            let rel_path = path
+                .strip_prefix(root)
+                .context("Failed to get relative path")?;
+            let Some(rel_path) = rel_path.to_str() else {
+                bail!("non UTF-8 path: {}", rel_path.display());
+            };
+            let key = rel_path.replace(std::path::MAIN_SEPARATOR, "/");
+            let file_type = fs::symlink_metadata(&path)
+                .context("Failed to stat file")?
+                .file_type();
+            if file_type.is_dir() {
+                walk(root, &path, files)?;
+            } else if file_type.is_file() {
+                files.insert(key, sha256_file(&path)?);
+            } else if file_type.is_symlink() {
+                let target = fs::read_link(&path).context("Failed to read symlink")?;
+                files.insert(key, sha256(target.as_os_str().as_encoded_bytes()));
+            } else {
+                bail!("unsupported file type: {key}");
+            }
+        }
+        Ok(())
+    }
+
+    let root = root.as_ref();
+    let mut files = BTreeMap::new();
+    walk(root, root, &mut files)?;
+    let mut hasher = sha2::Sha256::new();
+    for (path, digest) in &files {
+        hasher.update(path.as_bytes());
+        hasher.update([0]);
+        hasher.update(digest);
+    }
+    Ok(DirDigest {
+        digest: hasher.finalize().into(),
+        files,
+    })
 }
 
 pub fn copy_dir_all(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> io::Result<()> {
@@ -79,11 +138,55 @@ pub fn extend_rtmr3(event: &str, payload: &[u8]) -> Result<()> {
 pub fn extend_rtmr(index: u32, event_type: u32, event: &str, payload: &[u8]) -> Result<()> {
     let log =
         att::eventlog::TdxEventLog::new(index, event_type, event.to_string(), payload.to_vec());
-    att::extend_rtmr(index, event_type, log.digest).context("Failed to extend RTMR")?;
-    let hexed_payload = hex::encode(payload);
+    extend_and_log(&log)
+}
+
+/// Extend an RTMR with a SHA-384 digest computed by the caller, e.g. of a payload too large to
+/// load, and log the event without a payload.
+///
+/// RTMR3 events are checked against their payload when the log is verified, so they must be
+/// extended with [`extend_rtmr`].
+pub fn extend_rtmr_prehashed(
+    index: u32,
+    event_type: u32,
+    event: &str,
+    digest: &[u8],
+) -> Result<()> {
+    extend_and_log(&prehashed_event(index, event_type, event, digest)?)
+}
+
+fn prehashed_event(
+    index: u32,
+    event_type: u32,
+    event: &str,
+    digest: &[u8],
+) -> Result<att::eventlog::TdxEventLog> {
+    if index == 3 {
+        bail!("RTMR3 can not be extended with a prehashed digest");
+    }
+    let digest: [u8; 48] = digest.try_into().ok().with_context(|| {
+        format!(
+            "RTMRs use SHA-384, expected a 48 byte digest, got {} bytes",
+            digest.len()
+        )
+    })?;
+    Ok(att::eventlog::TdxEventLog {
+        imr: index,
+        event_type,
+        digest,
+        event: event.to_string(),
+        event_payload: vec![],
+    })
+}
+
+fn extend_and_log(log: &att::eventlog::TdxEventLog) -> Result<()> {
+    let index = log.imr;
+    att::extend_rtmr(index, log.event_type, log.digest).context("Failed to extend RTMR")?;
+    let event = &log.event;
+    let hexed_payload = hex::encode(&log.event_payload);
     let hexed_digest = hex_fmt::HexFmt(&log.digest);
     println!("Extended RTMR{index}: event={event}, payload={hexed_payload}, digest={hexed_digest}");
-    att::log_rtmr_event(&log).context("Failed to log RTMR extending event")?;
+    att::log_rtmr_event(log).context("Failed to log RTMR extending event")?;
     Ok(())
 }
 
@@ -101,13 +204,18 @@ pub fn run_command_with_stdin(
         .spawn()
         .context(format!("Failed to run {}", command))?;
     let mut child_stdin = child.stdin.take().context("Failed to get stdin")?;
-    child_stdin
-        .write_all(stdin.as_ref())
+    let stdin = stdin.as_ref();
+    // Feed stdin from another thread while the output is drained, otherwise a child that fills
+    // the stdout pipe before consuming all of its input never finishes.
+    let (written, output) = std::thread::scope(|s| {
+        let writer = s.spawn(move || child_stdin.write_all(stdin));
+        let output = child.wait_with_output();
+        (writer.join(), output)
+    });
+    written
+        .map_err(|_| anyhow::anyhow!("stdin writer panicked"))?
         .context("Failed to write to stdin")?;
-    drop(child_stdin);
-    let output = child
-        .wait_with_output()
-        .context(format!("Failed to wait for {}", command))?;
+    let output = output.context(format!("Failed to wait for {}", command))?;
     if !output.status.success() {
         bail!(
             "Command {} failed: {}",
@@ -134,51 +242,7 @@ pub fn run_command(command: &str, args: &[&str]) -> Result<Vec<u8>> {
     Ok(output.stdout)
 }
 
-#[derive(Deserialize)]
-#[allow(unused)]
-pub struct AppCompose {
-    pub manifest_version: u32,
-    pub name: String,
-    // Deprecated
-    #[serde(default)]
-    pub features: Vec<String>,
-    pub runner: String,
-    pub docker_compose_file: Option<String>,
-    #[serde(default)]
-    pub docker_config: DockerConfig,
-    #[serde(default)]
-    pub public_logs: bool,
-    #[serde(default)]
-    pub public_sysinfo: bool,
-    #[serde(default)]
-    pub kms_enabled: bool,
-    #[serde(default)]
-    pub tproxy_enabled: bool,
-}
-
-#[derive(Deserialize, Debug, Default)]
-pub struct DockerConfig {
-    /// The URL of the Docker registry.
-    pub registry: Option<String>,
-    /// The username of the registry account.
-    pub username: Option<String>,
-    /// The key of the encrypted environment variables for registry account token.
-    pub token_key: Option<String>,
-}
-
-impl AppCompose {
-    fn feature_enabled(&self, feature: &str) -> bool {
-        self.features.contains(&feature.to_string())
-    }
-
-    pub fn tproxy_enabled(&self) -> bool {
-        self.tproxy_enabled || self.feature_enabled("tproxy-net")
-    }
-
-    pub fn kms_enabled(&self) -> bool {
-        self.kms_enabled || self.feature_enabled("kms")
-    }
-}
+pub use app_compose::AppCompose;
 
 #[derive(Deserialize)]
 pub struct LocalConfig {
@@ -190,6 +254,43 @@ pub struct LocalConfig {
     pub host_api_url: String,
 }
 
+impl LocalConfig {
+    /// Load the config and check its URLs, so that a typo is reported here rather than as a
+    /// connection error later on.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let config: Self = deserialize_json_file(path)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        // The host API is reached over vsock inside a CVM
+        check_url(
+            "host_api_url",
+            &self.host_api_url,
+            &["http", "https", "vsock", "unix"],
+        )?;
+        // Teepod writes an empty URL for services it is not configured with
+        for (field, url) in [("kms_url", &self.kms_url), ("tproxy_url", &self.tproxy_url)] {
+            if let Some(url) = url.as_deref().filter(|url| !url.is_empty()) {
+                check_url(field, url, &["http", "https"])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_url(field: &str, value: &str, schemes: &[&str]) -> Result<()> {
+    let url = url::Url::parse(value).with_context(|| format!("invalid {field} {value:?}"))?;
+    if !schemes.contains(&url.scheme()) {
+        bail!(
+            "invalid {field} {value:?}: unsupported scheme {:?}, expected one of {schemes:?}",
+            url.scheme()
+        );
+    }
+    Ok(())
+}
+
 #[derive(Deserialize)]
 pub struct AppKeys {
     pub app_key: String,
@@ -198,3 +299,95 @@ pub struct AppKeys {
     pub env_crypt_key: Vec<u8>,
     pub certificate_chain: Vec<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_command_with_stdin_large_input() {
+        // Far more than a pipe buffer, so cat blocks on stdout until it is drained
+        let input: Vec<u8> = (0..8 * 1024 * 1024).map(|i| i as u8).collect();
+        let output = run_command_with_stdin("cat", &[], &input).unwrap();
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn test_sha256_dir() {
+        let base = tempfile::tempdir().unwrap();
+        let tree = |name: &str, files: &[(&str, &str)]| {
+            let root = base.path().join(name);
+            for (path, content) in files {
+                let path = root.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, content).unwrap();
+            }
+            root
+        };
+        let a = tree("a", &[("b/c", "1"), ("a", "2"), ("b.d", "3")]);
+        // Same tree, created in another order
+        let b = tree("b", &[("b.d", "3"), ("a", "2"), ("b/c", "1")]);
+        let c = tree("c", &[("b/c", "1"), ("a", "2"), ("b.d", "4")]);
+
+        let digest_a = sha256_dir(&a).unwrap();
+        let digest_b = sha256_dir(&b).unwrap();
+        assert_eq!(digest_a.digest, digest_b.digest);
+        assert_eq!(digest_a.files, digest_b.files);
+        assert_eq!(
+            digest_a.files.keys().collect::<Vec<_>>(),
+            ["a", "b.d", "b/c"]
+        );
+        assert_eq!(digest_a.files["a"], sha256(b"2"));
+        assert_eq!(sha256_file(a.join("a")).unwrap(), sha256(b"2"));
+        assert_ne!(sha256_dir(&c).unwrap().digest, digest_a.digest);
+    }
+
+    fn local_config(host_api_url: &str, kms_url: Option<&str>) -> LocalConfig {
+        LocalConfig {
+            rootfs_hash: vec![],
+            kms_url: kms_url.map(Into::into),
+            tproxy_url: Some(String::new()),
+            docker_registry: None,
+            host_api_url: host_api_url.into(),
+        }
+    }
+
+    #[test]
+    fn test_local_config_urls() {
+        let err = |config: LocalConfig| format!("{:#}", config.validate().unwrap_err());
+        local_config("vsock://2:10000/api", Some("https://kms.example.com:8000"))
+            .validate()
+            .unwrap();
+        local_config("vsock://2:10000/api", None)
+            .validate()
+            .unwrap();
+        assert_eq!(
+            err(local_config(
+                "vsock://2:10000/api",
+                Some("kms.example.com/prpc")
+            )),
+            "invalid kms_url \"kms.example.com/prpc\": relative URL without a base"
+        );
+        // Without a scheme, the host is taken as the scheme
+        assert_eq!(
+            err(local_config("localhost:10000", None)),
+            "invalid host_api_url \"localhost:10000\": unsupported scheme \"localhost\", \
+             expected one of [\"http\", \"https\", \"vsock\", \"unix\"]"
+        );
+        assert!(err(local_config("http://[::1", None)).starts_with("invalid host_api_url"));
+    }
+
+    #[test]
+    fn test_prehashed_event() {
+        let event = prehashed_event(2, DSTACK_EVENT_TAG, "image", &[1u8; 48]).unwrap();
+        assert_eq!(event.digest, [1u8; 48]);
+        assert!(event.event_payload.is_empty());
+
+        let err = prehashed_event(2, DSTACK_EVENT_TAG, "image", &[1u8; 32]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "RTMRs use SHA-384, expected a 48 byte digest, got 32 bytes"
+        );
+        assert!(prehashed_event(3, DSTACK_EVENT_TAG, "image", &[1u8; 48]).is_err());
+    }
+}
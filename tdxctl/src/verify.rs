@@ -0,0 +1,252 @@
+//! Check that a running CVM, or a quote taken from one, runs the expected app, e.g. to gate a
+//! deployment in CI.
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use fs_err as fs;
+use ra_rpc::client::RaClient;
+use ra_tls::{
+    attestation::{verify_event_logs, Attestation},
+    qvl::{self, quote::Report},
+    verify::verify_app_ca,
+};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tappd_rpc::worker_client::WorkerClient;
+
+#[derive(Parser)]
+/// Verify the attestation of a running CVM against the expected app
+pub struct VerifyArgs {
+    /// URL of the external tappd API of the CVM
+    #[arg(long)]
+    url: String,
+    /// The app compose file the CVM is expected to run
+    #[arg(long)]
+    compose: PathBuf,
+    /// Expected app id, if it was set at deployment instead of derived from the compose file
+    #[arg(long)]
+    app_id: Option<String>,
+    /// Root CA certificate of the KMS the app certificate is issued by. Omit for CVMs using the
+    /// local key provider, whose app certificate is self-signed.
+    #[arg(long)]
+    kms_root_ca: Option<PathBuf>,
+    /// PCCS URL to fetch the collateral for verifying the quote from
+    #[arg(
+        long,
+        default_value = "https://api.trustedservices.intel.com/tdx/certification/v4"
+    )]
+    pccs_url: String,
+    /// Expected MRTD in hex
+    #[arg(long)]
+    mrtd: Option<String>,
+    /// Expected RTMR0 in hex
+    #[arg(long)]
+    rtmr0: Option<String>,
+    /// Expected RTMR1 in hex
+    #[arg(long)]
+    rtmr1: Option<String>,
+    /// Expected RTMR2 in hex
+    #[arg(long)]
+    rtmr2: Option<String>,
+    /// Expected rootfs hash in hex
+    #[arg(long)]
+    rootfs_hash: Option<String>,
+}
+
+#[derive(Parser)]
+/// Verify the measurements of a quote against expected values, without network access.
+/// Only the measurements are checked, not the signature of the quote.
+pub struct VerifyQuoteArgs {
+    /// File containing the raw TDX quote
+    #[arg(long)]
+    quote: PathBuf,
+    /// JSON file with the expected mrtd, rtmr0-3 and rootfs_hash in hex. Missing fields are
+    /// not checked.
+    #[arg(long)]
+    expected: PathBuf,
+    /// Event log of the quote in JSON, as returned by tappd along with the quote. Required to
+    /// check rootfs_hash, and replayed against the RTMRs of the quote when given.
+    #[arg(long)]
+    events: Option<PathBuf>,
+}
+
+/// Expected measurements of a quote, in hex
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExpectedMeasurements {
+    mrtd: Option<String>,
+    rtmr0: Option<String>,
+    rtmr1: Option<String>,
+    rtmr2: Option<String>,
+    rtmr3: Option<String>,
+    rootfs_hash: Option<String>,
+}
+
+#[derive(Default)]
+struct Checks {
+    failed: Vec<&'static str>,
+}
+
+impl Checks {
+    fn compare(&mut self, field: &'static str, expected: &str, actual: &str) {
+        if expected.eq_ignore_ascii_case(actual) {
+            println!("[PASS] {field}");
+        } else {
+            println!("[FAIL] {field}: expected {expected}, got {actual}");
+            self.failed.push(field);
+        }
+    }
+}
+
+pub async fn cmd_verify(args: VerifyArgs) -> Result<()> {
+    let compose = fs::read_to_string(&args.compose).context("Failed to read compose file")?;
+    let compose_hash = app_compose::compose_hash(&compose);
+    let expected_app_id = args
+        .app_id
+        .clone()
+        .unwrap_or_else(|| app_compose::app_id_of(&compose));
+
+    let kms_root_ca = args
+        .kms_root_ca
+        .as_ref()
+        .map(fs::read_to_string)
+        .transpose()
+        .context("Failed to read KMS root CA certificate")?;
+
+    let url = format!("{}/prpc", args.url.trim_end_matches('/'));
+    // The TLS certificate is not trusted. Everything checked below comes from the app
+    // certificate, which is verified against the KMS root and the signed quote instead.
+    let tls_no_check = true;
+    let client = WorkerClient::new(RaClient::new(url, tls_no_check));
+    let info = client
+        .info()
+        .await
+        .context("Failed to get info from tappd")?;
+    if info.app_cert.is_empty() {
+        bail!("the CVM reported no app certificate");
+    }
+    let attestation =
+        verify_app_ca(&info.app_cert, kms_root_ca.as_deref()).context("Invalid app certificate")?;
+    println!("[PASS] app certificate");
+    verify_quote_signature(&attestation.quote, &args.pccs_url)
+        .await
+        .context("Invalid quote")?;
+    println!("[PASS] quote signature");
+    let report = attestation.decode_td_report()?;
+
+    let mut checks = Checks::default();
+    checks.compare(
+        "app_id",
+        &expected_app_id,
+        &attestation.decode_app_id().unwrap_or_default(),
+    );
+    checks.compare("reported app_id", &expected_app_id, &info.app_id);
+    checks.compare(
+        "compose_hash",
+        &compose_hash,
+        &attestation.decode_compose_hash().unwrap_or_default(),
+    );
+    let measurements = [
+        ("mrtd", &args.mrtd, report.mr_td),
+        ("rtmr0", &args.rtmr0, report.rt_mr0),
+        ("rtmr1", &args.rtmr1, report.rt_mr1),
+        ("rtmr2", &args.rtmr2, report.rt_mr2),
+    ];
+    for (field, expected, actual) in measurements {
+        if let Some(expected) = expected {
+            checks.compare(field, expected, &hex::encode(actual));
+        }
+    }
+    if let Some(expected) = &args.rootfs_hash {
+        checks.compare(
+            "rootfs_hash",
+            expected,
+            &attestation.decode_rootfs_hash().unwrap_or_default(),
+        );
+    }
+
+    if !checks.failed.is_empty() {
+        bail!("verification failed: {}", checks.failed.join(", "));
+    }
+    println!("verification passed");
+    Ok(())
+}
+
+/// Verify the signature and TCB of a quote with collateral fetched from the PCCS.
+async fn verify_quote_signature(quote: &[u8], pccs_url: &str) -> Result<()> {
+    let collateral = qvl::collateral::get_collateral(pccs_url, quote, Duration::from_secs(60))
+        .await
+        .context("Failed to get collateral")?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("Failed to get current time")?
+        .as_secs();
+    qvl::verify::verify(quote, &collateral, now)
+        .ok()
+        .context("Quote verification failed")?;
+    Ok(())
+}
+
+pub fn cmd_verify_quote(args: VerifyQuoteArgs) -> Result<()> {
+    let quote = fs::read(&args.quote).context("Failed to read quote")?;
+    let expected = fs::read_to_string(&args.expected).context("Failed to read expected file")?;
+    let expected: ExpectedMeasurements =
+        serde_json::from_str(&expected).context("Invalid expected measurements")?;
+    let raw_event_log = match &args.events {
+        Some(path) => fs::read(path).context("Failed to read event log")?,
+        None => vec![],
+    };
+    if expected.rootfs_hash.is_some() && args.events.is_none() {
+        bail!("checking rootfs_hash requires the event log, pass it with --events");
+    }
+    let attestation = Attestation::new(quote, raw_event_log)?;
+    let quote = attestation
+        .decode_quote()
+        .context("Failed to parse quote")?;
+    let report = match &quote.report {
+        Report::SgxEnclave(_) => bail!("SGX reports are not supported"),
+        Report::TD10(tdreport10) => tdreport10,
+        Report::TD15(tdreport15) => &tdreport15.base,
+    };
+
+    let mut checks = Checks::default();
+    let measurements = [
+        ("mrtd", &expected.mrtd, report.mr_td),
+        ("rtmr0", &expected.rtmr0, report.rt_mr0),
+        ("rtmr1", &expected.rtmr1, report.rt_mr1),
+        ("rtmr2", &expected.rtmr2, report.rt_mr2),
+        ("rtmr3", &expected.rtmr3, report.rt_mr3),
+    ];
+    for (field, expected, actual) in measurements {
+        if let Some(expected) = expected {
+            checks.compare(field, expected, &hex::encode(actual));
+        }
+    }
+    if args.events.is_some() {
+        // The rootfs hash is only as trustworthy as the event log it is read from
+        let rtmrs = [report.rt_mr0, report.rt_mr1, report.rt_mr2, report.rt_mr3];
+        let verification = verify_event_logs(&attestation.event_log, &rtmrs)?;
+        if let Some(index) = verification.invalid_event {
+            println!("[FAIL] event log: event {index} does not match its digest");
+            checks.failed.push("event log");
+        } else if let Some(index) = verification.mismatched_rtmr {
+            println!("[FAIL] event log: replay does not reproduce rtmr{index}");
+            checks.failed.push("event log");
+        } else {
+            println!("[PASS] event log");
+        }
+    }
+    if let Some(expected) = &expected.rootfs_hash {
+        checks.compare(
+            "rootfs_hash",
+            expected,
+            &attestation.decode_rootfs_hash().unwrap_or_default(),
+        );
+    }
+
+    if !checks.failed.is_empty() {
+        bail!("verification failed: {}", checks.failed.join(", "));
+    }
+    println!("verification passed");
+    Ok(())
+}
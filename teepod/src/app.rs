@@ -1,27 +1,57 @@
-use crate::config::{Config, Protocol};
+use crate::config::{self, AuthConfig, Config, FeatureCheck, Protocol, RtcBase};
 
 use anyhow::{bail, Context, Result};
+use app_compose::AppCompose;
 use bon::Builder;
 use fs_err as fs;
+use futures::stream::{self, StreamExt};
 use guest_api::client::DefaultClient as GuestClient;
 use id_pool::IdPool;
 use kms_rpc::kms_client::KmsClient;
-use ra_rpc::client::RaClient;
+use ra_rpc::client::{FallbackRaClient, RaClient, RevocationList};
+use secrets::TmpCa;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek, SeekFrom};
 use std::net::IpAddr;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex, MutexGuard};
-use supervisor_client::SupervisorClient;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant};
+use supervisor_client::{supervisor::ProcessInfo, SupervisorClient};
 use teepod_rpc::{self as pb, VmConfiguration};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
-pub use image::{Image, ImageInfo};
-pub use qemu::{VmConfig, VmWorkDir};
+pub use image::{Image, ImageCache, ImageEntry, ImageInfo};
+use layout::LAYOUT_VERSION;
+pub use qemu::{
+    check_extra_qemu_args, check_save_state_on_stop, qemu_img_path, resize_hd, VmConfig, VmWorkDir,
+};
 
+mod disk_usage;
+mod gc;
+mod hooks;
 mod id_pool;
 mod image;
+mod layout;
+mod prune;
 mod qemu;
+mod qmp;
+mod reconcile;
+mod restart;
+mod secrets;
+
+/// Read bytes `start..end` of a file, nothing if it does not exist.
+fn read_file_range(path: &Path, start: u64, end: u64) -> Result<Vec<u8>> {
+    if !path.exists() || end <= start {
+        return Ok(vec![]);
+    }
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut buf = Vec::new();
+    file.take(end - start).read_to_end(&mut buf)?;
+    Ok(buf)
+}
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct PortMapping {
@@ -42,6 +72,33 @@ pub struct Manifest {
     pub image: String,
     pub port_map: Vec<PortMapping>,
     pub created_at_ms: u64,
+    /// VMs with higher priority are started first when teepod starts
+    #[serde(default)]
+    #[builder(default)]
+    pub boot_priority: u32,
+    /// Cordoned VMs are not started automatically, only by an explicit start request
+    #[serde(default)]
+    #[builder(default)]
+    pub cordoned: bool,
+    /// The VM refuses to launch if the rootfs_hash of the image differs from this
+    #[serde(default)]
+    pub expected_rootfs_hash: Option<String>,
+    /// Overrides `cvm.clock.rtc_base` for this VM
+    #[serde(default)]
+    pub rtc_base: Option<RtcBase>,
+    /// Overrides `cvm.clock.inject_host_time` for this VM
+    #[serde(default)]
+    pub inject_host_time: Option<bool>,
+    /// The key the id was derived from, if the id is deterministic
+    #[serde(default)]
+    pub instance_key: Option<String>,
+    /// The CID requested at creation, instead of one picked from the pool
+    #[serde(default)]
+    pub cid: Option<u32>,
+    /// Appended to the QEMU command line, see `check_extra_qemu_args` for what is allowed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[builder(default)]
+    pub extra_qemu_args: Vec<String>,
 }
 
 #[derive(Clone)]
@@ -49,6 +106,12 @@ pub struct App {
     pub config: Arc<Config>,
     pub supervisor: SupervisorClient,
     state: Arc<Mutex<AppState>>,
+    /// The tmp CA if it comes from a secrets manager
+    tmp_ca: Option<Arc<TmpCa>>,
+    /// Parsed metadata of the images in `image_path`
+    images: Arc<Mutex<ImageCache>>,
+    /// The API auth config in effect, starting as `config.auth` and replaced by reload_auth
+    auth: Arc<RwLock<Arc<AuthConfig>>>,
 }
 
 impl App {
@@ -64,56 +127,96 @@ impl App {
         VmWorkDir::new(self.config.run_path.join(id))
     }
 
-    pub fn new(config: Config, supervisor: SupervisorClient) -> Self {
+    pub fn new(config: Config, supervisor: SupervisorClient) -> Result<Self> {
         let cid_start = config.cvm.cid_start;
         let cid_end = cid_start.saturating_add(config.cvm.cid_pool_size);
         let cid_pool = IdPool::new(cid_start, cid_end);
-        Self {
+        let tmp_ca = TmpCa::load(&config.cvm)?.map(Arc::new);
+        Ok(Self {
             supervisor: supervisor.clone(),
             state: Arc::new(Mutex::new(AppState {
                 cid_pool,
                 vms: HashMap::new(),
+                stop_reported: HashSet::new(),
             })),
+            auth: Arc::new(RwLock::new(Arc::new(config.auth.clone()))),
             config: Arc::new(config),
-        }
+            tmp_ca,
+            images: Default::default(),
+        })
+    }
+
+    /// The API auth config in effect. A request should check everything against one snapshot.
+    pub fn auth(&self) -> Arc<AuthConfig> {
+        self.auth.read().unwrap().clone()
+    }
+
+    /// Read the auth section of the config file again and swap it in, returning the number of
+    /// tokens loaded. Requests that already took a snapshot keep using it.
+    pub fn reload_auth(&self) -> Result<usize> {
+        let figment = config::load_config_figment(self.config.config_file.as_deref());
+        let auth: AuthConfig = figment
+            .extract_inner("auth")
+            .context("Failed to load auth config")?;
+        let tokens = auth.tokens.len();
+        *self.auth.write().unwrap() = Arc::new(auth);
+        info!("Reloaded API auth config with {tokens} tokens");
+        Ok(tokens)
     }
 
     pub async fn load_vm(
         &self,
         work_dir: impl AsRef<Path>,
         cids_assigned: &HashMap<String, u32>,
+        auto_start: bool,
     ) -> Result<()> {
         let vm_work_dir = VmWorkDir::new(work_dir.as_ref());
+        vm_work_dir
+            .migrate_layout()
+            .context("Failed to migrate work dir layout")?;
         let manifest = vm_work_dir.manifest().context("Failed to read manifest")?;
         let todo = "sanitize the image name";
         let image_path = self.config.image_path.join(&manifest.image);
         let image = Image::load(&image_path).context("Failed to load image")?;
         let vm_id = manifest.id.clone();
+        // Checked before taking a CID so a rejected VM does not leak one
+        if manifest.disk_size > self.config.cvm.max_disk_size {
+            bail!(
+                "disk size too large, max size is {}",
+                self.config.cvm.max_disk_size
+            );
+        }
         {
             let mut teapot = self.lock();
-            let cid = teapot
+            let cid = match teapot
                 .get(&vm_id)
                 .map(|vm| vm.config.cid)
                 .or_else(|| cids_assigned.get(&vm_id).cloned())
-                .or_else(|| teapot.cid_pool.allocate())
-                .context("CID pool exhausted")?;
+            {
+                Some(cid) => cid,
+                None => match manifest.cid {
+                    Some(cid) => {
+                        teapot
+                            .cid_pool
+                            .occupy(cid)
+                            .with_context(|| format!("CID {cid} is already in use"))?;
+                        cid
+                    }
+                    None => teapot.cid_pool.allocate().context("CID pool exhausted")?,
+                },
+            };
             let vm_config = VmConfig {
                 manifest,
                 image,
                 cid,
                 networking: self.config.networking.clone(),
+                clock: self.config.cvm.clock.clone(),
                 workdir: vm_work_dir.path().to_path_buf(),
             };
-            if vm_config.manifest.disk_size > self.config.cvm.max_disk_size {
-                bail!(
-                    "disk size too large, max size is {}",
-                    self.config.cvm.max_disk_size
-                );
-            }
             teapot.add(VmState::new(vm_config));
         };
         let started = vm_work_dir.started().context("Failed to read VM state")?;
-        if started {
+        if auto_start && started {
             self.start_vm(&vm_id).await?;
         }
 
@@ -121,6 +224,7 @@ impl App {
     }
 
     pub async fn start_vm(&self, id: &str) -> Result<()> {
+        self.check_port_conflicts(id)?;
         self.sync_dynamic_config(id)?;
         let is_running = self
             .supervisor
@@ -131,14 +235,22 @@ impl App {
             let mut state = self.lock();
             let vm_state = state.get_mut(id).context("VM not found")?;
             let work_dir = self.work_dir(id);
-            work_dir
-                .set_started(true)
-                .with_context(|| format!("Failed to set started for VM {id}"))?;
+            // Keep the start time of a VM that is already up, e.g. when teepod restarts
+            if !(is_running && work_dir.started().unwrap_or(false)) {
+                work_dir
+                    .set_started(true)
+                    .with_context(|| format!("Failed to set started for VM {id}"))?;
+            }
             if work_dir.serial_pty().exists() {
                 // remove the existing pty
                 fs::remove_file(work_dir.serial_pty())
                     .context("Failed to remove existing pty link")?;
             }
+            if !is_running {
+                work_dir
+                    .rotate_stderr()
+                    .context("Failed to rotate stderr log")?;
+            }
             let process_config = vm_state
                 .config
                 .config_qemu(&self.config.qemu_path, &work_dir)?;
@@ -154,26 +266,149 @@ impl App {
             .deploy(process_config)
             .await
             .with_context(|| format!("Failed to start VM {id}"))?;
+        self.lock().stop_reported.remove(id);
         Ok(())
     }
 
     pub async fn stop_vm(&self, id: &str) -> Result<()> {
         let work_dir = self.work_dir(id);
+        let report = self.claim_stop_of(id, self.supervisor.info(id).await?.as_ref());
         work_dir
             .set_started(false)
             .context("Failed to set started")?;
-        self.supervisor.stop(id).await?;
+        if let Err(err) = self.supervisor.stop(id).await {
+            if report {
+                self.lock().stop_reported.remove(id);
+            }
+            return Err(err);
+        }
+        if report {
+            self.report_stop(id).await;
+        }
         Ok(())
     }
 
+    /// Press the ACPI power button of a VM and wait up to `timeout` for it to power off,
+    /// killing it if it does not. Returns whether the VM powered off by itself.
+    pub async fn stop_vm_gracefully(&self, id: &str, timeout: Duration) -> Result<bool> {
+        const POLL_INTERVAL: Duration = Duration::from_millis(500);
+        let work_dir = self.work_dir(id);
+        let info = self.supervisor.info(id).await?;
+        let report = self.claim_stop_of(id, info.as_ref());
+        work_dir
+            .set_started(false)
+            .context("Failed to set started")?;
+        let is_running =
+            |info: Option<ProcessInfo>| info.map_or(false, |info| info.state.status.is_running());
+        if !is_running(info) {
+            if report {
+                self.report_stop(id).await;
+            }
+            return Ok(true);
+        }
+        match qmp::system_powerdown(&work_dir.qmp_socket()).await {
+            Ok(()) => {
+                let deadline = tokio::time::Instant::now() + timeout;
+                while tokio::time::Instant::now() < deadline {
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                    if !is_running(self.supervisor.info(id).await?) {
+                        info!("VM {id} powered off");
+                        if report {
+                            self.report_stop(id).await;
+                        }
+                        return Ok(true);
+                    }
+                }
+                warn!("VM {id} did not power off within {timeout:?}, killing it");
+            }
+            Err(err) => warn!("Failed to power down VM {id}, killing it: {err:?}"),
+        }
+        if let Err(err) = self.supervisor.stop(id).await {
+            if report {
+                self.lock().stop_reported.remove(id);
+            }
+            return Err(err);
+        }
+        if report {
+            self.report_stop(id).await;
+        }
+        Ok(false)
+    }
+
+    /// Remove a stopped VM. If a removal grace period is configured, the work dir is retained
+    /// until it expires or the VM is purged.
     pub async fn remove_vm(&self, id: &str) -> Result<()> {
+        self.unload_vm(id).await?;
+        let work_dir = self.work_dir(id);
+        if self.config.cvm.removal_grace_period.is_zero() {
+            fs::remove_dir_all(&work_dir).context("Failed to remove VM directory")?;
+        } else {
+            work_dir.mark_removed()?;
+        }
+        Ok(())
+    }
+
+    /// Remove a stopped VM and delete its work dir immediately, also for a VM already removed
+    /// but still within the removal grace period.
+    pub async fn purge_vm(&self, id: &str) -> Result<()> {
+        let work_dir = self.work_dir(id);
+        if !work_dir.manifest_path().exists() {
+            bail!("VM not found");
+        }
+        self.unload_vm(id).await?;
+        fs::remove_dir_all(&work_dir).context("Failed to remove VM directory")?;
+        Ok(())
+    }
+
+    /// Delete the work dirs of removed VMs whose removal grace period has expired.
+    pub async fn purge_expired_vms(self) {
+        let grace_period = self.config.cvm.removal_grace_period;
+        if grace_period.is_zero() {
+            return;
+        }
+        let mut interval = tokio::time::interval(Duration::from_secs(60).min(grace_period));
+        loop {
+            interval.tick().await;
+            let entries = match fs::read_dir(self.vm_dir()) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    error!("Failed to read VM directory: {err:?}");
+                    continue;
+                }
+            };
+            for entry in entries.flatten() {
+                let work_dir = VmWorkDir::new(entry.path());
+                let removed_at = match work_dir.removed_at() {
+                    Ok(Some(removed_at)) => removed_at,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        error!("Failed to read removal time of {:?}: {err:?}", entry.path());
+                        continue;
+                    }
+                };
+                if removed_at.elapsed().unwrap_or_default() < grace_period {
+                    continue;
+                }
+                info!("Purging removed VM {:?}", entry.path());
+                if let Err(err) = fs::remove_dir_all(work_dir.path()) {
+                    error!("Failed to purge {:?}: {err:?}", entry.path());
+                }
+            }
+        }
+    }
+
+    /// Remove a VM from the supervisor and teepod's state, leaving the work dir in place.
+    async fn unload_vm(&self, id: &str) -> Result<()> {
         let info = self.supervisor.info(id).await?;
         let is_running = info.as_ref().map_or(false, |i| i.state.status.is_running());
         if is_running {
             bail!("VM is running, stop it first");
         }
+        // A VM that crashed may be removed before the crash watcher sees it
+        let report = self.claim_stop_of(id, info.as_ref());
+        let app_id = self.app_id_of(id);
 
-        if let Some(info) = info {
+        if let Some(info) = &info {
             if !info.state.status.is_stopped() {
                 self.supervisor.stop(id).await?;
             }
@@ -185,10 +420,177 @@ impl App {
             if let Some(vm_state) = state.remove(id) {
                 state.cid_pool.free(vm_state.config.cid);
             }
+            state.stop_reported.remove(id);
+        }
+        if report {
+            self.spawn_post_stop_hook(id, app_id, info.as_ref());
+        }
+        Ok(())
+    }
+
+    /// Read the tail of qemu's stderr from the most recent launch attempt.
+    ///
+    /// Works from the work dir alone, so it also covers VMs that failed to load.
+    pub fn launch_log(&self, id: &str, max_bytes: u64) -> Result<pb::LaunchLogResponse> {
+        const DEFAULT_MAX_BYTES: u64 = 64 * 1024;
+
+        let work_dir = self.work_dir(id);
+        if !work_dir.manifest_path().exists() {
+            bail!("VM not found");
+        }
+        let stderr_file = work_dir.stderr_file();
+        if !stderr_file.exists() {
+            return Ok(pb::LaunchLogResponse::default());
+        }
+        let max_bytes = if max_bytes == 0 {
+            DEFAULT_MAX_BYTES
+        } else {
+            max_bytes
+        };
+        let mut file = fs::File::open(&stderr_file)?;
+        let len = file.metadata()?.len();
+        let truncated = len > max_bytes;
+        if truncated {
+            file.seek(SeekFrom::Start(len - max_bytes))?;
+        }
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        Ok(pb::LaunchLogResponse {
+            stderr: String::from_utf8_lossy(&buf).into_owned(),
+            truncated,
+        })
+    }
+
+    /// Read the serial console output of a VM.
+    ///
+    /// Without `offset`, returns the last `lines` lines. With it, returns the output written
+    /// after `offset`, waiting a while for some to appear if `follow` is set.
+    pub async fn vm_logs(
+        &self,
+        id: &str,
+        lines: u32,
+        offset: Option<u64>,
+        follow: bool,
+    ) -> Result<pb::VmLogsResponse> {
+        const DEFAULT_LINES: u32 = 100;
+        const MAX_LINES: u32 = 10000;
+        const MAX_BYTES: u64 = 1024 * 1024;
+        const FOLLOW_TIMEOUT: Duration = Duration::from_secs(30);
+        const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+        let work_dir = self.work_dir(id);
+        if !work_dir.manifest_path().exists() {
+            bail!("VM not found");
+        }
+        let serial_file = work_dir.serial_file();
+        let file_len = || fs::metadata(&serial_file).map_or(0, |m| m.len());
+
+        let Some(mut offset) = offset else {
+            let lines = match lines {
+                0 => DEFAULT_LINES,
+                n => n.min(MAX_LINES),
+            };
+            let len = file_len();
+            let start = len.saturating_sub(MAX_BYTES);
+            let buf = read_file_range(&serial_file, start, len)?;
+            let text = String::from_utf8_lossy(&buf);
+            let mut tail = text.split_inclusive('\n').collect::<Vec<_>>();
+            if start > 0 && tail.len() > 1 {
+                // Cut in the middle of a line
+                tail.remove(0);
+            }
+            let skip = tail.len().saturating_sub(lines as usize);
+            return Ok(pb::VmLogsResponse {
+                log: tail[skip..].concat(),
+                next_offset: len,
+            });
+        };
+        let deadline = Instant::now() + FOLLOW_TIMEOUT;
+        let mut len = file_len();
+        loop {
+            if len < offset {
+                // The log was recreated
+                offset = 0;
+            }
+            if !follow || len > offset || Instant::now() >= deadline {
+                break;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+            len = file_len();
         }
+        let end = len.min(offset + MAX_BYTES);
+        let buf = read_file_range(&serial_file, offset, end)?;
+        Ok(pb::VmLogsResponse {
+            log: String::from_utf8_lossy(&buf).into_owned(),
+            next_offset: end,
+        })
+    }
 
+    /// Update the manifest of a loaded VM, both on disk and in memory.
+    pub(crate) fn update_manifest(&self, id: &str, f: impl FnOnce(&mut Manifest)) -> Result<()> {
+        let mut state = self.lock();
+        let vm = state.get_mut(id).context("VM not found")?;
+        let mut manifest = vm.config.manifest.clone();
+        f(&mut manifest);
+        self.work_dir(id)
+            .put_manifest(&manifest)
+            .context("Failed to write manifest")?;
+        Arc::make_mut(&mut vm.config).manifest = manifest;
+        Ok(())
+    }
+
+    /// Rename a VM. With `unique_names`, a name taken by another VM is rejected.
+    pub(crate) fn rename_vm(&self, id: &str, name: &str) -> Result<()> {
+        let mut state = self.lock();
+        if self.config.cvm.unique_names
+            && state
+                .iter_vms()
+                .any(|vm| vm.config.manifest.name == name && vm.config.manifest.id != id)
+        {
+            bail!("VM name {name} is already taken");
+        }
+        let vm = state.get_mut(id).context("VM not found")?;
+        let mut manifest = vm.config.manifest.clone();
+        manifest.name = name.to_string();
+        self.work_dir(id)
+            .put_manifest(&manifest)
+            .context("Failed to write manifest")?;
+        Arc::make_mut(&mut vm.config).manifest = manifest;
+        Ok(())
+    }
+
+    /// Remove a VM regardless of its state. Used to clean up a VM that failed to be created.
+    pub(crate) async fn rollback_vm(&self, id: &str) -> Result<()> {
+        if let Some(info) = self.supervisor.info(id).await? {
+            if !info.state.status.is_stopped() {
+                self.supervisor.stop(id).await?;
+            }
+            self.supervisor.remove(id).await?;
+        }
+        {
+            let mut state = self.lock();
+            if let Some(vm_state) = state.remove(id) {
+                state.cid_pool.free(vm_state.config.cid);
+            }
+        }
         let vm_path = self.work_dir(id);
-        fs::remove_dir_all(&vm_path).context("Failed to remove VM directory")?;
+        if vm_path.exists() {
+            fs::remove_dir_all(&vm_path).context("Failed to remove VM directory")?;
+        }
+        Ok(())
+    }
+
+    /// Check that a CID requested for a new VM is within the pool and free.
+    pub(crate) fn check_requested_cid(&self, cid: u32) -> Result<()> {
+        let state = self.lock();
+        if !state.cid_pool.in_range(&cid) {
+            let start = self.config.cvm.cid_start;
+            let end = start.saturating_add(self.config.cvm.cid_pool_size);
+            bail!("CID {cid} is outside of the CID pool ({start}, {end})");
+        }
+        if state.cid_pool.is_allocated(&cid) {
+            bail!("CID {cid} is already in use");
+        }
         Ok(())
     }
 
@@ -206,20 +608,77 @@ impl App {
             }
         }
         if vm_path.exists() {
+            let mut vm_paths = vec![];
             for entry in fs::read_dir(vm_path).context("Failed to read VM directory")? {
                 let entry = entry.context("Failed to read directory entry")?;
                 let vm_path = entry.path();
+                if VmWorkDir::new(&vm_path).removed_marker_path().exists() {
+                    continue;
+                }
                 if vm_path.is_dir() {
-                    if let Err(err) = self.load_vm(vm_path, &occupied_cids).await {
-                        error!("Failed to load VM: {err:?}");
-                    }
+                    vm_paths.push(vm_path);
+                }
+            }
+            // Load VMs with a requested CID first so the pool does not hand their CIDs to others
+            vm_paths.sort_by_key(|path| {
+                let manifest = VmWorkDir::new(path).manifest().ok();
+                manifest.map_or(true, |m| m.cid.is_none())
+            });
+            for vm_path in vm_paths {
+                if let Err(err) = self.load_vm(vm_path, &occupied_cids, false).await {
+                    error!("Failed to load VM: {err:?}");
                 }
             }
         }
+        self.start_loaded_vms().await;
         Ok(())
     }
 
+    /// Start the loaded VMs that were running before, in order of boot priority.
+    ///
+    /// VMs of the same priority are started concurrently, bounded by `startup.concurrency`.
+    /// A priority group is started only after the previous group has been started.
+    async fn start_loaded_vms(&self) {
+        let mut manifests = self
+            .lock()
+            .iter_vms()
+            .map(|vm| vm.config.manifest.clone())
+            .filter(|manifest| self.work_dir(&manifest.id).started().unwrap_or(false))
+            .filter(|manifest| {
+                if manifest.cordoned {
+                    info!("Skipping cordoned VM {}({})", manifest.name, manifest.id);
+                }
+                !manifest.cordoned
+            })
+            .collect::<Vec<_>>();
+        manifests.sort_by_key(|m| (Reverse(m.boot_priority), m.created_at_ms));
+        let order = manifests
+            .iter()
+            .map(|m| format!("{}({}, priority={})", m.name, m.id, m.boot_priority))
+            .collect::<Vec<_>>();
+        info!("Starting VMs in order: {order:?}");
+
+        let concurrency = self.config.startup.concurrency.max(1);
+        for group in manifests.chunk_by(|a, b| a.boot_priority == b.boot_priority) {
+            stream::iter(group)
+                .for_each_concurrent(concurrency, |manifest| async move {
+                    if let Err(err) = self.start_vm(&manifest.id).await {
+                        error!("Failed to start VM {}: {err:?}", manifest.id);
+                    }
+                })
+                .await;
+        }
+    }
+
     pub async fn list_vms(&self) -> Result<Vec<pb::VmInfo>> {
+        self.list_vms_where(|_| true).await
+    }
+
+    /// List the VMs whose manifest matches `filter`, oldest first.
+    pub async fn list_vms_where(
+        &self,
+        filter: impl Fn(&Manifest) -> bool,
+    ) -> Result<Vec<pb::VmInfo>> {
         let vms = self
             .supervisor
             .list()
@@ -232,6 +691,7 @@ impl App {
         let mut infos = self
             .lock()
             .iter_vms()
+            .filter(|vm| filter(&vm.config.manifest))
             .map(|vm| {
                 vm.merged_info(
                     vms.get(&vm.config.manifest.id),
@@ -247,16 +707,28 @@ impl App {
         Ok(lst)
     }
 
-    pub fn list_images(&self) -> Result<Vec<(String, ImageInfo)>> {
-        let image_path = self.config.image_path.clone();
-        let images = fs::read_dir(image_path).context("Failed to read image directory")?;
-        Ok(images
-            .flat_map(|entry| {
-                let path = entry.ok()?.path();
-                let img = Image::load(&path).ok()?;
-                Some((path.file_name()?.to_string_lossy().to_string(), img.info))
-            })
-            .collect())
+    /// Load the metadata of all images from scratch, e.g. after images were modified in place.
+    pub fn rescan_images(&self) -> Result<()> {
+        let mut images = self.images.lock().unwrap();
+        images.clear();
+        self.refresh_images(&mut images)
+    }
+
+    fn refresh_images(&self, images: &mut ImageCache) -> Result<()> {
+        let cfg = &self.config.startup;
+        images.refresh(
+            &self.config.image_path,
+            cfg.image_scan_concurrency,
+            cfg.image_scan_batch_size,
+        )
+    }
+
+    /// List all entries of the image dir. Entries are served from the cache, which picks up
+    /// added, removed and modified images on each call.
+    pub fn list_images_detailed(&self) -> Result<Vec<Arc<ImageEntry>>> {
+        let mut images = self.images.lock().unwrap();
+        self.refresh_images(&mut images)?;
+        Ok(images.entries())
     }
 
     pub async fn vm_info(&self, id: &str) -> Result<Option<pb::VmInfo>> {
@@ -265,12 +737,159 @@ impl App {
         let Some(vm_state) = state.get(id) else {
             return Ok(None);
         };
-        let info = vm_state
-            .merged_info(proc_state.as_ref(), &self.work_dir(id))
+        let work_dir = self.work_dir(id);
+        let mut info = vm_state
+            .merged_info(proc_state.as_ref(), &work_dir)
             .to_pb(&self.config.gateway);
+        info.features = fs::read_to_string(work_dir.app_compose_path())
+            .ok()
+            .and_then(|compose| self.feature_wiring(&compose).ok());
         Ok(Some(info))
     }
 
+    /// Poll a VM until `reached` returns true for its info or `timeout` elapses.
+    /// Returns whether the state was reached along with the last seen info.
+    pub async fn wait_vm(
+        &self,
+        id: &str,
+        timeout: Duration,
+        reached: impl Fn(&pb::VmInfo) -> bool,
+    ) -> Result<(bool, pb::VmInfo)> {
+        const POLL_INTERVAL: Duration = Duration::from_millis(500);
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let info = self.vm_info(id).await?.context("VM not found")?;
+            if reached(&info) {
+                return Ok((true, info));
+            }
+            if tokio::time::Instant::now() + POLL_INTERVAL > deadline {
+                return Ok((false, info));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Cross-check the features an app compose enables with the endpoints configured for CVMs.
+    pub(crate) fn feature_wiring(&self, compose_file: &str) -> Result<pb::FeatureWiring> {
+        let compose: AppCompose =
+            serde_json::from_str(compose_file).context("Invalid compose file")?;
+        Ok(pb::FeatureWiring {
+            kms_requested: compose.kms_enabled(),
+            kms_available: !self.config.cvm.kms_url.is_empty(),
+            tproxy_requested: compose.tproxy_enabled(),
+            tproxy_available: !self.config.cvm.tproxy_url.is_empty(),
+        })
+    }
+
+    /// Reject or warn about an app compose that enables features the host can not provide.
+    pub(crate) fn check_features(&self, compose_file: &str) -> Result<()> {
+        let wiring = self.feature_wiring(compose_file)?;
+        let mut missing = vec![];
+        if wiring.kms_requested && !wiring.kms_available {
+            missing.push("kms");
+        }
+        if wiring.tproxy_requested && !wiring.tproxy_available {
+            missing.push("tproxy");
+        }
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let msg = format!(
+            "the app enables {} but teepod has no URL configured for it",
+            missing.join(", ")
+        );
+        match self.config.cvm.feature_check {
+            FeatureCheck::Error => bail!("{msg}"),
+            FeatureCheck::Warn => {
+                warn!("{msg}");
+                Ok(())
+            }
+        }
+    }
+
+    /// Find the id of a VM by its name. Names are not unique unless `cvm.unique_names` is set,
+    /// so this fails rather than picking one of several VMs with the name.
+    pub fn find_vm_by_name(&self, name: &str) -> Result<Option<String>> {
+        let state = self.lock();
+        let mut found = state
+            .iter_vms()
+            .filter(|vm| vm.config.manifest.name == name);
+        let Some(vm) = found.next() else {
+            return Ok(None);
+        };
+        if found.next().is_some() {
+            bail!("ambiguous VM name {name}, more than one VM has it");
+        }
+        Ok(Some(vm.config.manifest.id.clone()))
+    }
+
+    fn vm_name_taken(&self, name: &str) -> bool {
+        self.lock()
+            .iter_vms()
+            .any(|vm| vm.config.manifest.name == name)
+    }
+
+    /// Host ports mapped by the started VMs other than `except`, keyed by (protocol, port), with
+    /// the VM name as value. Stopped VMs keep their port map but release the ports.
+    pub(crate) fn mapped_host_ports(&self, except: Option<&str>) -> HashMap<(String, u16), String> {
+        self.lock()
+            .iter_vms()
+            .filter(|vm| {
+                let id = &vm.config.manifest.id;
+                Some(id.as_str()) != except && self.work_dir(id).started().unwrap_or(false)
+            })
+            .flat_map(|vm| {
+                let name = &vm.config.manifest.name;
+                vm.config
+                    .manifest
+                    .port_map
+                    .iter()
+                    .map(move |pm| ((pm.protocol.as_str().to_string(), pm.from), name.clone()))
+            })
+            .collect()
+    }
+
+    /// Fail if a host port of the VM is mapped by another started VM.
+    fn check_port_conflicts(&self, id: &str) -> Result<()> {
+        let port_map = self
+            .lock()
+            .get(id)
+            .context("VM not found")?
+            .config
+            .manifest
+            .port_map
+            .clone();
+        if port_map.is_empty() {
+            return Ok(());
+        }
+        let in_use = self.mapped_host_ports(Some(id));
+        for pm in &port_map {
+            let protocol = pm.protocol.as_str();
+            if let Some(vm) = in_use.get(&(protocol.to_string(), pm.from)) {
+                bail!(
+                    "host port {protocol}/{} is already mapped by VM {vm}",
+                    pm.from
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the naming policy to the name of a new VM.
+    pub(crate) fn assign_vm_name(&self, name: &str) -> Result<String> {
+        let cfg = &self.config.cvm;
+        if !cfg.unique_names || !self.vm_name_taken(name) {
+            return Ok(name.to_string());
+        }
+        if !cfg.auto_suffix_names {
+            bail!("VM name {name} is already taken");
+        }
+        (2..)
+            .map(|n| format!("{name}-{n}"))
+            .find(|candidate| !self.vm_name_taken(candidate))
+            .context("Failed to find an available name")
+    }
+
     pub(crate) fn vm_event_report(&self, cid: u32, event: &str, body: String) -> Result<()> {
         info!(cid, event, "VM event");
         let mut state = self.lock();
@@ -280,12 +899,19 @@ impl App {
         match event {
             "boot.progress" => {
                 vm.state.boot_progress = body;
+                let id = vm.config.manifest.id.clone();
+                drop(state);
+                self.shred_tmp_ca_key(&id);
             }
             "boot.error" => {
                 vm.state.boot_error = body;
             }
             "shutdown.progress" => {
                 vm.state.shutdown_progress = body;
+                vm.state.ready = false;
+            }
+            "app.ready" => {
+                vm.state.ready = true;
             }
             "instance.info" => {
                 if body.len() > 1024 * 4 {
@@ -303,6 +929,28 @@ impl App {
         Ok(())
     }
 
+    /// Read a compose file referenced by a path relative to the configured compose dir.
+    pub(crate) fn read_compose_file_ref(&self, path: &str) -> Result<String> {
+        let base = &self.config.compose_dir;
+        if base == &PathBuf::default() {
+            bail!("compose file references are not enabled");
+        }
+        let relative = Path::new(path);
+        if !relative
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+        {
+            bail!("invalid compose file ref: {path}");
+        }
+        let base = fs::canonicalize(base).context("Failed to resolve compose dir")?;
+        let full_path = fs::canonicalize(base.join(relative))
+            .with_context(|| format!("compose file not found: {path}"))?;
+        if !full_path.starts_with(&base) {
+            bail!("compose file ref escapes the compose dir: {path}");
+        }
+        fs::read_to_string(&full_path).context("Failed to read compose file")
+    }
+
     pub(crate) fn compose_file_path(&self, id: &str) -> PathBuf {
         self.shared_dir(id).join("app-compose.json")
     }
@@ -319,12 +967,25 @@ impl App {
         let work_dir = self.work_dir(id);
         let shared_dir = work_dir.join("shared");
         fs::create_dir_all(&shared_dir).context("Failed to create shared directory")?;
+        work_dir.set_layout_version(LAYOUT_VERSION)?;
         fs::write(shared_dir.join("app-compose.json"), &req.compose_file)
             .context("Failed to write compose file")?;
         if !req.encrypted_env.is_empty() {
             fs::write(shared_dir.join("encrypted-env"), &req.encrypted_env)
                 .context("Failed to write encrypted env")?;
         }
+        if !req.env.is_empty() {
+            let env = req
+                .env
+                .iter()
+                .map(|(key, value)| serde_json::json!({ "key": key, "value": value }))
+                .collect::<Vec<_>>();
+            fs::write(
+                shared_dir.join("plain-env"),
+                serde_json::to_string(&serde_json::json!({ "env": env }))?,
+            )
+            .context("Failed to write plain env")?;
+        }
         let app_id = req.app_id.clone().unwrap_or_default();
         if !app_id.is_empty() {
             let instance_info = serde_json::json!({
@@ -352,6 +1013,14 @@ impl App {
         let rootfs_hash = image_info
             .rootfs_hash
             .context("Rootfs hash not found in image info")?;
+        if let Some(expected) = &manifest.expected_rootfs_hash {
+            if !expected.eq_ignore_ascii_case(&rootfs_hash) {
+                bail!(
+                    "rootfs hash mismatch for image {}: expected {expected}, got {rootfs_hash}",
+                    manifest.image
+                );
+            }
+        }
         let vm_config = serde_json::json!({
             "rootfs_hash": rootfs_hash,
             "kms_url": cfg.cvm.kms_url,
@@ -364,20 +1033,33 @@ impl App {
         fs::write(shared_dir.join("config.json"), vm_config_str)
             .context("Failed to write vm config")?;
         fs::copy(&cfg.cvm.ca_cert, certs_dir.join("ca.cert")).context("Failed to copy ca cert")?;
-        fs::copy(&cfg.cvm.tmp_ca_cert, certs_dir.join("tmp-ca.cert"))
-            .context("Failed to copy tmp ca cert")?;
-        fs::copy(&cfg.cvm.tmp_ca_key, certs_dir.join("tmp-ca.key"))
-            .context("Failed to copy tmp ca key")?;
+        self.materialize_tmp_ca(&certs_dir)?;
         Ok(())
     }
 
-    pub(crate) fn kms_client(&self) -> Result<KmsClient<RaClient>> {
+    pub(crate) fn kms_client(&self) -> Result<KmsClient<FallbackRaClient>> {
         if self.config.kms_url.is_empty() {
             bail!("KMS is not configured");
         }
-        let url = format!("{}/prpc", self.config.kms_url);
-        let prpc_client = RaClient::new(url, true);
-        Ok(KmsClient::new(prpc_client))
+        let revocation = &self.config.kms_revocation;
+        let revocation_list = if revocation.enabled {
+            let list = RevocationList::load(&revocation.list_file)
+                .context("Failed to load KMS revocation list")?;
+            Some(list)
+        } else {
+            None
+        };
+        let clients = std::iter::once(&self.config.kms_url)
+            .chain(&self.config.kms_fallback_urls)
+            .map(|url| {
+                let client = RaClient::new(format!("{url}/prpc"), true);
+                match &revocation_list {
+                    Some(list) => client.with_revocation_list(list.clone()),
+                    None => Ok(client),
+                }
+            })
+            .collect::<Result<_>>()?;
+        Ok(KmsClient::new(FallbackRaClient::new(clients)))
     }
 
     pub(crate) fn tappd_client(&self, id: &str) -> Result<GuestClient> {
@@ -399,6 +1081,8 @@ struct VmStateMut {
     boot_progress: String,
     boot_error: String,
     shutdown_progress: String,
+    /// The guest reported that the app inside is ready to serve
+    ready: bool,
 }
 
 impl VmStateMut {
@@ -410,12 +1094,14 @@ impl VmStateMut {
         };
         self.boot_error.clear();
         self.shutdown_progress.clear();
+        self.ready = false;
     }
 
     pub fn reset_na(&mut self) {
         self.boot_progress = "N/A".to_string();
         self.shutdown_progress = "N/A".to_string();
         self.boot_error.clear();
+        self.ready = false;
     }
 }
 
@@ -431,6 +1117,8 @@ impl VmState {
 pub(crate) struct AppState {
     cid_pool: IdPool<u32>,
     vms: HashMap<String, VmState>,
+    /// VMs whose last stop already ran the post-stop hook, until they are started again
+    stop_reported: HashSet<String>,
 }
 
 impl AppState {
@@ -0,0 +1,75 @@
+//! Disk space consumed under `run_path`.
+
+use std::path::Path;
+
+use fs_err as fs;
+use path_absolutize::Absolutize;
+use teepod_rpc as pb;
+
+use super::App;
+
+impl App {
+    /// Usage of the filesystem backing `run_path`, None if no mounted disk contains it.
+    pub fn run_path_usage(&self) -> Option<pb::DiskUsage> {
+        let run_path = self.config.run_path.absolutize().ok()?;
+        let disks = sysinfo::Disks::new_with_refreshed_list();
+        mount_usage(
+            &run_path,
+            disks
+                .list()
+                .iter()
+                .map(|d| (d.mount_point(), d.total_space(), d.available_space())),
+        )
+    }
+
+    /// Size of the disk image of each loaded VM, zero if the image is missing.
+    pub fn vm_disk_usage(&self) -> Vec<pb::VmDiskUsage> {
+        let ids: Vec<String> = self
+            .lock()
+            .iter_vms()
+            .map(|vm| vm.config.manifest.id.clone())
+            .collect();
+        ids.into_iter()
+            .map(|id| {
+                let image_size = fs::metadata(self.work_dir(&id).hda_path()).map_or(0, |m| m.len());
+                pb::VmDiskUsage { id, image_size }
+            })
+            .collect()
+    }
+}
+
+/// Usage of the mount among `mounts` (mount point, total, available) that contains `path`.
+fn mount_usage<'a>(
+    path: &Path,
+    mounts: impl Iterator<Item = (&'a Path, u64, u64)>,
+) -> Option<pb::DiskUsage> {
+    let (_, total, free) = mounts
+        .filter(|(mount_point, _, _)| path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _, _)| mount_point.components().count())?;
+    Some(pb::DiskUsage {
+        total,
+        used: total.saturating_sub(free),
+        free,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mount_usage() {
+        let mounts = [
+            (Path::new("/"), 1000, 600),
+            (Path::new("/var"), 500, 100),
+            (Path::new("/var/lib/other"), 50, 50),
+        ];
+        let usage = |path: &str| mount_usage(Path::new(path), mounts.iter().copied());
+
+        let var = usage("/var/lib/teepod/run").unwrap();
+        assert_eq!((var.total, var.used, var.free), (500, 400, 100));
+        let root = usage("/home/teepod/run").unwrap();
+        assert_eq!((root.total, root.used, root.free), (1000, 400, 600));
+        assert!(mount_usage(Path::new("/var"), std::iter::empty()).is_none());
+    }
+}
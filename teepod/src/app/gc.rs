@@ -0,0 +1,143 @@
+//! Reclaiming work dirs that belong to no loaded VM.
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use fs_err as fs;
+use tracing::info;
+
+use super::{App, VmWorkDir};
+
+/// A work dir under `run_path` that no loaded VM owns.
+#[derive(Debug, Clone)]
+pub struct OrphanedWorkDir {
+    pub id: String,
+    pub reason: String,
+}
+
+impl App {
+    /// Find the work dirs that have no valid manifest or whose VM is not loaded, and remove
+    /// them if `remove` is set.
+    ///
+    /// Work dirs of VMs marked as started, of removed VMs waiting to be purged and of VMs
+    /// that may still be being created are left alone.
+    pub fn gc_vms(&self, remove: bool) -> Result<Vec<OrphanedWorkDir>> {
+        // Hold the state lock so no VM gets loaded while its work dir is being removed.
+        let state = self.lock();
+        let loaded: HashSet<String> = state
+            .iter_vms()
+            .map(|vm| vm.config.manifest.id.clone())
+            .collect();
+        let vm_dir = self.vm_dir();
+        let mut orphans = vec![];
+        if !vm_dir.exists() {
+            return Ok(orphans);
+        }
+        let min_age = self.config.timeouts.create_vm;
+        for entry in fs::read_dir(&vm_dir).context("Failed to read VM directory")? {
+            let path = entry.context("Failed to read directory entry")?.path();
+            if !path.is_dir() {
+                continue;
+            }
+            let Some(reason) = orphan_reason(&path, &loaded, min_age) else {
+                continue;
+            };
+            let id = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            if remove {
+                info!("Removing orphaned work dir {id}: {reason}");
+                fs::remove_dir_all(&path)
+                    .with_context(|| format!("Failed to remove work dir {id}"))?;
+            }
+            orphans.push(OrphanedWorkDir { id, reason });
+        }
+        drop(state);
+        orphans.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(orphans)
+    }
+}
+
+/// Why the work dir at `path` is orphaned, or None if it must be kept.
+fn orphan_reason(path: &Path, loaded: &HashSet<String>, min_age: Duration) -> Option<String> {
+    let work_dir = VmWorkDir::new(path);
+    if work_dir.removed_marker_path().exists() {
+        return None;
+    }
+    let now = SystemTime::now();
+    match work_dir.manifest() {
+        Ok(manifest) => {
+            if loaded.contains(&manifest.id) || work_dir.started().unwrap_or(true) {
+                return None;
+            }
+            let created_at = UNIX_EPOCH + Duration::from_millis(manifest.created_at_ms);
+            if now.duration_since(created_at).unwrap_or_default() < min_age {
+                return None;
+            }
+            Some("VM is not loaded".into())
+        }
+        Err(err) => {
+            // Without a manifest, the work dir may be one create_vm has just made
+            let modified = fs::metadata(path).ok()?.modified().ok()?;
+            if now.duration_since(modified).unwrap_or_default() < min_age {
+                return None;
+            }
+            Some(format!("{err:#}"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::Manifest;
+
+    fn manifest(id: &str) -> Manifest {
+        Manifest::builder()
+            .id(id.into())
+            .name(id.into())
+            .app_id("app".into())
+            .image("image".into())
+            .vcpu(1)
+            .memory(1024)
+            .disk_size(10)
+            .port_map(vec![])
+            .created_at_ms(0)
+            .build()
+    }
+
+    #[test]
+    fn test_orphan_reason() {
+        let tmp = tempfile::tempdir().unwrap();
+        let run_path = tmp.path();
+        let loaded = HashSet::from(["loaded".to_string()]);
+
+        let corrupted = VmWorkDir::new(run_path.join("corrupted"));
+        fs::create_dir_all(corrupted.path()).unwrap();
+        fs::write(corrupted.manifest_path(), "{not json").unwrap();
+        for id in ["loaded", "started", "stopped"] {
+            let work_dir = VmWorkDir::new(run_path.join(id));
+            work_dir.put_manifest(&manifest(id)).unwrap();
+            work_dir.set_started(id == "started").unwrap();
+        }
+
+        let reason = |id: &str, min_age| orphan_reason(&run_path.join(id), &loaded, min_age);
+        let reason_corrupted = reason("corrupted", Duration::ZERO).unwrap();
+        assert!(
+            reason_corrupted.contains("Failed to parse manifest"),
+            "{reason_corrupted}"
+        );
+        assert_eq!(reason("loaded", Duration::ZERO), None);
+        assert_eq!(reason("started", Duration::ZERO), None);
+        assert_eq!(
+            reason("stopped", Duration::ZERO).as_deref(),
+            Some("VM is not loaded")
+        );
+        // Too young, may still be being created
+        assert_eq!(reason("corrupted", Duration::from_secs(3600)), None);
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use supervisor_client::supervisor::{ProcessInfo, ProcessStatus};
+use tokio::process::Command;
+use tracing::{error, info};
+
+use super::App;
+
+impl App {
+    /// Mark the current stop of a VM as reported, returning false if it already was.
+    ///
+    /// Each stop runs the post-stop hook once, whether it is reported by a stop request, by
+    /// unloading a VM that crashed, or by the crash watcher.
+    pub(crate) fn claim_stop_report(&self, id: &str) -> bool {
+        self.lock().stop_reported.insert(id.to_string())
+    }
+
+    /// Claim the stop report of a VM about to be stopped or unloaded if that ends a run: the VM
+    /// is running, or it was started and its process has exited since.
+    pub(crate) fn claim_stop_of(&self, id: &str, process: Option<&ProcessInfo>) -> bool {
+        let Some(process) = process else {
+            return false;
+        };
+        let ends_run =
+            process.state.status.is_running() || self.work_dir(id).started().unwrap_or(false);
+        ends_run && self.claim_stop_report(id)
+    }
+
+    /// Run the post-stop hook for a VM whose stop was claimed by a stop request.
+    pub(crate) async fn report_stop(&self, id: &str) {
+        let process = self.supervisor.info(id).await.ok().flatten();
+        self.spawn_post_stop_hook(id, self.app_id_of(id), process.as_ref());
+    }
+
+    /// Run the post-stop hook for a VM in the background.
+    ///
+    /// `app_id` is taken by the caller, as the VM may be unloaded before the hook runs.
+    pub(crate) fn spawn_post_stop_hook(
+        &self,
+        id: &str,
+        app_id: String,
+        process: Option<&ProcessInfo>,
+    ) {
+        let hook = self.config.hooks.post_stop.clone();
+        if hook.is_empty() {
+            return;
+        }
+        let id = id.to_string();
+        let exit_code = match process.map(|p| &p.state.status) {
+            Some(ProcessStatus::Exited(code)) => code.to_string(),
+            _ => String::new(),
+        };
+        tokio::spawn(async move {
+            if let Err(err) = run_post_stop_hook(&hook, &id, &app_id, &exit_code).await {
+                error!("Post-stop hook failed for VM {id}: {err:?}");
+            }
+        });
+    }
+
+    pub(crate) fn app_id_of(&self, id: &str) -> String {
+        self.lock()
+            .get(id)
+            .map(|vm| vm.config.manifest.app_id.clone())
+            .unwrap_or_default()
+    }
+
+    /// Watch the VM processes and run the post-stop hook when a VM crashes.
+    ///
+    /// Stops requested through teepod run the hook themselves, so this only picks up VMs that
+    /// went from running to not running without their stop being reported yet.
+    pub async fn watch_vm_stops(self) {
+        if self.config.hooks.post_stop.is_empty() {
+            return;
+        }
+        let mut running = HashMap::<String, bool>::new();
+        let mut interval = tokio::time::interval(self.config.hooks.poll_interval);
+        loop {
+            interval.tick().await;
+            let processes = match self.supervisor.list().await {
+                Ok(processes) => processes,
+                Err(err) => {
+                    error!("Failed to list VMs: {err:?}");
+                    continue;
+                }
+            };
+            let mut next = HashMap::new();
+            for process in processes {
+                let id = process.config.id.clone();
+                let is_running = process.state.status.is_running();
+                if !is_running
+                    && running.get(&id).copied().unwrap_or(false)
+                    && self.claim_stop_report(&id)
+                {
+                    info!("VM {id} stopped unexpectedly");
+                    self.spawn_post_stop_hook(&id, self.app_id_of(&id), Some(&process));
+                }
+                next.insert(id, is_running);
+            }
+            running = next;
+        }
+    }
+}
+
+async fn run_post_stop_hook(hook: &str, id: &str, app_id: &str, exit_code: &str) -> Result<()> {
+    info!("Running post-stop hook for VM {id}");
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(hook)
+        .env("TEEPOD_VM_ID", id)
+        .env("TEEPOD_APP_ID", app_id)
+        .env("TEEPOD_EXIT_CODE", exit_code)
+        .output()
+        .await
+        .context("Failed to run hook")?;
+    if !output.status.success() {
+        bail!(
+            "hook exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
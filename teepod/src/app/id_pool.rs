@@ -40,6 +40,15 @@ impl<T: Number> IdPool<T> {
         }
     }
 
+    /// Whether `id` is one the pool could hand out, regardless of whether it is taken.
+    pub fn in_range(&self, id: &T) -> bool {
+        *id > self.start && *id < self.end
+    }
+
+    pub fn is_allocated(&self, id: &T) -> bool {
+        self.allocated.contains(id)
+    }
+
     pub fn allocate(&mut self) -> Option<T> {
         let mut id = self.start.clone();
         while let Some(next) = id.next() {
@@ -59,3 +68,34 @@ impl<T: Number> IdPool<T> {
         self.allocated.remove(&id);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_and_reuse() {
+        // Ids are handed out from start + 1 up to end - 1
+        let mut pool = IdPool::new(10u32, 14);
+        assert_eq!(pool.allocate(), Some(11));
+        pool.occupy(12).unwrap();
+        assert!(pool.occupy(12).is_err());
+        assert_eq!(pool.allocate(), Some(13));
+        assert_eq!(pool.allocate(), None);
+
+        pool.free(12);
+        assert!(!pool.is_allocated(&12));
+        assert_eq!(pool.allocate(), Some(12));
+        assert_eq!(pool.allocate(), None);
+
+        // The lowest free id is reused first
+        pool.free(13);
+        pool.free(11);
+        assert_eq!(pool.allocate(), Some(11));
+        assert_eq!(pool.allocate(), Some(13));
+
+        assert!(!pool.in_range(&10));
+        assert!(pool.in_range(&13));
+        assert!(!pool.in_range(&14));
+    }
+}
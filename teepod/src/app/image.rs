@@ -1,11 +1,14 @@
 use fs_err as fs;
 use path_absolutize::Absolutize;
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ImageInfo {
     pub cmdline: Option<String>,
     pub kernel: String,
@@ -20,6 +23,29 @@ pub struct ImageInfo {
     pub version: String,
     #[serde(default)]
     pub is_dev: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Resources and ports used for a VM when the create request leaves them out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vm_defaults: Option<VmDefaults>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VmDefaults {
+    pub vcpu: Option<u32>,
+    /// Memory in MB
+    pub memory: Option<u32>,
+    /// Disk size in GB
+    pub disk_size: Option<u32>,
+    #[serde(default)]
+    pub ports: Vec<DefaultPortMapping>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DefaultPortMapping {
+    pub protocol: String,
+    pub host_port: u32,
+    pub vm_port: u32,
 }
 
 impl ImageInfo {
@@ -31,7 +57,147 @@ impl ImageInfo {
     }
 }
 
+/// An entry of the image directory, possibly failed to load.
 #[derive(Debug)]
+pub struct ImageEntry {
+    pub name: String,
+    /// Raw contents of metadata.json
+    pub metadata: Option<String>,
+    /// Modification time of metadata.json
+    pub created_at: Option<SystemTime>,
+    /// Total size of the files in the image dir in bytes
+    pub size: u64,
+    pub image: Result<Image>,
+}
+
+impl ImageEntry {
+    pub fn load(base_path: impl AsRef<Path>) -> Option<Self> {
+        let base_path = base_path.as_ref();
+        let name = base_path.file_name()?.to_string_lossy().to_string();
+        let metadata_path = base_path.join("metadata.json");
+        let metadata = fs::read_to_string(&metadata_path).ok();
+        let created_at = fs::metadata(&metadata_path).and_then(|m| m.modified()).ok();
+        let image = Image::load(base_path);
+        Some(Self {
+            name,
+            metadata,
+            created_at,
+            size: dir_size(base_path),
+            image,
+        })
+    }
+}
+
+/// Total size of the files under `path`, skipping what can not be read.
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => dir_size(&entry.path()),
+            Ok(_) => entry.metadata().map_or(0, |m| m.len()),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Parsed entries of the image directory, so listing images does not parse every metadata.json.
+#[derive(Default)]
+pub struct ImageCache {
+    entries: BTreeMap<String, CachedImage>,
+}
+
+struct CachedImage {
+    stamp: Option<SystemTime>,
+    entry: Arc<ImageEntry>,
+}
+
+impl ImageCache {
+    /// Bring the cache in line with `image_path`. New or modified images are loaded in batches
+    /// of `batch_size` on up to `concurrency` threads, removed images are dropped.
+    pub fn refresh(
+        &mut self,
+        image_path: &Path,
+        concurrency: usize,
+        batch_size: usize,
+    ) -> Result<()> {
+        let mut present = BTreeSet::new();
+        let mut stale = vec![];
+        for entry in fs::read_dir(image_path).context("Failed to read image directory")? {
+            let Ok(entry) = entry else {
+                continue;
+            };
+            let path = entry.path();
+            if !path.is_dir() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().to_string();
+            let stamp = image_stamp(&path);
+            if self.entries.get(&name).map(|cached| cached.stamp) != Some(stamp) {
+                stale.push((path, stamp));
+            }
+            present.insert(name);
+        }
+        self.entries.retain(|name, _| present.contains(name));
+        for (stamp, entry) in load_in_parallel(&stale, concurrency, batch_size) {
+            self.entries.insert(
+                entry.name.clone(),
+                CachedImage {
+                    stamp,
+                    entry: Arc::new(entry),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn entries(&self) -> Vec<Arc<ImageEntry>> {
+        self.entries
+            .values()
+            .map(|cached| cached.entry.clone())
+            .collect()
+    }
+}
+
+/// The latest modification time of an image dir and its metadata.json. Adding or replacing
+/// files in the dir bumps it, so a changed image gets reloaded.
+fn image_stamp(path: &Path) -> Option<SystemTime> {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    modified(path).max(modified(&path.join("metadata.json")))
+}
+
+fn load_in_parallel(
+    images: &[(PathBuf, Option<SystemTime>)],
+    concurrency: usize,
+    batch_size: usize,
+) -> Vec<(Option<SystemTime>, ImageEntry)> {
+    let batches = Mutex::new(images.chunks(batch_size.max(1)));
+    let n_batches = images.len().div_ceil(batch_size.max(1));
+    let loaded = Mutex::new(vec![]);
+    std::thread::scope(|s| {
+        for _ in 0..concurrency.clamp(1, n_batches.max(1)) {
+            s.spawn(|| loop {
+                let Some(batch) = batches.lock().unwrap().next() else {
+                    break;
+                };
+                let entries: Vec<_> = batch
+                    .iter()
+                    .flat_map(|(path, stamp)| Some((*stamp, ImageEntry::load(path)?)))
+                    .collect();
+                loaded.lock().unwrap().extend(entries);
+            });
+        }
+    });
+    loaded.into_inner().unwrap()
+}
+
+#[derive(Debug, Clone)]
 pub struct Image {
     pub info: ImageInfo,
     pub initrd: PathBuf,
@@ -0,0 +1,67 @@
+//! Versioning and migration of the on-disk VM work dir layout
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use fs_err as fs;
+use tracing::info;
+
+use super::VmWorkDir;
+
+/// The layout version of VM work dirs created by this build.
+pub const LAYOUT_VERSION: u32 = 1;
+
+/// Migrations from layout version `n` to `n + 1`, indexed by `n`.
+const MIGRATIONS: [fn(&VmWorkDir) -> Result<()>; LAYOUT_VERSION as usize] = [migrate_v0_to_v1];
+
+/// Version 0 is the unversioned layout, which is identical to version 1.
+fn migrate_v0_to_v1(_workdir: &VmWorkDir) -> Result<()> {
+    Ok(())
+}
+
+impl VmWorkDir {
+    pub fn layout_version_path(&self) -> PathBuf {
+        self.path().join("layout-version")
+    }
+
+    /// Read the layout version of the work dir. Work dirs without a version file are version 0.
+    pub fn layout_version(&self) -> Result<u32> {
+        let path = self.layout_version_path();
+        if !path.exists() {
+            return Ok(0);
+        }
+        fs::read_to_string(&path)
+            .context("Failed to read layout version")?
+            .trim()
+            .parse()
+            .context("Invalid layout version")
+    }
+
+    pub fn set_layout_version(&self, version: u32) -> Result<()> {
+        fs::write(self.layout_version_path(), version.to_string())
+            .context("Failed to write layout version")
+    }
+
+    /// Upgrade the work dir to the current layout version.
+    ///
+    /// Fails without touching anything if the work dir has a newer layout than this build understands.
+    pub fn migrate_layout(&self) -> Result<()> {
+        let version = self.layout_version()?;
+        if version > LAYOUT_VERSION {
+            bail!(
+                "unsupported layout version {version} of {}, the max supported version is {LAYOUT_VERSION}",
+                self.path().display()
+            );
+        }
+        for from in version..LAYOUT_VERSION {
+            let to = from + 1;
+            info!(
+                "migrating layout of {} from v{from} to v{to}",
+                self.path().display()
+            );
+            MIGRATIONS[from as usize](self)
+                .with_context(|| format!("Failed to migrate layout from v{from} to v{to}"))?;
+            self.set_layout_version(to)?;
+        }
+        Ok(())
+    }
+}
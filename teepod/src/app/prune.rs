@@ -0,0 +1,68 @@
+//! Removal of images that no VM uses.
+
+use std::collections::BTreeSet;
+
+use anyhow::{Context, Result};
+use fs_err as fs;
+use tracing::info;
+
+use super::{App, VmWorkDir};
+
+impl App {
+    /// Remove the images in the image dir that are neither used by a VM nor protected.
+    /// Returns the names of the removed images, or of the images that would be removed if
+    /// `dry_run` is set.
+    ///
+    /// VMs removed but not yet purged still count as users of their image. If the manifest of
+    /// any VM can not be read, nothing is removed.
+    pub fn prune_images(&self, dry_run: bool, keep: &[String]) -> Result<Vec<String>> {
+        // Hold the state lock so no VM gets loaded while the images are being removed.
+        let state = self.lock();
+        let mut in_use: BTreeSet<String> = state
+            .iter_vms()
+            .map(|vm| vm.config.manifest.image.clone())
+            .collect();
+        let vm_dir = self.vm_dir();
+        if vm_dir.exists() {
+            for entry in fs::read_dir(&vm_dir).context("Failed to read VM directory")? {
+                let path = entry.context("Failed to read directory entry")?.path();
+                if !path.is_dir() {
+                    continue;
+                }
+                let manifest = VmWorkDir::new(&path)
+                    .manifest()
+                    .with_context(|| format!("Failed to read manifest in {}", path.display()))?;
+                in_use.insert(manifest.image);
+            }
+        }
+        let protected: BTreeSet<&String> =
+            self.config.protected_images.iter().chain(keep).collect();
+
+        let mut removed = vec![];
+        let images =
+            fs::read_dir(&self.config.image_path).context("Failed to read image directory")?;
+        for entry in images {
+            let path = entry.context("Failed to read directory entry")?.path();
+            // Skip anything that is not a complete image, e.g. one still being copied in
+            if !path.join("metadata.json").exists() {
+                continue;
+            }
+            let Some(name) = path.file_name() else {
+                continue;
+            };
+            let name = name.to_string_lossy().to_string();
+            if in_use.contains(&name) || protected.contains(&name) {
+                continue;
+            }
+            if !dry_run {
+                info!("Pruning unused image {name}");
+                fs::remove_dir_all(&path)
+                    .with_context(|| format!("Failed to remove image {name}"))?;
+            }
+            removed.push(name);
+        }
+        drop(state);
+        removed.sort();
+        Ok(removed)
+    }
+}
@@ -1,13 +1,14 @@
 //! QEMU related code
 use crate::{
     app::Manifest,
-    config::{GatewayConfig, Networking},
+    config::{ClockConfig, GatewayConfig, Networking},
 };
 use std::{
+    collections::HashMap,
     ops::Deref,
     path::{Path, PathBuf},
     process::Command,
-    time::{Duration, SystemTime},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use super::{image::Image, VmState};
@@ -17,6 +18,7 @@ use fs_err as fs;
 use serde::{Deserialize, Serialize};
 use supervisor_client::supervisor::{ProcessConfig, ProcessInfo};
 use teepod_rpc as pb;
+use tracing::warn;
 
 #[derive(Debug, Deserialize)]
 pub struct InstanceInfo {
@@ -34,28 +36,136 @@ pub struct VmInfo {
     pub boot_error: String,
     pub shutdown_progress: String,
     pub image_version: String,
+    pub last_started_at_ms: Option<u64>,
+    pub uptime_secs: u64,
 }
 
-#[derive(Debug, Builder)]
+#[derive(Debug, Clone, Builder)]
 pub struct VmConfig {
     pub manifest: Manifest,
     pub image: Image,
     pub cid: u32,
     pub networking: Networking,
+    pub clock: ClockConfig,
     pub workdir: PathBuf,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct State {
     started: bool,
+    /// When the VM was last started, cleared when it is stopped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    started_at_ms: Option<u64>,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Seconds a VM started at `started_at_ms` has been up at `now_ms`, zero if it is not running.
+fn uptime_secs(started_at_ms: Option<u64>, is_running: bool, now_ms: u64) -> u64 {
+    match started_at_ms {
+        Some(started_at_ms) if is_running => now_ms.saturating_sub(started_at_ms) / 1000,
+        _ => 0,
+    }
+}
+
+/// Devices that may be added with extra QEMU args. They need no host resources besides QEMU.
+const ALLOWED_QEMU_DEVICES: &[&str] = &["virtio-rng-pci", "virtio-balloon-pci"];
+
+/// Properties the allowed devices may be given. Properties naming host files, backends or the
+/// driver are left out.
+const ALLOWED_QEMU_DEVICE_PROPS: &[&str] = &[
+    "id",
+    "bus",
+    "addr",
+    "max-bytes",
+    "period",
+    "deflate-on-oom",
+    "free-page-reporting",
+];
+
+/// Fail if saving the VM state on stop is requested.
+///
+/// The memory of a TD is encrypted with a key only the TDX module holds, and QEMU blocks savevm
+/// and migration for TDX guests, so there is nothing to save on stop or restore on start. VMs
+/// always cold boot; persistent state belongs on the encrypted disk.
+pub fn check_save_state_on_stop(requested: bool) -> Result<()> {
+    if requested {
+        bail!("save_state_on_stop is not supported, TDX guests can not be snapshotted");
+    }
+    Ok(())
+}
+
+/// Check extra QEMU args from a manifest, listing every offending arg.
+///
+/// Only `-cpu <model>` and `-device <driver>[,<prop>=<value>...]` with a driver and properties
+/// from the lists above are allowed. Other options could write host files, take over the
+/// networking, disks or CID teepod manages, or get around the resource limits.
+pub fn check_extra_qemu_args(args: &[String]) -> Result<()> {
+    let mut rejected = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let option = arg.strip_prefix("--").or_else(|| arg.strip_prefix('-'));
+        let Some(option @ ("cpu" | "device")) = option else {
+            rejected.push(arg.clone());
+            continue;
+        };
+        let Some(value) = args.next() else {
+            rejected.push(arg.clone());
+            continue;
+        };
+        let allowed = match option {
+            "cpu" => !value.starts_with('-'),
+            _ => is_allowed_device(value),
+        };
+        if !allowed {
+            rejected.push(format!("{arg} {value}"));
+        }
+    }
+    if !rejected.is_empty() {
+        bail!(
+            "extra qemu args not allowed by teepod: {}",
+            rejected.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn is_allowed_device(device: &str) -> bool {
+    // An escaped comma would hide a property from the split below
+    if device.contains(",,") {
+        return false;
+    }
+    let mut props = device.split(',');
+    let driver = props.next().unwrap_or_default();
+    ALLOWED_QEMU_DEVICES.contains(&driver)
+        && props.all(|prop| {
+            prop.split_once('=')
+                .is_some_and(|(name, _)| ALLOWED_QEMU_DEVICE_PROPS.contains(&name))
+        })
+}
+
+/// The qemu-img installed next to `qemu`, falling back to the one in PATH.
+pub fn qemu_img_path(qemu: &Path) -> PathBuf {
+    let sibling = qemu.with_file_name("qemu-img");
+    if sibling.is_file() {
+        sibling
+    } else {
+        PathBuf::from("qemu-img")
+    }
 }
 
 fn create_hd(
+    qemu_img: &Path,
     image_file: impl AsRef<Path>,
     backing_file: Option<impl AsRef<Path>>,
     size: &str,
 ) -> Result<()> {
-    let mut command = Command::new("qemu-img");
+    let mut command = Command::new(qemu_img);
     command.arg("create").arg("-f").arg("qcow2");
     if let Some(backing_file) = backing_file {
         command
@@ -75,6 +185,62 @@ fn create_hd(
     Ok(())
 }
 
+/// Virtual size of a disk image in bytes.
+fn disk_virtual_size(qemu_img: &Path, image_file: &Path) -> Result<u64> {
+    #[derive(Deserialize)]
+    struct DiskInfo {
+        #[serde(rename = "virtual-size")]
+        virtual_size: u64,
+    }
+    let output = Command::new(qemu_img)
+        .args(["info", "--output=json"])
+        .arg(image_file)
+        .output()
+        .context("Failed to run qemu-img")?;
+    if !output.status.success() {
+        bail!(
+            "Failed to read disk info: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let info: DiskInfo =
+        serde_json::from_slice(&output.stdout).context("Invalid qemu-img info output")?;
+    Ok(info.virtual_size)
+}
+
+/// Grow the disk image to `size_gb`. Resizing to the current size is a no-op, shrinking is
+/// refused.
+pub fn resize_hd(qemu_img: &Path, image_file: &Path, size_gb: u32) -> Result<()> {
+    const GB: u64 = 1024 * 1024 * 1024;
+    if !image_file.exists() {
+        bail!("Disk image {} not found", image_file.display());
+    }
+    let current_size = disk_virtual_size(qemu_img, image_file)?;
+    let new_size = size_gb as u64 * GB;
+    if new_size < current_size {
+        bail!(
+            "Cannot shrink disk below its current size of {}GB",
+            current_size.div_ceil(GB)
+        );
+    }
+    if new_size == current_size {
+        return Ok(());
+    }
+    let output = Command::new(qemu_img)
+        .arg("resize")
+        .arg(image_file)
+        .arg(format!("{size_gb}G"))
+        .output()
+        .context("Failed to run qemu-img")?;
+    if !output.status.success() {
+        bail!(
+            "Failed to resize disk: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
+
 impl VmInfo {
     pub fn to_pb(&self, gw: &GatewayConfig) -> pb::VmInfo {
         let workdir = VmWorkDir::new(&self.workdir);
@@ -83,6 +249,8 @@ impl VmInfo {
             name: self.manifest.name.clone(),
             status: self.status.into(),
             uptime: self.uptime.clone(),
+            uptime_secs: self.uptime_secs,
+            last_started_at_ms: self.last_started_at_ms,
             boot_progress: self.boot_progress.clone(),
             boot_error: self.boot_error.clone(),
             shutdown_progress: self.shutdown_progress.clone(),
@@ -108,6 +276,16 @@ impl VmInfo {
                     })
                     .collect(),
                 app_id: Some(self.manifest.app_id.clone()),
+                boot_priority: self.manifest.boot_priority,
+                compose_file_ref: None,
+                expected_rootfs_hash: self.manifest.expected_rootfs_hash.clone(),
+                env: workdir.plain_env().unwrap_or_default(),
+                rtc_base: self.manifest.rtc_base.map(|b| b.as_str().into()),
+                inject_host_time: self.manifest.inject_host_time,
+                instance_key: self.manifest.instance_key.clone(),
+                cid: self.manifest.cid,
+                extra_qemu_args: self.manifest.extra_qemu_args.clone(),
+                save_state_on_stop: None,
             }),
             app_url: self.instance_id.as_ref().map(|id| {
                 format!(
@@ -118,6 +296,8 @@ impl VmInfo {
             app_id: self.manifest.app_id.clone(),
             instance_id: self.instance_id.as_deref().map(Into::into),
             exited_at: self.exited_at.clone(),
+            cordoned: self.manifest.cordoned,
+            features: None,
         }
     }
 }
@@ -134,6 +314,7 @@ impl VmState {
         let todo = "more light way to get started";
         let started = workdir.started().unwrap_or(false);
         let status = match (started, is_running) {
+            (true, true) if self.state.ready => "ready",
             (true, true) => "running",
             (true, false) => "exited",
             (false, true) => "stopping",
@@ -152,6 +333,11 @@ impl VmState {
         let uptime = display_ts(proc_state.and_then(|info| info.state.started_at.as_ref()));
         let exited_at = display_ts(proc_state.and_then(|info| info.state.stopped_at.as_ref()));
         let instance_id = workdir.instance_info().ok().map(|info| info.instance_id);
+        // State files written before start times were recorded fall back to the process start
+        let last_started_at_ms = workdir.started_at_ms().ok().flatten().or_else(|| {
+            let started_at = proc_state?.state.started_at?;
+            Some(started_at.duration_since(UNIX_EPOCH).ok()?.as_millis() as u64)
+        });
         VmInfo {
             manifest: self.config.manifest.clone(),
             workdir: workdir.path().to_path_buf(),
@@ -163,10 +349,24 @@ impl VmState {
             boot_error: self.state.boot_error.clone(),
             shutdown_progress: self.state.shutdown_progress.clone(),
             image_version: self.config.image.info.version.clone(),
+            last_started_at_ms,
+            uptime_secs: uptime_secs(last_started_at_ms, is_running, now_ms()),
         }
     }
 }
 
+/// The longest path a unix socket can bind to, sun_path less the trailing NUL
+const MAX_UNIX_SOCKET_PATH: usize = 107;
+
+/// The `-qmp` argument for a socket path, none if the path is too long for a unix socket. A
+/// deep `run_path` must not stop VMs from launching, only from being powered down gracefully.
+fn qmp_arg(socket: &Path) -> Option<String> {
+    if socket.as_os_str().len() > MAX_UNIX_SOCKET_PATH {
+        return None;
+    }
+    Some(format!("unix:{},server,nowait", socket.display()))
+}
+
 impl VmConfig {
     pub fn config_qemu(&self, qemu: &Path, workdir: impl AsRef<Path>) -> Result<ProcessConfig> {
         let workdir = VmWorkDir::new(workdir);
@@ -176,7 +376,12 @@ impl VmConfig {
         let disk_size = format!("{}G", self.manifest.disk_size);
         let hda_path = workdir.hda_path();
         if !hda_path.exists() {
-            create_hd(&hda_path, self.image.hda.as_ref(), &disk_size)?;
+            create_hd(
+                &qemu_img_path(qemu),
+                &hda_path,
+                self.image.hda.as_ref(),
+                &disk_size,
+            )?;
         }
         if !shared_dir.exists() {
             fs::create_dir_all(&shared_dir)?;
@@ -188,6 +393,24 @@ impl VmConfig {
         command.arg("-m").arg(format!("{}M", self.manifest.memory));
         command.arg("-nographic");
         command.arg("-nodefaults");
+        let rtc_base = self.manifest.rtc_base.unwrap_or(self.clock.rtc_base);
+        command
+            .arg("-rtc")
+            .arg(format!("base={},clock=host", rtc_base.as_str()));
+        let host_time_path = workdir.host_time_path();
+        if self
+            .manifest
+            .inject_host_time
+            .unwrap_or(self.clock.inject_host_time)
+        {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .context("System time is before the epoch")?;
+            fs::write(&host_time_path, now.as_secs().to_string())
+                .context("Failed to write host time")?;
+        } else if host_time_path.exists() {
+            fs::remove_file(&host_time_path).context("Failed to remove stale host time")?;
+        }
         command.arg("-chardev").arg(format!(
             "pty,id=com0,path={},logfile={}",
             serial_pty.display(),
@@ -215,6 +438,12 @@ impl VmConfig {
                     netcfg.dhcp_start,
                     if netcfg.restrict { "yes" } else { "no" }
                 );
+                if let Some(ipv6_net) = &netcfg.ipv6_net {
+                    netdev.push_str(&format!(",ipv6=on,ipv6-net={ipv6_net}"));
+                    if let Some(ipv6_host) = &netcfg.ipv6_host {
+                        netdev.push_str(&format!(",ipv6-host={ipv6_host}"));
+                    }
+                }
                 for pm in &self.manifest.port_map {
                     netdev.push_str(&format!(
                         ",hostfwd={}:{}:{}-:{}",
@@ -251,6 +480,19 @@ impl VmConfig {
         if let Some(cmdline) = &self.image.info.cmdline {
             command.arg("-append").arg(cmdline);
         }
+        match qmp_arg(&workdir.qmp_socket()) {
+            Some(qmp) => {
+                command.arg("-qmp").arg(qmp);
+            }
+            None => warn!(
+                "QMP socket path {} is longer than {MAX_UNIX_SOCKET_PATH} bytes, \
+                 VM {} will be killed instead of powered down on stop",
+                workdir.qmp_socket().display(),
+                self.manifest.id
+            ),
+        }
+        check_extra_qemu_args(&self.manifest.extra_qemu_args)?;
+        command.args(&self.manifest.extra_qemu_args);
 
         let args = command
             .get_args()
@@ -326,23 +568,61 @@ impl VmWorkDir {
             .context("Failed to write manifest")
     }
 
-    pub fn started(&self) -> Result<bool> {
+    fn state(&self) -> Result<Option<State>> {
         let state_path = self.state_path();
         if !state_path.exists() {
-            return Ok(false);
+            return Ok(None);
         }
         let state: State =
             serde_json::from_str(&fs::read_to_string(state_path).context("Failed to read state")?)
                 .context("Failed to parse state")?;
-        Ok(state.started)
+        Ok(Some(state))
     }
 
+    pub fn started(&self) -> Result<bool> {
+        Ok(self.state()?.map_or(false, |state| state.started))
+    }
+
+    /// When the VM was last started, None if it is stopped.
+    pub fn started_at_ms(&self) -> Result<Option<u64>> {
+        Ok(self.state()?.and_then(|state| state.started_at_ms))
+    }
+
+    /// Mark the VM as started now, or as stopped.
     pub fn set_started(&self, started: bool) -> Result<()> {
-        let state_path = self.state_path();
-        fs::write(state_path, serde_json::to_string(&State { started })?)
+        let state = State {
+            started,
+            started_at_ms: started.then(now_ms),
+        };
+        fs::write(self.state_path(), serde_json::to_string(&state)?)
             .context("Failed to write state")
     }
 
+    pub fn removed_marker_path(&self) -> PathBuf {
+        self.workdir.join("removed")
+    }
+
+    /// When the VM was removed, if its work dir is retained for the removal grace period.
+    pub fn removed_at(&self) -> Result<Option<SystemTime>> {
+        let marker = self.removed_marker_path();
+        if !marker.exists() {
+            return Ok(None);
+        }
+        let ms: u64 = fs::read_to_string(marker)?
+            .trim()
+            .parse()
+            .context("Failed to parse removal time")?;
+        Ok(Some(UNIX_EPOCH + Duration::from_millis(ms)))
+    }
+
+    pub fn mark_removed(&self) -> Result<()> {
+        let ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        fs::write(self.removed_marker_path(), ms.to_string()).context("Failed to mark removed")
+    }
+
     pub fn shared_dir(&self) -> PathBuf {
         self.workdir.join("shared")
     }
@@ -355,6 +635,33 @@ impl VmWorkDir {
         self.shared_dir().join("encrypted-env")
     }
 
+    pub fn host_time_path(&self) -> PathBuf {
+        self.shared_dir().join("host-time")
+    }
+
+    pub fn plain_env_path(&self) -> PathBuf {
+        self.shared_dir().join("plain-env")
+    }
+
+    /// Read the non-secret environment variables passed to the CVM.
+    pub fn plain_env(&self) -> Result<HashMap<String, String>> {
+        #[derive(Deserialize)]
+        struct Pair {
+            key: String,
+            value: String,
+        }
+        #[derive(Deserialize)]
+        struct Data {
+            env: Vec<Pair>,
+        }
+        let path = self.plain_env_path();
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+        let data: Data = serde_json::from_slice(&fs::read(path)?)?;
+        Ok(data.env.into_iter().map(|p| (p.key, p.value)).collect())
+    }
+
     pub fn serial_file(&self) -> PathBuf {
         self.workdir.join("serial.log")
     }
@@ -371,10 +678,18 @@ impl VmWorkDir {
         self.workdir.join("stderr.log")
     }
 
+    pub fn prev_stderr_file(&self) -> PathBuf {
+        self.workdir.join("stderr.prev.log")
+    }
+
     pub fn pid_file(&self) -> PathBuf {
         self.workdir.join("qemu.pid")
     }
 
+    pub fn qmp_socket(&self) -> PathBuf {
+        self.workdir.join("qmp.sock")
+    }
+
     pub fn hda_path(&self) -> PathBuf {
         self.workdir.join("hda.img")
     }
@@ -389,9 +704,141 @@ impl VmWorkDir {
 }
 
 impl VmWorkDir {
+    /// Move the stderr log aside so that it only holds the output of the next launch.
+    pub fn rotate_stderr(&self) -> Result<()> {
+        let stderr_file = self.stderr_file();
+        if stderr_file.exists() {
+            fs::rename(&stderr_file, self.prev_stderr_file())?;
+        }
+        Ok(())
+    }
+
     pub fn instance_info(&self) -> Result<InstanceInfo> {
         let info_file = self.instance_info_path();
         let info: InstanceInfo = serde_json::from_slice(&fs::read(&info_file)?)?;
         Ok(info)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uptime() {
+        let tmp = tempfile::tempdir().unwrap();
+        let work_dir = VmWorkDir::new(tmp.path());
+        assert_eq!(work_dir.started_at_ms().unwrap(), None);
+
+        work_dir.set_started(true).unwrap();
+        let started_at = work_dir.started_at_ms().unwrap().unwrap();
+        assert!(started_at.abs_diff(now_ms()) < 60_000);
+        // Advance the clock by 90.5s
+        assert_eq!(uptime_secs(Some(started_at), true, started_at + 90_500), 90);
+        assert_eq!(uptime_secs(Some(started_at), false, started_at + 90_500), 0);
+        // A clock going backwards does not underflow
+        assert_eq!(uptime_secs(Some(started_at), true, started_at - 1000), 0);
+
+        work_dir.set_started(false).unwrap();
+        assert!(!work_dir.started().unwrap());
+        assert_eq!(work_dir.started_at_ms().unwrap(), None);
+        assert_eq!(uptime_secs(None, true, started_at), 0);
+    }
+
+    #[test]
+    fn test_qmp_arg() {
+        let socket = Path::new("/var/run/teepod/vm/0123/qmp.sock");
+        assert_eq!(
+            qmp_arg(socket).unwrap(),
+            "unix:/var/run/teepod/vm/0123/qmp.sock,server,nowait"
+        );
+        let socket = PathBuf::from(format!("/{}/qmp.sock", "a".repeat(100)));
+        assert_eq!(qmp_arg(&socket), None);
+    }
+
+    #[test]
+    fn test_check_save_state_on_stop() {
+        check_save_state_on_stop(false).unwrap();
+        let err = check_save_state_on_stop(true).unwrap_err();
+        assert!(err.to_string().contains("not supported"), "{err}");
+    }
+
+    #[test]
+    fn test_check_extra_qemu_args() {
+        let args = |args: &[&str]| args.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        check_extra_qemu_args(&args(&[
+            "-cpu",
+            "host,+avx512f",
+            "-device",
+            "virtio-rng-pci",
+            "--device",
+            "virtio-balloon-pci,id=balloon0,deflate-on-oom=on",
+        ]))
+        .unwrap();
+
+        let err = check_extra_qemu_args(&args(&[
+            "-cpu",
+            "host",
+            "-netdev",
+            "user,id=net1",
+            "-device",
+            "vhost-vsock-pci,guest-cid=3",
+            "-device",
+        ]))
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "extra qemu args not allowed by teepod: -netdev, user,id=net1, \
+             -device vhost-vsock-pci,guest-cid=3, -device"
+        );
+
+        let bypasses: &[&[&str]] = &[
+            // Writing host files
+            &["-D", "/etc/cron.d/x"],
+            &["-pidfile", "/etc/passwd"],
+            &["-trace", "file=/etc/passwd"],
+            &["-device", "virtio-rng-pci,romfile=/etc/shadow"],
+            // Taking over the CID, networking or disks
+            &["-global", "vhost-vsock-pci.guest-cid=3"],
+            &["-device", r#"{"driver":"vhost-vsock-pci","guest-cid":3}"#],
+            &["-device", "driver=virtio-net-pci,netdev=net0"],
+            &["-device", "virtio-rng-pci,id=x,,netdev=net0"],
+            &["-device", "virtio-rng-pci,chardev=c0"],
+            &["-device", "virtio-rng-pci,drive=hd1"],
+            &["-pflash", "/dev/sda"],
+            // Getting around the resource limits
+            &["-m", "64G"],
+            &["-smp", "64"],
+            // Weakening the host
+            &["-sandbox", "off"],
+            &["-runas", "root"],
+            &["-chroot", "/"],
+            &["-incoming", "tcp:0:4444"],
+            &["-add-fd", "fd=3,set=1"],
+            &["-cpu", "-m"],
+        ];
+        for bypass in bypasses {
+            assert!(
+                check_extra_qemu_args(&args(bypass)).is_err(),
+                "{bypass:?} is allowed"
+            );
+        }
+    }
+
+    #[test]
+    fn test_resize_hd() {
+        let Ok(qemu_img) = which::which("qemu-img") else {
+            eprintln!("SKIPPED test_resize_hd: qemu-img is not in PATH");
+            return;
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let image_file = dir.path().join("hda.img");
+        create_hd(&qemu_img, &image_file, None::<&Path>, "1G").unwrap();
+
+        resize_hd(&qemu_img, &image_file, 2).unwrap();
+        assert_eq!(disk_virtual_size(&qemu_img, &image_file).unwrap(), 2 << 30);
+        resize_hd(&qemu_img, &image_file, 2).unwrap();
+        assert!(resize_hd(&qemu_img, &image_file, 1).is_err());
+        assert!(resize_hd(&qemu_img, &dir.path().join("missing.img"), 3).is_err());
+    }
+}
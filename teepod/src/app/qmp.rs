@@ -0,0 +1,98 @@
+//! A minimal client of the QEMU machine protocol, for asking a guest to power down.
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+/// How long to wait for QEMU to answer on the QMP socket
+const QMP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Send an ACPI power button press to the VM listening on the QMP socket at `path`.
+pub async fn system_powerdown(path: &Path) -> Result<()> {
+    tokio::time::timeout(QMP_TIMEOUT, async {
+        let stream = UnixStream::connect(path)
+            .await
+            .context("Failed to connect to the QMP socket")?;
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let greeting = next_reply(&mut lines).await?;
+        if greeting.get("QMP").is_none() {
+            bail!("unexpected QMP greeting: {greeting}");
+        }
+        for command in ["qmp_capabilities", "system_powerdown"] {
+            let request = json!({ "execute": command }).to_string() + "\n";
+            writer
+                .write_all(request.as_bytes())
+                .await
+                .context("Failed to send QMP command")?;
+            let reply = next_reply(&mut lines).await?;
+            if let Some(error) = reply.get("error") {
+                bail!("QMP command {command} failed: {error}");
+            }
+        }
+        Ok(())
+    })
+    .await
+    .context("Timed out talking to QMP")?
+}
+
+/// Read the next message that is not an asynchronous event.
+async fn next_reply<R: tokio::io::AsyncBufRead + Unpin>(
+    lines: &mut tokio::io::Lines<R>,
+) -> Result<Value> {
+    loop {
+        let line = lines
+            .next_line()
+            .await
+            .context("Failed to read from the QMP socket")?
+            .context("QMP socket closed")?;
+        let message: Value = serde_json::from_str(&line).context("Invalid QMP message")?;
+        if message.get("event").is_none() {
+            return Ok(message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UnixListener;
+
+    #[tokio::test]
+    async fn test_system_powerdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("qmp.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            writer
+                .write_all(b"{\"QMP\": {\"version\": {}, \"capabilities\": []}}\n")
+                .await
+                .unwrap();
+            let mut commands = vec![];
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let request: Value = serde_json::from_str(&line).unwrap();
+                commands.push(request["execute"].as_str().unwrap().to_string());
+                writer
+                    .write_all(b"{\"event\": \"POWERDOWN\"}\n{\"return\": {}}\n")
+                    .await
+                    .unwrap();
+            }
+            commands
+        });
+        system_powerdown(&path).await.unwrap();
+        assert_eq!(
+            server.await.unwrap(),
+            ["qmp_capabilities", "system_powerdown"]
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(system_powerdown(&path).await.is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use anyhow::{Context, Result};
+use fs_err as fs;
+
+use super::{App, Image, VmWorkDir};
+
+/// What reconciliation at startup does with a VM.
+#[derive(Debug, Clone)]
+pub enum ReconcileAction {
+    /// The VM was running before and will be started
+    Start,
+    /// The VM is still running in the supervisor and will be adopted as is
+    AdoptRunning,
+    /// The VM was running before, but is cordoned and will be left stopped
+    SkipCordoned,
+    /// The VM was stopped before and will be left stopped
+    SkipStopped,
+    /// The VM was removed and its work dir is waiting to be purged
+    SkipRemoved,
+    /// The VM can not be loaded and will be left alone
+    LoadFailure(String),
+    /// The supervisor runs a process without a VM work dir, it will be left alone
+    Orphaned,
+}
+
+impl fmt::Display for ReconcileAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Start => write!(f, "start"),
+            Self::AdoptRunning => write!(f, "adopt running"),
+            Self::SkipCordoned => write!(f, "skip (cordoned)"),
+            Self::SkipStopped => write!(f, "skip (stopped)"),
+            Self::SkipRemoved => write!(f, "skip (removed)"),
+            Self::LoadFailure(err) => write!(f, "skip (failed to load: {err})"),
+            Self::Orphaned => write!(f, "skip (orphaned process)"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReconcileStep {
+    pub id: String,
+    pub name: String,
+    pub boot_priority: u32,
+    pub action: ReconcileAction,
+}
+
+impl App {
+    /// Compute what `reload_vms` would do, without changing anything.
+    ///
+    /// VMs to start are listed first, in the order they would be started.
+    pub async fn reconcile_plan(&self) -> Result<Vec<ReconcileStep>> {
+        let processes = self.supervisor.list().await.context("Failed to list VMs")?;
+        let running = processes
+            .iter()
+            .map(|p| (p.config.id.clone(), p.state.status.is_running()))
+            .collect::<HashMap<_, _>>();
+        let mut seen = HashSet::new();
+        let mut steps = vec![];
+        let mut created_at = HashMap::new();
+        let vm_path = self.vm_dir();
+        if vm_path.exists() {
+            for entry in fs::read_dir(vm_path).context("Failed to read VM directory")? {
+                let entry = entry.context("Failed to read directory entry")?;
+                if !entry.path().is_dir() {
+                    continue;
+                }
+                let work_dir = VmWorkDir::new(entry.path());
+                let dir_name = entry.file_name().to_string_lossy().to_string();
+                let step = |name: &str, boot_priority, action| ReconcileStep {
+                    id: dir_name.clone(),
+                    name: name.to_string(),
+                    boot_priority,
+                    action,
+                };
+                seen.insert(dir_name.clone());
+                if work_dir.removed_marker_path().exists() {
+                    steps.push(step("", 0, ReconcileAction::SkipRemoved));
+                    continue;
+                }
+                let manifest = match work_dir.manifest() {
+                    Ok(manifest) => manifest,
+                    Err(err) => {
+                        steps.push(step(
+                            "",
+                            0,
+                            ReconcileAction::LoadFailure(format!("{err:#}")),
+                        ));
+                        continue;
+                    }
+                };
+                let step = |action| ReconcileStep {
+                    id: manifest.id.clone(),
+                    name: manifest.name.clone(),
+                    boot_priority: manifest.boot_priority,
+                    action,
+                };
+                seen.insert(manifest.id.clone());
+                created_at.insert(manifest.id.clone(), manifest.created_at_ms);
+                let image_path = self.config.image_path.join(&manifest.image);
+                if let Err(err) = Image::load(&image_path) {
+                    steps.push(step(ReconcileAction::LoadFailure(format!(
+                        "failed to load image: {err:#}"
+                    ))));
+                    continue;
+                }
+                let started = match work_dir.started() {
+                    Ok(started) => started,
+                    Err(err) => {
+                        steps.push(step(ReconcileAction::LoadFailure(format!("{err:#}"))));
+                        continue;
+                    }
+                };
+                let action = if running.get(&manifest.id).copied().unwrap_or(false) {
+                    ReconcileAction::AdoptRunning
+                } else if !started {
+                    ReconcileAction::SkipStopped
+                } else if manifest.cordoned {
+                    ReconcileAction::SkipCordoned
+                } else {
+                    ReconcileAction::Start
+                };
+                steps.push(step(action));
+            }
+        }
+        for process in &processes {
+            if !seen.contains(&process.config.id) {
+                steps.push(ReconcileStep {
+                    id: process.config.id.clone(),
+                    name: process.config.name.clone(),
+                    boot_priority: 0,
+                    action: ReconcileAction::Orphaned,
+                });
+            }
+        }
+        steps.sort_by_key(|step| {
+            (
+                !matches!(step.action, ReconcileAction::Start),
+                Reverse(step.boot_priority),
+                created_at.get(&step.id).copied().unwrap_or_default(),
+            )
+        });
+        Ok(steps)
+    }
+}
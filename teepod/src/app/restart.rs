@@ -0,0 +1,181 @@
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use tracing::warn;
+
+use super::App;
+
+/// The VM operations a restart is made of, so the sequencing can be tested without a supervisor.
+pub(crate) trait VmControl {
+    /// Stop the VM, gracefully if possible.
+    async fn stop(&self, id: &str) -> Result<()>;
+
+    /// Wait up to `timeout` for the VM process to exit. Returns whether it did and the last
+    /// status seen.
+    async fn wait_stopped(&self, id: &str, timeout: Duration) -> Result<(bool, String)>;
+
+    /// Start the VM.
+    async fn start(&self, id: &str) -> Result<()>;
+
+    /// Record that the VM is not meant to be running.
+    fn mark_stopped(&self, id: &str) -> Result<()>;
+}
+
+impl VmControl for App {
+    async fn stop(&self, id: &str) -> Result<()> {
+        self.stop_vm_gracefully(id, self.config.timeouts.graceful_stop)
+            .await?;
+        Ok(())
+    }
+
+    async fn wait_stopped(&self, id: &str, timeout: Duration) -> Result<(bool, String)> {
+        let (stopped, info) = self
+            .wait_vm(id, timeout, |info| {
+                matches!(info.status.as_str(), "stopped" | "exited")
+            })
+            .await?;
+        Ok((stopped, info.status))
+    }
+
+    async fn start(&self, id: &str) -> Result<()> {
+        self.start_vm(id).await
+    }
+
+    fn mark_stopped(&self, id: &str) -> Result<()> {
+        self.work_dir(id).set_started(false)
+    }
+}
+
+/// Stop a VM, wait up to `timeout` for its process to exit and start it again.
+///
+/// If the start fails, the VM is left stopped rather than marked as started.
+pub(crate) async fn restart(vm: &impl VmControl, id: &str, timeout: Duration) -> Result<()> {
+    vm.stop(id).await?;
+    let (stopped, status) = vm.wait_stopped(id, timeout).await?;
+    if !stopped {
+        bail!(
+            "VM {id} did not stop within {}s, last status: {status}",
+            timeout.as_secs()
+        );
+    }
+    if let Err(err) = vm.start(id).await {
+        if let Err(reset_err) = vm.mark_stopped(id) {
+            warn!("Failed to reset started for VM {id}: {reset_err:?}");
+        }
+        return Err(err.context(format!("VM {id} is stopped, failed to start it again")));
+    }
+    Ok(())
+}
+
+impl App {
+    /// Stop a VM, wait up to `timeout` for its process to exit and start it again.
+    ///
+    /// If the start fails, the VM is left stopped rather than marked as started.
+    pub async fn restart_vm(&self, id: &str, timeout: Duration) -> Result<()> {
+        restart(self, id, timeout).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MockVm {
+        calls: Mutex<Vec<&'static str>>,
+        started: Mutex<bool>,
+        stops_in_time: bool,
+        start_fails: bool,
+    }
+
+    impl MockVm {
+        fn new(stops_in_time: bool, start_fails: bool) -> Self {
+            Self {
+                started: Mutex::new(true),
+                stops_in_time,
+                start_fails,
+                ..Default::default()
+            }
+        }
+
+        fn calls(&self) -> Vec<&'static str> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        fn started(&self) -> bool {
+            *self.started.lock().unwrap()
+        }
+    }
+
+    impl VmControl for MockVm {
+        async fn stop(&self, _id: &str) -> Result<()> {
+            self.calls.lock().unwrap().push("stop");
+            *self.started.lock().unwrap() = false;
+            Ok(())
+        }
+
+        async fn wait_stopped(&self, _id: &str, _timeout: Duration) -> Result<(bool, String)> {
+            self.calls.lock().unwrap().push("wait");
+            let status = if self.stops_in_time {
+                "stopped"
+            } else {
+                "running"
+            };
+            Ok((self.stops_in_time, status.into()))
+        }
+
+        async fn start(&self, _id: &str) -> Result<()> {
+            self.calls.lock().unwrap().push("start");
+            // Like start_vm, the VM is marked as started before it is deployed
+            *self.started.lock().unwrap() = true;
+            if self.start_fails {
+                return Err(anyhow!("qemu failed"));
+            }
+            Ok(())
+        }
+
+        fn mark_stopped(&self, _id: &str) -> Result<()> {
+            self.calls.lock().unwrap().push("mark_stopped");
+            *self.started.lock().unwrap() = false;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restart_stops_then_starts() {
+        let vm = MockVm::new(true, false);
+        restart(&vm, "vm", Duration::from_secs(10)).await.unwrap();
+        assert_eq!(vm.calls(), ["stop", "wait", "start"]);
+        assert!(vm.started());
+    }
+
+    #[tokio::test]
+    async fn test_restart_failed_start_leaves_vm_stopped() {
+        let vm = MockVm::new(true, true);
+        let err = restart(&vm, "vm", Duration::from_secs(10))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "VM vm is stopped, failed to start it again"
+        );
+        assert_eq!(err.root_cause().to_string(), "qemu failed");
+        assert_eq!(vm.calls(), ["stop", "wait", "start", "mark_stopped"]);
+        assert!(!vm.started());
+    }
+
+    #[tokio::test]
+    async fn test_restart_stop_timeout() {
+        let vm = MockVm::new(false, false);
+        let err = restart(&vm, "vm", Duration::from_secs(10))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "VM vm did not stop within 10s, last status: running"
+        );
+        assert_eq!(vm.calls(), ["stop", "wait"]);
+    }
+}
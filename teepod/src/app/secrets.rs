@@ -0,0 +1,107 @@
+//! The tmp CA cert and key handed to CVMs, from files or an external secrets manager.
+
+use std::{io::Write, path::Path, process::Command};
+
+use anyhow::{bail, Context, Result};
+use fs_err as fs;
+use tracing::{info, warn};
+
+use super::App;
+use crate::config::{CvmConfig, SecretsConfig};
+
+/// The tmp CA fetched from a secrets manager, kept in memory for the lifetime of teepod.
+pub(crate) struct TmpCa {
+    cert: Vec<u8>,
+    key: Vec<u8>,
+}
+
+impl TmpCa {
+    /// Fetch the tmp CA if a secrets manager is configured, None for file based config.
+    pub(crate) fn load(cfg: &CvmConfig) -> Result<Option<Self>> {
+        match &cfg.secrets {
+            SecretsConfig::File => Ok(None),
+            SecretsConfig::Command {
+                tmp_ca_cert,
+                tmp_ca_key,
+            } => {
+                info!("Fetching tmp CA from the secrets manager");
+                Ok(Some(Self {
+                    cert: run_secret_command(tmp_ca_cert).context("Failed to fetch tmp CA cert")?,
+                    key: run_secret_command(tmp_ca_key).context("Failed to fetch tmp CA key")?,
+                }))
+            }
+        }
+    }
+}
+
+impl Drop for TmpCa {
+    fn drop(&mut self) {
+        self.key.fill(0);
+    }
+}
+
+fn run_secret_command(command: &str) -> Result<Vec<u8>> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .context("Failed to run command")?;
+    if !output.status.success() {
+        bail!(
+            "command exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    if output.stdout.is_empty() {
+        bail!("command printed nothing");
+    }
+    Ok(output.stdout)
+}
+
+/// Overwrite a file with zeros before removing it. On copy-on-write or journaling filesystems
+/// the old blocks may survive, so this is best effort.
+fn shred(path: &Path) -> Result<()> {
+    let len = fs::metadata(path)?.len();
+    let mut file = fs::OpenOptions::new().write(true).open(path)?;
+    file.write_all(&vec![0u8; len as usize])?;
+    file.sync_all()?;
+    drop(file);
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+impl App {
+    /// Put the tmp CA into the certs dir of a VM that is about to start.
+    pub(crate) fn materialize_tmp_ca(&self, certs_dir: &Path) -> Result<()> {
+        let tmp_ca_cert = certs_dir.join("tmp-ca.cert");
+        let tmp_ca_key = certs_dir.join("tmp-ca.key");
+        match &self.tmp_ca {
+            None => {
+                let cfg = &self.config.cvm;
+                fs::copy(&cfg.tmp_ca_cert, tmp_ca_cert).context("Failed to copy tmp ca cert")?;
+                fs::copy(&cfg.tmp_ca_key, tmp_ca_key).context("Failed to copy tmp ca key")?;
+            }
+            Some(tmp_ca) => {
+                fs::write(tmp_ca_cert, &tmp_ca.cert).context("Failed to write tmp ca cert")?;
+                fs::write(tmp_ca_key, &tmp_ca.key).context("Failed to write tmp ca key")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the tmp CA key of a VM once the guest has copied its shared dir, if the key comes
+    /// from a secrets manager. File based keys are left alone since they are on disk anyway.
+    pub(crate) fn shred_tmp_ca_key(&self, id: &str) {
+        if self.tmp_ca.is_none() {
+            return;
+        }
+        let path = self.shared_dir(id).join("certs").join("tmp-ca.key");
+        if !path.exists() {
+            return;
+        }
+        if let Err(err) = shred(&path) {
+            warn!("Failed to shred tmp CA key of VM {id}: {err:?}");
+        }
+    }
+}
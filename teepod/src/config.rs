@@ -1,12 +1,18 @@
-use std::{net::IpAddr, path::PathBuf, str::FromStr};
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
 
 use anyhow::{bail, Context, Result};
+use log_config::LoggingConfig;
 use path_absolutize::Absolutize;
 use rocket::figment::{
     providers::{Format, Toml},
     Figment,
 };
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 pub const CONFIG_FILENAME: &str = "teepod.toml";
 pub const SYSTEM_CONFIG_FILENAME: &str = "/etc/teepod/teepod.toml";
@@ -23,7 +29,7 @@ pub fn load_config_figment(config_file: Option<&str>) -> Figment {
         .merge(leaf_config)
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Protocol {
     Tcp,
@@ -51,7 +57,7 @@ impl Protocol {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct PortRange {
     pub protocol: Protocol,
     pub from: u16,
@@ -62,6 +68,38 @@ impl PortRange {
     pub fn contains(&self, protocol: &str, port: u16) -> bool {
         self.protocol.as_str() == protocol && port >= self.from && port <= self.to
     }
+
+    pub fn validate(&self) -> Result<()> {
+        let Self { protocol, from, to } = self;
+        let protocol = protocol.as_str();
+        if *from == 0 {
+            bail!("{protocol} port range {from}-{to}: ports start at 1");
+        }
+        if from > to {
+            bail!("{protocol} port range {from}-{to}: from is greater than to");
+        }
+        Ok(())
+    }
+}
+
+/// Merge the overlapping or adjacent ranges of the same protocol, returning the ranges sorted by
+/// protocol and start port. The ranges must have been validated.
+pub fn merge_port_ranges(ranges: &[PortRange]) -> Vec<PortRange> {
+    let mut sorted = ranges.to_vec();
+    sorted.sort_by_key(|r| (r.protocol.as_str().to_string(), r.from));
+    let mut merged: Vec<PortRange> = Vec::with_capacity(sorted.len());
+    for range in sorted {
+        match merged.last_mut() {
+            Some(last)
+                if last.protocol == range.protocol
+                    && u32::from(range.from) <= u32::from(last.to) + 1 =>
+            {
+                last.to = last.to.max(range.to);
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -78,6 +116,15 @@ impl PortMappingConfig {
         }
         self.range.iter().any(|r| r.contains(protocol, port))
     }
+
+    /// Validate the ranges and merge the overlapping ones.
+    pub fn normalize(&mut self) -> Result<()> {
+        for range in &self.range {
+            range.validate()?;
+        }
+        self.range = merge_port_ranges(&self.range);
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -93,12 +140,115 @@ pub struct CvmConfig {
     pub docker_registry: String,
     /// The maximum disk size in GB
     pub max_disk_size: u32,
+    /// The maximum number of vCPUs of a VM
+    pub max_allocable_vcpu: u32,
+    /// The maximum memory of a VM in MB
+    pub max_allocable_memory_in_mb: u32,
+    /// The maximum size of an app compose file in bytes
+    pub max_compose_file_size: usize,
+    /// The maximum size of an encrypted env in bytes
+    pub max_encrypted_env_size: usize,
     /// The start of the CID pool that allocates CIDs to VMs
     pub cid_start: u32,
     /// The size of the CID pool that allocates CIDs to VMs
     pub cid_pool_size: u32,
     /// Port mapping configuration
     pub port_mapping: PortMappingConfig,
+    /// Reject creating a VM whose name is already taken by another VM. Default: false
+    #[serde(default)]
+    pub unique_names: bool,
+    /// Instead of rejecting, append a numeric suffix to a name that is already taken.
+    /// Only effective if `unique_names` is enabled. Default: false
+    #[serde(default)]
+    pub auto_suffix_names: bool,
+    /// How long the work dir of a removed VM is kept before it is purged. Default: 0, purge
+    /// immediately
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub removal_grace_period: Duration,
+    /// What to do when an app compose enables KMS or TProxy but the corresponding URL is not
+    /// configured. Default: error
+    #[serde(default)]
+    pub feature_check: FeatureCheck,
+    /// Default guest clock settings, can be overridden per VM
+    #[serde(default)]
+    pub clock: ClockConfig,
+    /// Where the tmp CA given to CVMs comes from. Default: the `tmp_ca_cert` and `tmp_ca_key`
+    /// files
+    #[serde(default)]
+    pub secrets: SecretsConfig,
+}
+
+/// Source of the tmp CA cert and key.
+///
+/// With `file`, the key sits on the host disk and is copied into the shared dir of every VM
+/// on each start, where it stays. With `command`, teepod fetches the cert and key once at
+/// startup, e.g. from Vault or a cloud secrets manager, and keeps them in memory only. The key
+/// is written to the shared dir of a VM when it starts and shredded once the guest reports boot
+/// progress, by which time it has copied the shared dir. A compromised host can still read the
+/// key from teepod's memory or from a VM's shared dir during boot, and shredding is best effort
+/// on copy-on-write filesystems, but the key is no longer left at rest on disk.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum SecretsConfig {
+    #[default]
+    File,
+    Command {
+        /// Shell command printing the tmp CA cert in PEM
+        tmp_ca_cert: String,
+        /// Shell command printing the tmp CA key in PEM
+        tmp_ca_key: String,
+    },
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ClockConfig {
+    /// What the RTC of the guest starts from. Default: utc
+    #[serde(default)]
+    pub rtc_base: RtcBase,
+    /// Write the host time to the shared dir on each launch so the guest can step its clock
+    /// forward before anything checks certificate validity. The guest ignores it unless the app
+    /// compose sets `allow_host_time`. Default: false
+    #[serde(default)]
+    pub inject_host_time: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RtcBase {
+    #[default]
+    Utc,
+    Localtime,
+}
+
+impl RtcBase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Utc => "utc",
+            Self::Localtime => "localtime",
+        }
+    }
+}
+
+impl std::str::FromStr for RtcBase {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "utc" => Ok(Self::Utc),
+            "localtime" => Ok(Self::Localtime),
+            _ => bail!("invalid rtc base: {s}, expected utc or localtime"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FeatureCheck {
+    /// Reject creating the VM
+    #[default]
+    Error,
+    /// Log a warning and create the VM anyway
+    Warn,
 }
 
 #[derive(Debug, Clone, Default, Deserialize)]
@@ -106,7 +256,89 @@ pub struct AuthConfig {
     /// Whether to enable API token authentication
     pub enabled: bool,
     /// The API tokens
-    pub tokens: Vec<String>,
+    pub tokens: Vec<ApiTokenEntry>,
+}
+
+impl AuthConfig {
+    /// Whether a request carrying `token` may use the API at unix time `now`.
+    pub fn authorizes(&self, token: Option<&str>, now: u64) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        token
+            .and_then(|token| self.find(token))
+            .is_some_and(|entry| !entry.expired(now))
+    }
+
+    /// The images a token may launch VMs from, or None if it is not restricted.
+    pub fn allowed_images(&self, token: &str) -> Option<&[String]> {
+        self.find(token).and_then(|entry| entry.allowed_images())
+    }
+
+    /// Whether a token may manage teepod itself: reload auth, prune images and remove work dirs.
+    pub fn is_admin(&self, token: &str) -> bool {
+        self.find(token).is_some_and(|entry| entry.is_admin())
+    }
+
+    /// The entry of a token, compared in constant time so the response time does not tell how
+    /// much of a guess matched.
+    fn find(&self, token: &str) -> Option<&ApiTokenEntry> {
+        use subtle::ConstantTimeEq;
+        self.tokens
+            .iter()
+            .find(|entry| bool::from(entry.token().as_bytes().ct_eq(token.as_bytes())))
+    }
+}
+
+/// An API token, either a plain string with full admin access or a table restricting what it can
+/// do.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ApiTokenEntry {
+    Plain(String),
+    Restricted {
+        token: String,
+        /// Names of the images the token may create VMs from or switch VMs to
+        #[serde(default)]
+        allowed_images: Option<Vec<String>>,
+        /// Unix time in seconds from which the token is rejected
+        #[serde(default)]
+        expires_at: Option<u64>,
+        /// Whether the token may manage teepod itself, see [`AuthConfig::is_admin`]
+        #[serde(default)]
+        admin: bool,
+    },
+}
+
+impl ApiTokenEntry {
+    pub fn token(&self) -> &str {
+        match self {
+            Self::Plain(token) => token,
+            Self::Restricted { token, .. } => token,
+        }
+    }
+
+    pub fn allowed_images(&self) -> Option<&[String]> {
+        match self {
+            Self::Plain(_) => None,
+            Self::Restricted { allowed_images, .. } => allowed_images.as_deref(),
+        }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        match self {
+            Self::Plain(_) => true,
+            Self::Restricted { admin, .. } => *admin,
+        }
+    }
+
+    /// Whether the token has expired at unix time `now`.
+    pub fn expired(&self, now: u64) -> bool {
+        match self {
+            Self::Plain(_) => false,
+            Self::Restricted { expires_at, .. } => expires_at.is_some_and(|at| now >= at),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Default, Deserialize)]
@@ -126,14 +358,29 @@ pub struct GatewayConfig {
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
+    /// The config file teepod was started with, read again when reloading the API tokens
+    #[serde(skip)]
+    pub config_file: Option<String>,
     #[serde(default)]
     pub image_path: PathBuf,
+    /// Images never removed by image pruning
+    #[serde(default)]
+    pub protected_images: Vec<String>,
     #[serde(default)]
     pub run_path: PathBuf,
     #[serde(default)]
     pub qemu_path: PathBuf,
+    /// The directory compose files can be referenced from by `compose_file_ref`.
+    /// Referencing is disabled if empty.
+    #[serde(default)]
+    pub compose_dir: PathBuf,
     /// The URL of the KMS server
     pub kms_url: String,
+    /// KMS servers tried in order if `kms_url` can not be reached
+    #[serde(default)]
+    pub kms_fallback_urls: Vec<String>,
+    /// Retries of KMS calls failing with a transient error
+    pub kms_retry: KmsRetryConfig,
 
     /// CVM configuration
     pub cvm: CvmConfig,
@@ -151,6 +398,79 @@ pub struct Config {
 
     /// Host API configuration
     pub host_api: HostApiConfig,
+
+    /// Logging configuration
+    #[serde(default)]
+    pub logging: LoggingConfig,
+
+    /// Startup reconciliation configuration
+    pub startup: StartupConfig,
+
+    /// Timeouts of long running operations
+    pub timeouts: TimeoutsConfig,
+
+    /// Revocation checking of the KMS certificate
+    #[serde(default)]
+    pub kms_revocation: RevocationConfig,
+
+    /// Commands run on VM lifecycle events
+    pub hooks: HooksConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HooksConfig {
+    /// Shell command run after a VM stops or crashes, empty to disable.
+    /// TEEPOD_VM_ID, TEEPOD_APP_ID and TEEPOD_EXIT_CODE are set in its environment.
+    pub post_stop: String,
+    /// How often to check the VM processes for crashes. Stops requested through teepod run the
+    /// hook right away.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub poll_interval: Duration,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RevocationConfig {
+    /// Whether to refuse KMS peers whose certificate is revoked
+    pub enabled: bool,
+    /// File with one hex SHA-256 fingerprint of a revoked DER certificate per line
+    pub list_file: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct KmsRetryConfig {
+    /// Max number of retries, 0 to disable
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled on each retry
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub delay: Duration,
+    /// Max delay between retries
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub max_delay: Duration,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TimeoutsConfig {
+    /// Timeout of create_vm, the partially created VM is removed when exceeded
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub create_vm: Duration,
+    /// How long a graceful stop waits for the VM to power off before killing it
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub graceful_stop: Duration,
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    humantime::parse_duration(&s).map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StartupConfig {
+    /// Max number of VMs to start concurrently when teepod starts
+    pub concurrency: usize,
+    /// Max number of threads loading image metadata
+    pub image_scan_concurrency: usize,
+    /// Number of images loaded by a thread at a time
+    pub image_scan_batch_size: usize,
 }
 
 impl Config {
@@ -158,6 +478,11 @@ impl Config {
         Ok(Self {
             image_path: self.image_path.absolutize()?.to_path_buf(),
             run_path: self.run_path.absolutize()?.to_path_buf(),
+            compose_dir: if self.compose_dir == PathBuf::default() {
+                self.compose_dir
+            } else {
+                self.compose_dir.absolutize()?.to_path_buf()
+            },
             ..self
         })
     }
@@ -175,6 +500,43 @@ pub struct UserNetworking {
     pub net: String,
     pub dhcp_start: String,
     pub restrict: bool,
+    /// IPv6 prefix of the guest network, e.g. `fec0::/64`. Enables dual-stack when set.
+    #[serde(default)]
+    pub ipv6_net: Option<String>,
+    /// IPv6 address of the host in the guest network, must be within `ipv6_net`
+    #[serde(default)]
+    pub ipv6_host: Option<String>,
+}
+
+impl UserNetworking {
+    pub fn validate(&self) -> Result<()> {
+        let Some(net) = &self.ipv6_net else {
+            if self.ipv6_host.is_some() {
+                bail!("ipv6_host requires ipv6_net");
+            }
+            return Ok(());
+        };
+        let (prefix, len) = net
+            .split_once('/')
+            .with_context(|| format!("invalid ipv6_net {net}, expected <addr>/<len>"))?;
+        let prefix = Ipv6Addr::from_str(prefix)
+            .with_context(|| format!("invalid ipv6_net prefix {prefix}"))?;
+        let len: u32 = len
+            .parse()
+            .with_context(|| format!("invalid ipv6_net prefix length {len}"))?;
+        if len > 126 {
+            bail!("ipv6_net prefix length must be at most 126");
+        }
+        if let Some(host) = &self.ipv6_host {
+            let host =
+                Ipv6Addr::from_str(host).with_context(|| format!("invalid ipv6_host {host}"))?;
+            let mask = u128::MAX.checked_shl(128 - len).unwrap_or(0);
+            if u128::from(host) & mask != u128::from(prefix) & mask {
+                bail!("ipv6_host {host} is not in {net}");
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -188,6 +550,26 @@ pub struct HostApiConfig {
     pub port: u32,
 }
 
+/// Check that a configured URL is a http(s) URL and strip trailing slashes, so that paths can be
+/// appended to it. An empty URL means not configured and is kept as is.
+fn normalize_url(name: &str, url: &str) -> Result<String> {
+    let url = url.trim().trim_end_matches('/');
+    if url.is_empty() {
+        return Ok(String::new());
+    }
+    let Some((scheme, rest)) = url.split_once("://") else {
+        bail!("{name}: missing scheme in {url:?}, expected http:// or https://");
+    };
+    if !matches!(scheme.to_ascii_lowercase().as_str(), "http" | "https") {
+        bail!("{name}: unsupported scheme {scheme:?} in {url:?}");
+    }
+    let host = rest.split('/').next().unwrap_or_default();
+    if host.is_empty() || url.contains(char::is_whitespace) {
+        bail!("{name}: invalid URL {url:?}");
+    }
+    Ok(url.to_string())
+}
+
 impl Config {
     pub fn extract_or_default(figment: &Figment) -> Result<Self> {
         let mut me: Self = figment.extract()?;
@@ -207,6 +589,71 @@ impl Config {
                 me.qemu_path = qemu_path;
             }
         }
+        me.kms_url = normalize_url("kms_url", &me.kms_url)?;
+        me.kms_fallback_urls = me
+            .kms_fallback_urls
+            .iter()
+            .map(|url| normalize_url("kms_fallback_urls", url))
+            .collect::<Result<_>>()?;
+        me.cvm.kms_url = normalize_url("cvm.kms_url", &me.cvm.kms_url)?;
+        me.cvm.tproxy_url = normalize_url("cvm.tproxy_url", &me.cvm.tproxy_url)?;
+        me.cvm.docker_registry = normalize_url("cvm.docker_registry", &me.cvm.docker_registry)?;
+        if let Networking::User(netcfg) = &me.networking {
+            netcfg
+                .validate()
+                .context("Invalid user networking config")?;
+        }
+        me.cvm
+            .port_mapping
+            .normalize()
+            .context("Invalid port mapping config")?;
         Ok(me)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(protocol: Protocol, from: u16, to: u16) -> PortRange {
+        PortRange { protocol, from, to }
+    }
+
+    #[test]
+    fn test_port_range_validate() {
+        range(Protocol::Tcp, 1, 1).validate().unwrap();
+        range(Protocol::Udp, 443, 65535).validate().unwrap();
+
+        let err = range(Protocol::Tcp, 9000, 8000).validate().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "tcp port range 9000-8000: from is greater than to"
+        );
+        let err = range(Protocol::Udp, 0, 100).validate().unwrap_err();
+        assert_eq!(err.to_string(), "udp port range 0-100: ports start at 1");
+    }
+
+    #[test]
+    fn test_merge_port_ranges() {
+        let merged = merge_port_ranges(&[
+            range(Protocol::Tcp, 8000, 9000),
+            range(Protocol::Udp, 443, 443),
+            range(Protocol::Tcp, 1, 100),
+            range(Protocol::Tcp, 8500, 8600),
+            range(Protocol::Tcp, 9001, 9100),
+            range(Protocol::Udp, 440, 444),
+            range(Protocol::Tcp, 65000, 65535),
+            range(Protocol::Tcp, 65535, 65535),
+        ]);
+        assert_eq!(
+            merged,
+            [
+                range(Protocol::Tcp, 1, 100),
+                range(Protocol::Tcp, 8000, 9100),
+                range(Protocol::Tcp, 65000, 65535),
+                range(Protocol::Udp, 440, 444),
+            ]
+        );
+        assert!(merge_port_ranges(&[]).is_empty());
+    }
+}
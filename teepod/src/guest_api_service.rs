@@ -60,7 +60,7 @@ impl ProxiedGuestApiRpc for GuestApiHandler {
 
     async fn list_containers(self, request: Id) -> Result<ListContainersResponse> {
         self.tappd_client(&request.id)?
-            .list_containers()
+            .list_containers(Default::default())
             .await
             .map_err(Into::into)
     }
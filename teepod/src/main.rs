@@ -9,7 +9,6 @@ use rocket::{
     fairing::AdHoc,
     figment::{providers::Serialized, Figment},
 };
-use rocket_apitoken::ApiToken;
 use rocket_vsock_listener::VsockListener;
 use supervisor_client::SupervisorClient;
 
@@ -39,14 +38,25 @@ struct Args {
     /// Path to the configuration file
     #[arg(short, long)]
     config: Option<String>,
+
+    /// Print what reconciliation of the VMs at startup would do, then exit without doing it
+    #[arg(long)]
+    reconcile_dry_run: bool,
+
+    /// Remove the images not used by any VM, then exit
+    #[arg(long)]
+    prune_images: bool,
+
+    /// With --prune-images, only print the images that would be removed
+    #[arg(long, requires = "prune_images")]
+    dry_run: bool,
 }
 
-async fn run_external_api(app: App, figment: Figment, api_auth: ApiToken) -> Result<()> {
+async fn run_external_api(app: App, figment: Figment) -> Result<()> {
     let external_api = rocket::custom(figment)
         .mount("/", main_routes::routes())
         .mount("/guest", guest_api_routes::routes())
         .manage(app)
-        .manage(api_auth)
         .attach(AdHoc::on_response("Add app rev header", |_req, res| {
             Box::pin(async move {
                 res.set_raw_header("X-App-Version", app_version());
@@ -87,16 +97,11 @@ async fn run_host_api(app: App, figment: Figment) -> Result<()> {
 
 #[rocket::main]
 async fn main() -> Result<()> {
-    {
-        use tracing_subscriber::{fmt, EnvFilter};
-        let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
-        fmt().with_env_filter(filter).init();
-    }
-
     let args = Args::parse();
     let figment = config::load_config_figment(args.config.as_deref());
-    let config = Config::extract_or_default(&figment)?.abs_path()?;
-    let api_auth = ApiToken::new(config.auth.tokens.clone(), config.auth.enabled);
+    let mut config = Config::extract_or_default(&figment)?.abs_path()?;
+    config.config_file = args.config.clone();
+    config.logging.init()?;
     let supervisor = {
         let cfg = &config.supervisor;
         let abs_exe = Path::new(&cfg.exe).absolutize()?;
@@ -104,11 +109,29 @@ async fn main() -> Result<()> {
             .await
             .context("Failed to start supervisor")?
     };
-    let state = app::App::new(config, supervisor);
+    let state = app::App::new(config, supervisor).context("Failed to create app")?;
+    if args.reconcile_dry_run {
+        for step in state.reconcile_plan().await? {
+            println!(
+                "{} {} (priority={}): {}",
+                step.id, step.name, step.boot_priority, step.action
+            );
+        }
+        return Ok(());
+    }
+    if args.prune_images {
+        for image in state.prune_images(args.dry_run, &[])? {
+            println!("{image}");
+        }
+        return Ok(());
+    }
+    state.rescan_images().context("Failed to scan images")?;
     state.reload_vms().await.context("Failed to reload VMs")?;
+    tokio::spawn(state.clone().watch_vm_stops());
+    tokio::spawn(state.clone().purge_expired_vms());
 
     tokio::select! {
-        result = run_external_api(state.clone(), figment.clone(), api_auth) => {
+        result = run_external_api(state.clone(), figment.clone()) => {
             result.context("Failed to run external API")?;
         }
         result = run_host_api(state, figment) => {
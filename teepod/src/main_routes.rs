@@ -1,5 +1,6 @@
 use crate::app::App;
-use crate::main_service::RpcHandler;
+use crate::config::AuthConfig;
+use crate::main_service::{ApiCaller, RpcHandler};
 use anyhow::Result;
 use fs_err as fs;
 use ra_rpc::rocket_helper::PrpcHandler;
@@ -7,14 +8,16 @@ use rocket::{
     data::{Data, Limits},
     get,
     http::ContentType,
+    http::Status,
     mtls::Certificate,
     post,
+    request::{self, FromRequest},
     response::{status::Custom, stream::TextStream},
-    routes, Route, State,
+    routes, Request, Route, State,
 };
-use rocket_apitoken::Authorized;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::time::timeout;
 use tracing::{debug, info};
 
@@ -44,10 +47,44 @@ async fn res(path: &str) -> Result<(ContentType, String), Custom<String>> {
     }
 }
 
+/// A request whose bearer token is accepted by the auth config in effect.
+///
+/// The auth config is snapshotted when the guard runs, so a request keeps the permissions it was
+/// admitted with even if the tokens are reloaded while it is being handled.
+struct Authorized {
+    auth: Arc<AuthConfig>,
+    token: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Authorized {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let Some(app) = req.rocket().state::<App>() else {
+            return request::Outcome::Error((Status::InternalServerError, ()));
+        };
+        let token = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+        let auth = app.auth();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        if !auth.authorizes(token.as_deref(), now) {
+            return request::Outcome::Error((Status::Unauthorized, ()));
+        }
+        request::Outcome::Success(Authorized { auth, token })
+    }
+}
+
 #[post("/prpc/<method>?<json>", data = "<data>")]
 #[allow(clippy::too_many_arguments)]
 async fn prpc_post(
-    _auth: Authorized,
+    auth: Authorized,
     state: &State<App>,
     cert: Option<Certificate<'_>>,
     method: &str,
@@ -56,8 +93,13 @@ async fn prpc_post(
     content_type: Option<&ContentType>,
     json: bool,
 ) -> Custom<Vec<u8>> {
+    let caller = ApiCaller {
+        app: state.inner().clone(),
+        auth: auth.auth,
+        token: auth.token,
+    };
     PrpcHandler::builder()
-        .state(&**state)
+        .state(&caller)
         .maybe_certificate(cert)
         .method(method)
         .data(data)
@@ -71,14 +113,19 @@ async fn prpc_post(
 
 #[get("/prpc/<method>")]
 async fn prpc_get(
-    _auth: Authorized,
+    auth: Authorized,
     state: &State<App>,
     method: &str,
     limits: &Limits,
     content_type: Option<&ContentType>,
 ) -> Custom<Vec<u8>> {
+    let caller = ApiCaller {
+        app: state.inner().clone(),
+        auth: auth.auth,
+        token: auth.token,
+    };
     PrpcHandler::builder()
-        .state(&**state)
+        .state(&caller)
         .method(method)
         .limits(limits)
         .maybe_content_type(content_type)
@@ -132,6 +179,10 @@ fn vm_logs(
     let workdir = app.work_dir(&id);
     let ch = ch.unwrap_or("serial").to_string();
     TextStream! {
+        if !workdir.manifest_path().exists() {
+            yield format!("VM {id} not found");
+            return;
+        }
         let log_file = match ch.as_str() {
             "serial" => workdir.serial_file(),
             "stdout" => workdir.stdout_file(),
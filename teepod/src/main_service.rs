@@ -1,27 +1,228 @@
+use std::collections::HashMap;
 use std::ops::Deref;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, bail, Context, Result};
+use app_compose::{app_id_of, AppCompose, RESERVED_ENV_KEYS};
 use fs_err as fs;
-use ra_rpc::{CallContext, RpcCall};
+use ra_rpc::{client::is_transient_error, CallContext, RpcCall};
 use teepod_rpc::teepod_server::{TeepodRpc, TeepodServer};
 use teepod_rpc::{
-    AppId, GetInfoResponse, Id, ImageInfo as RpcImageInfo, ImageListResponse, PublicKeyResponse,
-    ResizeVmRequest, StatusResponse, UpgradeAppRequest, VersionResponse, VmConfiguration,
+    AppId, CapabilitiesResponse, Capability, CloneVmRequest, CordonRequest, GcVmsRequest,
+    GcVmsResponse, GetInfoResponse, Id, ImageInfo as RpcImageInfo, ImageListResponse,
+    LaunchLogRequest, LaunchLogResponse, ListImagesRequest, PortMapping as RpcPortMapping,
+    PruneImagesRequest, PruneImagesResponse, PublicKeyResponse, ReloadAuthResponse,
+    RenameVmRequest, ResizeVmRequest, RestartVmRequest, StatusResponse, StopVmRequest,
+    StopVmResponse, UpgradeAppRequest, VersionResponse, VmConfiguration, VmInfo, VmListResponse,
+    VmLogsRequest, VmLogsResponse, WaitVmRequest, WaitVmResponse,
 };
-use tracing::{info, warn};
+use tracing::{error, info, warn};
 
-use crate::app::{App, Manifest, PortMapping, VmWorkDir};
+use crate::app::{
+    check_extra_qemu_args, check_save_state_on_stop, qemu_img_path, resize_hd, App, ImageEntry,
+    ImageInfo, Manifest, PortMapping, VmWorkDir,
+};
+use crate::config::{AuthConfig, CvmConfig, Networking, PortMappingConfig, Protocol, RtcBase};
 
-fn hex_sha256(data: &str) -> String {
-    use sha2::Digest;
-    let mut hasher = sha2::Sha256::new();
-    hasher.update(data);
-    hex::encode(hasher.finalize())
+/// Resources given to a VM if neither the request nor the image's `vm_defaults` set them
+const DEFAULT_VCPU: u32 = 1;
+const DEFAULT_MEMORY_MB: u32 = 2048;
+const DEFAULT_DISK_SIZE_GB: u32 = 20;
+
+/// Upper bounds on the resources of a VM.
+struct ResourceLimits {
+    vcpu: u32,
+    memory: u32,
+    disk_size: u32,
+}
+
+impl ResourceLimits {
+    fn of(cfg: &CvmConfig) -> Self {
+        Self {
+            vcpu: cfg.max_allocable_vcpu,
+            memory: cfg.max_allocable_memory_in_mb,
+            disk_size: cfg.max_disk_size,
+        }
+    }
+
+    fn check(&self, vcpu: u32, memory: u32, disk_size: u32) -> Result<()> {
+        for (field, value, max, unit) in [
+            ("vcpu", vcpu, self.vcpu, ""),
+            ("memory", memory, self.memory, "MB"),
+            ("disk_size", disk_size, self.disk_size, "GB"),
+        ] {
+            if value == 0 {
+                bail!("{field} must not be zero");
+            }
+            if value > max {
+                bail!("{field} of {value}{unit} exceeds the max of {max}{unit}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Removes a partially created VM when dropped, unless disarmed.
+struct RollbackGuard {
+    app: App,
+    id: String,
+    armed: bool,
+}
+
+impl RollbackGuard {
+    fn new(app: App, id: String) -> Self {
+        Self {
+            app,
+            id,
+            armed: true,
+        }
+    }
+
+    fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for RollbackGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        let app = self.app.clone();
+        let id = std::mem::take(&mut self.id);
+        warn!("Rolling back VM {id}");
+        tokio::spawn(async move {
+            if let Err(err) = app.rollback_vm(&id).await {
+                error!("Failed to roll back VM {id}: {err:?}");
+            }
+        });
+    }
+}
+
+/// The state a teepod RPC call is made with: the app, the auth config the request was admitted
+/// with and the API token of the caller.
+pub struct ApiCaller {
+    pub app: App,
+    pub auth: Arc<AuthConfig>,
+    pub token: Option<String>,
 }
 
 pub struct RpcHandler {
     app: App,
+    /// Images the caller may use, None if unrestricted
+    allowed_images: Option<Vec<String>>,
+    /// Whether the caller may manage teepod itself
+    admin: bool,
+}
+
+impl RpcHandler {
+    /// Fill in the resources and ports a create request leaves out from the image's
+    /// `vm_defaults`. Fields set in the request take precedence.
+    fn apply_image_defaults(&self, request: &mut VmConfiguration) -> Result<()> {
+        let image_path = self.app.config.image_path.join(&request.image);
+        let info = ImageInfo::load(image_path.join("metadata.json"))
+            .context("Failed to load image info")?;
+        let Some(defaults) = info.vm_defaults else {
+            return Ok(());
+        };
+        let max_disk_size = self.app.config.cvm.max_disk_size;
+        if defaults.disk_size.is_some_and(|size| size > max_disk_size) {
+            bail!(
+                "image {} declares a default disk size above the max of {max_disk_size}GB",
+                request.image
+            );
+        }
+        let host_cpus = std::thread::available_parallelism().map_or(u32::MAX, |n| n.get() as u32);
+        if defaults.vcpu.is_some_and(|vcpu| vcpu > host_cpus) {
+            bail!(
+                "image {} declares more default vCPUs than the host has ({host_cpus})",
+                request.image
+            );
+        }
+        if request.vcpu == 0 {
+            request.vcpu = defaults.vcpu.unwrap_or_default();
+        }
+        if request.memory == 0 {
+            request.memory = defaults.memory.unwrap_or_default();
+        }
+        if request.disk_size == 0 {
+            request.disk_size = defaults.disk_size.unwrap_or_default();
+        }
+        if request.ports.is_empty() && !defaults.ports.is_empty() {
+            if self.app.config.cvm.port_mapping.enabled {
+                request.ports = defaults
+                    .ports
+                    .into_iter()
+                    .map(|p| RpcPortMapping {
+                        protocol: p.protocol,
+                        host_port: p.host_port,
+                        vm_port: p.vm_port,
+                    })
+                    .collect();
+            } else {
+                info!(
+                    "Port mapping is disabled, ignoring the ports suggested by image {}",
+                    request.image
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn check_payload_sizes(&self, compose_file: &str, encrypted_env: &[u8]) -> Result<()> {
+        let cfg = &self.app.config.cvm;
+        if compose_file.len() > cfg.max_compose_file_size {
+            bail!(
+                "payload too large: compose_file is {} bytes, the limit is {}",
+                compose_file.len(),
+                cfg.max_compose_file_size
+            );
+        }
+        if encrypted_env.len() > cfg.max_encrypted_env_size {
+            bail!(
+                "payload too large: encrypted_env is {} bytes, the limit is {}",
+                encrypted_env.len(),
+                cfg.max_encrypted_env_size
+            );
+        }
+        Ok(())
+    }
+
+    /// Write the work dir of a new VM and load it, removing it again on failure.
+    async fn provision_vm(&self, manifest: &Manifest, request: &VmConfiguration) -> Result<()> {
+        let id = &manifest.id;
+        // Roll back the partially created VM if we fail, time out or the client goes away.
+        let rollback = RollbackGuard::new(self.app.clone(), id.clone());
+        let timeout = self.app.config.timeouts.create_vm;
+        tokio::time::timeout(timeout, async {
+            let vm_work_dir = self.app.work_dir(id);
+            vm_work_dir
+                .put_manifest(manifest)
+                .context("Failed to write manifest")?;
+            let work_dir = self.prepare_work_dir(id, request)?;
+            if let Err(err) = vm_work_dir.set_started(true) {
+                warn!("Failed to set started: {}", err);
+            }
+            self.app
+                .load_vm(&work_dir, &Default::default(), true)
+                .await
+                .context("Failed to load VM")
+        })
+        .await
+        .map_err(|_| anyhow!("creating VM {id} timed out after {timeout:?}"))??;
+        rollback.disarm();
+        Ok(())
+    }
+
+    fn check_image_allowed(&self, image: &str) -> Result<()> {
+        if let Some(allowed) = &self.allowed_images {
+            if !allowed.iter().any(|name| name == image) {
+                bail!("image {image} is not allowed for this API token");
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Deref for RpcHandler {
@@ -32,17 +233,6 @@ impl Deref for RpcHandler {
     }
 }
 
-fn app_id_of(compose_file: &str) -> String {
-    fn truncate40(s: &str) -> &str {
-        if s.len() > 40 {
-            &s[..40]
-        } else {
-            s
-        }
-    }
-    truncate40(&hex_sha256(compose_file)).to_string()
-}
-
 /// Validate the label of the VM. Valid chars are alphanumeric, dash and underscore.
 fn validate_label(label: &str) -> Result<()> {
     if label
@@ -54,38 +244,235 @@ fn validate_label(label: &str) -> Result<()> {
     Ok(())
 }
 
+/// A UUID derived from the app id and a caller chosen instance key.
+///
+/// The app id is length prefixed so that no two pairs hash the same input.
+fn deterministic_vm_id(app_id: &str, instance_key: &str) -> String {
+    use sha2::Digest;
+    let mut hasher = sha2::Sha256::new();
+    hasher.update((app_id.len() as u64).to_be_bytes());
+    hasher.update(app_id);
+    hasher.update(instance_key);
+    let digest = hasher.finalize();
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    uuid::Builder::from_custom_bytes(bytes)
+        .into_uuid()
+        .to_string()
+}
+
+const MAX_ENV_ITEMS: usize = 1024;
+const MAX_ENV_TOTAL_SIZE: usize = 1024 * 1024;
+
+/// Validate all requested port mappings, reporting every offending mapping at once.
+///
+/// The error message is `invalid port mappings: <json>`, where the JSON is a list of
+/// `{"index", "protocol", "host_port", "vm_port", "reason"}` objects.
+fn validate_port_map(
+    pm_cfg: &PortMappingConfig,
+    ports: &[RpcPortMapping],
+    in_use: &HashMap<(String, u16), String>,
+) -> Result<Vec<PortMapping>> {
+    let mut port_map = vec![];
+    let mut errors = vec![];
+    let mut seen = HashMap::new();
+    for (index, p) in ports.iter().enumerate() {
+        let mut reasons = vec![];
+        let protocol = match p.protocol.parse::<Protocol>() {
+            Ok(protocol) => Some(protocol),
+            Err(err) => {
+                reasons.push(err.to_string());
+                None
+            }
+        };
+        let from = u16::try_from(p.host_port).ok();
+        if from.is_none() {
+            reasons.push("host port out of range".to_string());
+        }
+        let to = u16::try_from(p.vm_port).ok();
+        if to.is_none() {
+            reasons.push("vm port out of range".to_string());
+        }
+        if let (Some(protocol), Some(from)) = (&protocol, from) {
+            if !pm_cfg.is_allowed(protocol.as_str(), from) {
+                reasons.push("host port is not in the allowed range".to_string());
+            }
+            let key = (protocol.as_str().to_string(), from);
+            if let Some(other) = seen.insert(key.clone(), index) {
+                reasons.push(format!("host port is also mapped by ports[{other}]"));
+            }
+            if let Some(vm) = in_use.get(&key) {
+                reasons.push(format!("host port is already mapped by VM {vm}"));
+            }
+        }
+        match (protocol, from, to) {
+            (Some(protocol), Some(from), Some(to)) if reasons.is_empty() => {
+                port_map.push(PortMapping {
+                    address: pm_cfg.address,
+                    protocol,
+                    from,
+                    to,
+                });
+            }
+            _ => {
+                for reason in reasons {
+                    errors.push(serde_json::json!({
+                        "index": index,
+                        "protocol": p.protocol,
+                        "host_port": p.host_port,
+                        "vm_port": p.vm_port,
+                        "reason": reason,
+                    }));
+                }
+            }
+        }
+    }
+    if !errors.is_empty() {
+        bail!(
+            "invalid port mappings: {}",
+            serde_json::Value::Array(errors)
+        );
+    }
+    Ok(port_map)
+}
+
+const DEFAULT_WAIT_TIMEOUT_SECS: u32 = 60;
+const MAX_WAIT_TIMEOUT_SECS: u32 = 600;
+
+/// Describe an image entry, flagging images that failed to load with the error.
+fn image_info_pb(entry: &ImageEntry, detailed: bool) -> RpcImageInfo {
+    let mut info = RpcImageInfo {
+        name: entry.name.clone(),
+        metadata: entry.metadata.clone().filter(|_| detailed),
+        created_at_ms: entry
+            .created_at
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_millis() as u64),
+        size: entry.size,
+        ..Default::default()
+    };
+    match &entry.image {
+        Ok(image) => {
+            info.description = match &image.info.description {
+                Some(description) => description.clone(),
+                None => serde_json::to_string(&image.info).unwrap_or_default(),
+            };
+            info.version = image.info.version.clone();
+            info.is_dev = image.info.is_dev;
+            info.rootfs_hash = image.info.rootfs_hash.clone();
+        }
+        Err(err) => info.error = Some(format!("{err:?}")),
+    }
+    info
+}
+
+fn wait_timeout_secs(requested: u32) -> u32 {
+    match requested {
+        0 => DEFAULT_WAIT_TIMEOUT_SECS,
+        secs => secs.min(MAX_WAIT_TIMEOUT_SECS),
+    }
+}
+
+/// Apply the same limits as the guest does on environment variables.
+fn validate_plain_env(env: &HashMap<String, String>) -> Result<()> {
+    if env.len() > MAX_ENV_ITEMS {
+        bail!("Too many environment variables: {}", env.len());
+    }
+    let mut total_size = 0;
+    for (key, value) in env {
+        if key.len() > 255 {
+            bail!("Environment variable name too long: {key}");
+        }
+        if value.len() > 128 * 1024 {
+            bail!("Environment variable value too long for key: {key}");
+        }
+        let mut chars = key.chars();
+        let valid_start = chars
+            .next()
+            .map_or(false, |c| c.is_ascii_alphabetic() || c == '_');
+        if !valid_start || !chars.all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            bail!("Invalid env key: {key}");
+        }
+        if RESERVED_ENV_KEYS.contains(&key.as_str()) {
+            bail!("Reserved env key: {key}");
+        }
+        total_size += key.len() + value.len();
+    }
+    if total_size > MAX_ENV_TOTAL_SIZE {
+        bail!("Environment variables total size too large");
+    }
+    Ok(())
+}
+
 impl TeepodRpc for RpcHandler {
-    async fn create_vm(self, request: VmConfiguration) -> Result<Id> {
+    async fn create_vm(self, mut request: VmConfiguration) -> Result<Id> {
         validate_label(&request.name)?;
+        validate_plain_env(&request.env)?;
+        check_extra_qemu_args(&request.extra_qemu_args)?;
+        check_save_state_on_stop(request.save_state_on_stop.unwrap_or(false))?;
+        self.check_image_allowed(&request.image)?;
+        request.name = self.assign_vm_name(&request.name)?;
+
+        if let Some(compose_file_ref) = &request.compose_file_ref {
+            if !request.compose_file.is_empty() {
+                bail!("compose_file and compose_file_ref are mutually exclusive");
+            }
+            request.compose_file = self.read_compose_file_ref(compose_file_ref)?;
+        }
+        self.check_payload_sizes(&request.compose_file, &request.encrypted_env)?;
+        let app_compose = AppCompose::parse(&request.compose_file)?;
+        if !request.env.is_empty() && !app_compose.allow_plain_env {
+            bail!("the app compose does not allow plain env, set allow_plain_env to use it");
+        }
+        self.check_features(&request.compose_file)?;
+        self.apply_image_defaults(&mut request)?;
+        if request.vcpu == 0 {
+            request.vcpu = DEFAULT_VCPU;
+        }
+        if request.memory == 0 {
+            request.memory = DEFAULT_MEMORY_MB;
+        }
+        if request.disk_size == 0 {
+            request.disk_size = DEFAULT_DISK_SIZE_GB;
+        }
+        ResourceLimits::of(&self.app.config.cvm).check(
+            request.vcpu,
+            request.memory,
+            request.disk_size,
+        )?;
 
         let pm_cfg = &self.app.config.cvm.port_mapping;
         if !(request.ports.is_empty() || pm_cfg.enabled) {
             bail!("Port mapping is disabled");
         }
-        let port_map = request
-            .ports
-            .iter()
-            .map(|p| {
-                let from = p.host_port.try_into().context("Invalid host port")?;
-                let to = p.vm_port.try_into().context("Invalid vm port")?;
-                if !pm_cfg.is_allowed(&p.protocol, from) {
-                    bail!("Port mapping is not allowed for {}:{}", p.protocol, from);
-                }
-                let protocol = p.protocol.parse().context("Invalid protocol")?;
-                Ok(PortMapping {
-                    address: pm_cfg.address,
-                    protocol,
-                    from,
-                    to,
-                })
-            })
-            .collect::<Result<Vec<_>>>()?;
+        let port_map =
+            validate_port_map(pm_cfg, &request.ports, &self.app.mapped_host_ports(None))?;
+        let rtc_base = request
+            .rtc_base
+            .as_deref()
+            .map(str::parse::<RtcBase>)
+            .transpose()?;
+        if let Some(cid) = request.cid {
+            self.app.check_requested_cid(cid)?;
+        }
 
         let app_id = match &request.app_id {
             Some(id) => id.clone(),
             None => app_id_of(&request.compose_file),
         };
-        let id = uuid::Uuid::new_v4().to_string();
+        let id = match &request.instance_key {
+            Some(key) => {
+                if key.is_empty() {
+                    bail!("instance_key must not be empty");
+                }
+                let id = deterministic_vm_id(&app_id, key);
+                if self.app.work_dir(&id).exists() {
+                    bail!("VM {id} for instance {key} of app {app_id} already exists");
+                }
+                id
+            }
+            None => uuid::Uuid::new_v4().to_string(),
+        };
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap_or_default()
@@ -93,35 +480,81 @@ impl TeepodRpc for RpcHandler {
         let manifest = Manifest::builder()
             .id(id.clone())
             .name(request.name.clone())
-            .app_id(app_id.clone())
+            .app_id(app_id)
             .image(request.image.clone())
             .vcpu(request.vcpu)
             .memory(request.memory)
             .disk_size(request.disk_size)
             .port_map(port_map)
             .created_at_ms(now)
+            .boot_priority(request.boot_priority)
+            .maybe_expected_rootfs_hash(request.expected_rootfs_hash.clone())
+            .maybe_rtc_base(rtc_base)
+            .maybe_inject_host_time(request.inject_host_time)
+            .maybe_instance_key(request.instance_key.clone())
+            .maybe_cid(request.cid)
+            .extra_qemu_args(request.extra_qemu_args.clone())
             .build();
-        let vm_work_dir = self.app.work_dir(&id);
-        vm_work_dir
-            .put_manifest(&manifest)
-            .context("Failed to write manifest")?;
-        let work_dir = self.prepare_work_dir(&id, &request)?;
-        if let Err(err) = vm_work_dir.set_started(true) {
-            warn!("Failed to set started: {}", err);
-        }
+        self.provision_vm(&manifest, &request).await?;
+        Ok(Id { id })
+    }
 
-        let result = self
-            .app
-            .load_vm(&work_dir, &Default::default())
-            .await
-            .context("Failed to load VM");
-        if let Err(err) = result {
-            if let Err(err) = fs::remove_dir_all(&work_dir) {
-                warn!("Failed to remove work dir: {}", err);
-            }
-            return Err(err);
+    async fn clone_vm(self, request: CloneVmRequest) -> Result<Id> {
+        if self.app.vm_info(&request.id).await?.is_none() {
+            bail!("VM {} not found", request.id);
         }
+        let src_work_dir = self.app.work_dir(&request.id);
+        let src = src_work_dir
+            .manifest()
+            .context("Failed to read source manifest")?;
+        self.check_image_allowed(&src.image)?;
+        let name = request.name.unwrap_or_else(|| src.name.clone());
+        validate_label(&name)?;
+        let name = self.assign_vm_name(&name)?;
+
+        let compose_file = fs::read_to_string(src_work_dir.app_compose_path())
+            .context("Failed to read compose file")?;
+        let encrypted_env_path = src_work_dir.encrypted_env_path();
+        let encrypted_env = if encrypted_env_path.exists() {
+            fs::read(encrypted_env_path).context("Failed to read encrypted env")?
+        } else {
+            vec![]
+        };
+        let env = src_work_dir
+            .plain_env()
+            .context("Failed to read plain env")?;
+        // Only the app_id is carried over, the instance id is assigned to the clone on boot
+        let app_id = fs::read(src_work_dir.instance_info_path())
+            .ok()
+            .and_then(|data| serde_json::from_slice::<serde_json::Value>(&data).ok())
+            .and_then(|info| info["app_id"].as_str().map(str::to_string));
+        let config = VmConfiguration {
+            name: name.clone(),
+            image: src.image.clone(),
+            compose_file,
+            encrypted_env,
+            env,
+            app_id,
+            ..Default::default()
+        };
 
+        let id = uuid::Uuid::new_v4().to_string();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let manifest = Manifest {
+            id: id.clone(),
+            name,
+            port_map: vec![],
+            created_at_ms: now,
+            cordoned: false,
+            instance_key: None,
+            cid: None,
+            ..src
+        };
+        info!("Cloning VM {} into {id}", request.id);
+        self.provision_vm(&manifest, &config).await?;
         Ok(Id { id })
     }
 
@@ -133,11 +566,31 @@ impl TeepodRpc for RpcHandler {
         Ok(())
     }
 
-    async fn stop_vm(self, request: Id) -> Result<()> {
+    async fn stop_vm(self, request: StopVmRequest) -> Result<StopVmResponse> {
+        let forced = match request.mode.as_deref().unwrap_or("graceful") {
+            "graceful" => !self
+                .app
+                .stop_vm_gracefully(&request.id, self.app.config.timeouts.graceful_stop)
+                .await
+                .context("Failed to stop VM")?,
+            "force" => {
+                self.app
+                    .stop_vm(&request.id)
+                    .await
+                    .context("Failed to stop VM")?;
+                true
+            }
+            other => bail!("invalid stop mode: {other}"),
+        };
+        Ok(StopVmResponse { forced })
+    }
+
+    async fn restart_vm(self, request: RestartVmRequest) -> Result<()> {
+        let timeout_secs = wait_timeout_secs(request.timeout_secs);
         self.app
-            .stop_vm(&request.id)
+            .restart_vm(&request.id, Duration::from_secs(timeout_secs as u64))
             .await
-            .context("Failed to stop VM")?;
+            .context("Failed to restart VM")?;
         Ok(())
     }
 
@@ -149,48 +602,82 @@ impl TeepodRpc for RpcHandler {
         Ok(())
     }
 
+    async fn purge_vm(self, request: Id) -> Result<()> {
+        self.app
+            .purge_vm(&request.id)
+            .await
+            .context("Failed to purge VM")?;
+        Ok(())
+    }
+
     async fn status(self) -> Result<StatusResponse> {
         Ok(StatusResponse {
             vms: self.app.list_vms().await?,
             port_mapping_enabled: self.app.config.cvm.port_mapping.enabled,
+            run_path_usage: self.app.run_path_usage(),
+            vm_disks: self.app.vm_disk_usage(),
         })
     }
 
-    async fn list_images(self) -> Result<ImageListResponse> {
-        Ok(ImageListResponse {
-            images: self
-                .app
-                .list_images()?
-                .into_iter()
-                .map(|(name, info)| RpcImageInfo {
-                    name,
-                    description: serde_json::to_string(&info).unwrap_or_default(),
-                    version: info.version,
-                    is_dev: info.is_dev,
-                })
-                .collect(),
+    async fn get_vms_by_app_id(self, request: AppId) -> Result<VmListResponse> {
+        let vms = self
+            .app
+            .list_vms_where(|manifest| manifest.app_id == request.app_id)
+            .await?;
+        Ok(VmListResponse { vms })
+    }
+
+    async fn list_images(self, request: ListImagesRequest) -> Result<ImageListResponse> {
+        if request.rescan {
+            self.app.rescan_images()?;
+        }
+        let images = self
+            .app
+            .list_images_detailed()?
+            .iter()
+            .map(|entry| image_info_pb(entry, request.detailed))
+            .collect();
+        Ok(ImageListResponse { images })
+    }
+
+    async fn prune_images(self, request: PruneImagesRequest) -> Result<PruneImagesResponse> {
+        if !self.admin {
+            bail!("only admin API tokens can prune images");
+        }
+        let removed = self.app.prune_images(request.dry_run, &request.keep)?;
+        Ok(PruneImagesResponse { removed })
+    }
+
+    async fn reload_auth(self) -> Result<ReloadAuthResponse> {
+        if !self.admin {
+            bail!("only admin API tokens can reload auth");
+        }
+        let tokens = self.app.reload_auth()?;
+        Ok(ReloadAuthResponse {
+            tokens: tokens as u32,
         })
     }
 
+    async fn gc_vms(self, request: GcVmsRequest) -> Result<GcVmsResponse> {
+        if request.remove && !self.admin {
+            bail!("only admin API tokens can remove work dirs");
+        }
+        let work_dirs = self
+            .app
+            .gc_vms(request.remove)?
+            .into_iter()
+            .map(|orphan| teepod_rpc::OrphanedWorkDir {
+                id: orphan.id,
+                reason: orphan.reason,
+            })
+            .collect();
+        Ok(GcVmsResponse { work_dirs })
+    }
+
     async fn upgrade_app(self, request: UpgradeAppRequest) -> Result<Id> {
+        self.check_payload_sizes(&request.compose_file, &request.encrypted_env)?;
         let new_id = if !request.compose_file.is_empty() {
-            {
-                // check the compose file is valid
-                let todo = "import from external crate";
-                #[allow(dead_code)]
-                #[derive(serde::Deserialize)]
-                struct AppCompose {
-                    manifest_version: u32,
-                    name: String,
-                    runner: String,
-                    docker_compose_file: Option<String>,
-                }
-                let app_compose: AppCompose =
-                    serde_json::from_str(&request.compose_file).context("Invalid compose file")?;
-                if app_compose.docker_compose_file.is_none() {
-                    bail!("Docker compose file cannot be empty");
-                }
-            }
+            AppCompose::parse(&request.compose_file)?;
             let compose_file_path = self.compose_file_path(&request.id);
             if !compose_file_path.exists() {
                 bail!("The instance {} not found", request.id);
@@ -212,18 +699,43 @@ impl TeepodRpc for RpcHandler {
 
     async fn get_app_env_encrypt_pub_key(self, request: AppId) -> Result<PublicKeyResponse> {
         let kms = self.kms_client()?;
-        let response = kms
-            .get_app_env_encrypt_pub_key(kms_rpc::AppId {
-                app_id: request.app_id,
-            })
-            .await?;
+        let cfg = &self.app.config.kms_retry;
+        let mut delay = cfg.delay;
+        let mut attempt = 0;
+        let response = loop {
+            let result = kms
+                .get_app_env_encrypt_pub_key(kms_rpc::AppId {
+                    app_id: request.app_id.clone(),
+                })
+                .await;
+            match result {
+                Ok(response) => break response,
+                Err(err) if is_transient_error(&err) && attempt < cfg.max_retries => {
+                    attempt += 1;
+                    warn!(
+                        "failed to get app env pubkey from KMS, retrying in {delay:?} ({attempt}/{}): {err:?}",
+                        cfg.max_retries
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = delay.saturating_mul(2).min(cfg.max_delay);
+                }
+                Err(err) => return Err(err).context("Failed to get app env pubkey from KMS"),
+            }
+        };
         Ok(PublicKeyResponse {
             public_key: response.public_key,
         })
     }
 
     async fn get_info(self, request: Id) -> Result<GetInfoResponse> {
-        if let Some(vm) = self.app.vm_info(&request.id).await? {
+        let mut vm = self.app.vm_info(&request.id).await?;
+        if vm.is_none() {
+            // Fallback to lookup by name
+            if let Some(id) = self.app.find_vm_by_name(&request.id)? {
+                vm = self.app.vm_info(&id).await?;
+            }
+        }
+        if let Some(vm) = vm {
             Ok(GetInfoResponse {
                 found: true,
                 info: Some(vm),
@@ -260,48 +772,206 @@ impl TeepodRpc for RpcHandler {
             manifest.memory = memory;
         }
         if let Some(image) = request.image {
+            self.check_image_allowed(&image)?;
             manifest.image = image;
+            manifest.expected_rootfs_hash = request.expected_rootfs_hash;
+        } else if request.expected_rootfs_hash.is_some() {
+            bail!("expected_rootfs_hash requires image");
         }
+        ResourceLimits::of(&self.app.config.cvm).check(
+            manifest.vcpu,
+            manifest.memory,
+            request.disk_size.unwrap_or(manifest.disk_size),
+        )?;
         if let Some(disk_size) = request.disk_size {
-            let max_disk_size = self.app.config.cvm.max_disk_size;
-            if disk_size > max_disk_size {
-                bail!("Disk size is too large, max is {max_disk_size}GB");
-            }
             if disk_size < manifest.disk_size {
                 bail!("Cannot shrink disk size");
             }
-            manifest.disk_size = disk_size;
-
-            // Run qemu-img resize to resize the disk
-            info!("Resizing disk to {}GB", disk_size);
-            let hda_path = vm_work_dir.hda_path();
-            let new_size_str = format!("{}G", disk_size);
-            let output = std::process::Command::new("qemu-img")
-                .args(["resize", &hda_path.display().to_string(), &new_size_str])
-                .output()
+            if disk_size > manifest.disk_size {
+                info!("Resizing disk to {}GB", disk_size);
+                resize_hd(
+                    &qemu_img_path(&self.app.config.qemu_path),
+                    &vm_work_dir.hda_path(),
+                    disk_size,
+                )
                 .context("Failed to resize disk")?;
-            if !output.status.success() {
-                bail!(
-                    "Failed to resize disk: {}",
-                    String::from_utf8_lossy(&output.stderr)
-                );
             }
+            manifest.disk_size = disk_size;
         }
         vm_work_dir
             .put_manifest(&manifest)
             .context("failed to update manifest")?;
         self.app
-            .load_vm(work_dir, &Default::default())
+            .load_vm(work_dir, &Default::default(), true)
             .await
             .context("Failed to load VM")?;
         Ok(())
     }
 
+    async fn set_cordon(self, request: CordonRequest) -> Result<()> {
+        info!(
+            id = request.id,
+            cordoned = request.cordoned,
+            "Setting cordon"
+        );
+        self.app
+            .update_manifest(&request.id, |manifest| manifest.cordoned = request.cordoned)
+            .context("Failed to set cordon")?;
+        Ok(())
+    }
+
+    async fn rename_vm(self, request: RenameVmRequest) -> Result<()> {
+        if request.name.is_empty() {
+            bail!("Name cannot be empty");
+        }
+        validate_label(&request.name)?;
+        info!(id = request.id, name = request.name, "Renaming VM");
+        self.app
+            .rename_vm(&request.id, &request.name)
+            .context("Failed to rename VM")?;
+        Ok(())
+    }
+
     async fn shutdown_vm(self, request: Id) -> Result<()> {
         self.tappd_client(&request.id)?.shutdown().await?;
         Ok(())
     }
 
+    async fn get_launch_log(self, request: LaunchLogRequest) -> Result<LaunchLogResponse> {
+        self.app.launch_log(&request.id, request.max_bytes)
+    }
+
+    async fn get_vm_logs(self, request: VmLogsRequest) -> Result<VmLogsResponse> {
+        self.app
+            .vm_logs(&request.id, request.lines, request.offset, request.follow)
+            .await
+    }
+
+    async fn wait_vm(self, request: WaitVmRequest) -> Result<WaitVmResponse> {
+        let reached: fn(&VmInfo) -> bool = match request.target_state.as_str() {
+            "running" => |info| matches!(info.status.as_str(), "running" | "ready"),
+            "stopped" => |info| matches!(info.status.as_str(), "stopped" | "exited"),
+            "ready" => |info| info.status == "ready",
+            other => bail!("invalid target state: {other}"),
+        };
+        let timeout_secs = wait_timeout_secs(request.timeout_secs);
+        let (reached, info) = self
+            .app
+            .wait_vm(
+                &request.id,
+                Duration::from_secs(timeout_secs as u64),
+                reached,
+            )
+            .await?;
+        Ok(WaitVmResponse {
+            reached,
+            info: Some(info),
+        })
+    }
+
+    async fn capabilities(self) -> Result<CapabilitiesResponse> {
+        fn cap(name: &str, enabled: bool, limits: &[(&str, u64)]) -> Capability {
+            Capability {
+                name: name.to_string(),
+                enabled,
+                limits: limits.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+            }
+        }
+        let cfg = &self.app.config;
+        let (user_net, ipv6) = match &cfg.networking {
+            Networking::User(net) => (true, net.ipv6_net.is_some()),
+            Networking::Custom(_) => (false, false),
+        };
+        let capabilities = vec![
+            cap(
+                "vm",
+                true,
+                &[
+                    ("max_disk_size_gb", cfg.cvm.max_disk_size as u64),
+                    ("max_vcpu", cfg.cvm.max_allocable_vcpu as u64),
+                    ("max_memory_mb", cfg.cvm.max_allocable_memory_in_mb as u64),
+                    ("cid_pool_size", cfg.cvm.cid_pool_size as u64),
+                ],
+            ),
+            cap(
+                "port_mapping",
+                cfg.cvm.port_mapping.enabled,
+                &[("ranges", cfg.cvm.port_mapping.range.len() as u64)],
+            ),
+            cap(
+                "kms",
+                !cfg.kms_url.is_empty() && !cfg.cvm.kms_url.is_empty(),
+                &[("fallback_urls", cfg.kms_fallback_urls.len() as u64)],
+            ),
+            cap("kms_revocation", cfg.kms_revocation.enabled, &[]),
+            cap("tproxy", !cfg.cvm.tproxy_url.is_empty(), &[]),
+            cap("user_networking", user_net, &[]),
+            cap("ipv6", ipv6, &[]),
+            cap(
+                "compose_file_ref",
+                !cfg.compose_dir.as_os_str().is_empty(),
+                &[],
+            ),
+            cap("unique_names", cfg.cvm.unique_names, &[]),
+            cap(
+                "removal_grace_period",
+                !cfg.cvm.removal_grace_period.is_zero(),
+                &[("seconds", cfg.cvm.removal_grace_period.as_secs())],
+            ),
+            cap("post_stop_hook", !cfg.hooks.post_stop.is_empty(), &[]),
+            cap(
+                "plain_env",
+                true,
+                &[
+                    ("max_items", MAX_ENV_ITEMS as u64),
+                    ("max_total_size", MAX_ENV_TOTAL_SIZE as u64),
+                ],
+            ),
+            cap(
+                "payload_limits",
+                true,
+                &[
+                    (
+                        "max_compose_file_size",
+                        cfg.cvm.max_compose_file_size as u64,
+                    ),
+                    (
+                        "max_encrypted_env_size",
+                        cfg.cvm.max_encrypted_env_size as u64,
+                    ),
+                ],
+            ),
+            cap("expected_rootfs_hash", true, &[]),
+            cap("cordon", true, &[]),
+            cap("rename_vm", true, &[]),
+            cap("extra_qemu_args", true, &[]),
+            cap("save_state_on_stop", false, &[]),
+            cap(
+                "graceful_stop",
+                true,
+                &[("timeout_secs", cfg.timeouts.graceful_stop.as_secs())],
+            ),
+            cap("clone_vm", true, &[]),
+            cap("boot_priority", true, &[]),
+            cap("launch_log", true, &[]),
+            cap("vm_logs", true, &[("max_lines", 10000)]),
+            cap("gc_vms", true, &[]),
+            cap(
+                "restart_vm",
+                true,
+                &[("max_timeout_secs", MAX_WAIT_TIMEOUT_SECS as u64)],
+            ),
+            cap("auth", self.app.auth().enabled, &[]),
+            cap("reload_auth", true, &[]),
+            cap(
+                "create_vm_timeout",
+                true,
+                &[("seconds", cfg.timeouts.create_vm.as_secs())],
+            ),
+        ];
+        Ok(CapabilitiesResponse { capabilities })
+    }
+
     async fn version(self) -> Result<VersionResponse> {
         Ok(VersionResponse {
             version: crate::CARGO_PKG_VERSION.to_string(),
@@ -310,19 +980,233 @@ impl TeepodRpc for RpcHandler {
     }
 }
 
-impl RpcCall<App> for RpcHandler {
+impl RpcCall<ApiCaller> for RpcHandler {
     type PrpcService = TeepodServer<Self>;
 
     fn into_prpc_service(self) -> Self::PrpcService {
         TeepodServer::new(self)
     }
 
-    fn construct(context: CallContext<'_, App>) -> Result<Self>
+    fn construct(context: CallContext<'_, ApiCaller>) -> Result<Self>
     where
         Self: Sized,
     {
+        let ApiCaller { app, auth, token } = context.state;
+        let (allowed_images, admin) = match token {
+            Some(token) if auth.enabled => (
+                auth.allowed_images(token).map(|images| images.to_vec()),
+                auth.is_admin(token),
+            ),
+            _ => (None, !auth.enabled),
+        };
         Ok(RpcHandler {
-            app: context.state.clone(),
+            app: app.clone(),
+            allowed_images,
+            admin,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resource_limits() {
+        let limits = ResourceLimits {
+            vcpu: 8,
+            memory: 4096,
+            disk_size: 100,
+        };
+        limits.check(8, 4096, 100).unwrap();
+        limits.check(1, 1, 1).unwrap();
+
+        let err = limits.check(9, 4096, 100).unwrap_err().to_string();
+        assert_eq!(err, "vcpu of 9 exceeds the max of 8");
+        let err = limits.check(8, 4097, 100).unwrap_err().to_string();
+        assert_eq!(err, "memory of 4097MB exceeds the max of 4096MB");
+        let err = limits.check(8, 4096, 101).unwrap_err().to_string();
+        assert_eq!(err, "disk_size of 101GB exceeds the max of 100GB");
+        let err = limits.check(0, 4096, 100).unwrap_err().to_string();
+        assert_eq!(err, "vcpu must not be zero");
+    }
+
+    #[test]
+    fn test_image_info_pb() {
+        let tmp = tempfile::tempdir().unwrap();
+        let image_path = tmp.path();
+        let good = image_path.join("dstack-0.3.0");
+        fs::create_dir_all(&good).unwrap();
+        fs::write(good.join("bzImage"), [0u8; 100]).unwrap();
+        fs::write(good.join("initramfs.cpio.gz"), [0u8; 20]).unwrap();
+        let metadata = serde_json::json!({
+            "kernel": "bzImage",
+            "initrd": "initramfs.cpio.gz",
+            "rootfs_hash": "abcd",
+            "description": "test image",
+        })
+        .to_string();
+        fs::write(good.join("metadata.json"), &metadata).unwrap();
+        let broken = image_path.join("broken");
+        fs::create_dir_all(&broken).unwrap();
+        fs::write(broken.join("metadata.json"), "{").unwrap();
+
+        let info = image_info_pb(&ImageEntry::load(&good).unwrap(), false);
+        assert_eq!(info.name, "dstack-0.3.0");
+        assert_eq!(info.description, "test image");
+        assert_eq!(info.version, "0.3.0");
+        assert_eq!(info.rootfs_hash.as_deref(), Some("abcd"));
+        assert_eq!(info.size, 120 + metadata.len() as u64);
+        assert!(info.created_at_ms.is_some());
+        assert_eq!(info.metadata, None);
+        assert_eq!(info.error, None);
+
+        let info = image_info_pb(&ImageEntry::load(&broken).unwrap(), true);
+        assert_eq!(info.name, "broken");
+        assert_eq!(info.metadata.as_deref(), Some("{"));
+        assert!(info.error.unwrap().contains("failed to parse image info"));
+    }
+
+    #[test]
+    fn test_wait_timeout_secs() {
+        assert_eq!(wait_timeout_secs(0), DEFAULT_WAIT_TIMEOUT_SECS);
+        assert_eq!(wait_timeout_secs(5), 5);
+        assert_eq!(wait_timeout_secs(3600), MAX_WAIT_TIMEOUT_SECS);
+    }
+
+    #[test]
+    fn test_deterministic_vm_id() {
+        let id = deterministic_vm_id("app", "0");
+        assert_eq!(id, deterministic_vm_id("app", "0"));
+        assert!(uuid::Uuid::parse_str(&id).is_ok());
+        assert_ne!(id, deterministic_vm_id("app", "1"));
+        // The split between app id and instance key is part of the id
+        assert_ne!(
+            deterministic_vm_id("a:b", "c"),
+            deterministic_vm_id("a", "b:c")
+        );
+        assert_ne!(
+            deterministic_vm_id("ab", "c"),
+            deterministic_vm_id("a", "bc")
+        );
+    }
+
+    #[test]
+    fn test_validate_label() {
+        validate_label("my-app_2").unwrap();
+        for name in ["my app", "app.prod", "app/../x", "app;rm"] {
+            let err = validate_label(name).unwrap_err().to_string();
+            assert_eq!(err, format!("Invalid name: {name}"));
+        }
+    }
+
+    #[test]
+    fn test_plain_env() {
+        let env = |key: &str| HashMap::from([(key.to_string(), "value".to_string())]);
+        validate_plain_env(&env("LOG_LEVEL")).unwrap();
+        validate_plain_env(&env("_private")).unwrap();
+        let err = validate_plain_env(&env("1ABC")).unwrap_err().to_string();
+        assert_eq!(err, "Invalid env key: 1ABC");
+        let err = validate_plain_env(&env("LD_PRELOAD"))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "Reserved env key: LD_PRELOAD");
+    }
+
+    #[test]
+    fn test_overlapping_ports() {
+        let pm_cfg = PortMappingConfig {
+            enabled: true,
+            address: "127.0.0.1".parse().unwrap(),
+            range: vec![
+                crate::config::PortRange {
+                    protocol: Protocol::Tcp,
+                    from: 8000,
+                    to: 9000,
+                },
+                crate::config::PortRange {
+                    protocol: Protocol::Udp,
+                    from: 8000,
+                    to: 9000,
+                },
+            ],
+        };
+        let port = |host_port, vm_port| RpcPortMapping {
+            protocol: "tcp".into(),
+            host_port,
+            vm_port,
+        };
+        let mut in_use = HashMap::new();
+
+        let first = validate_port_map(&pm_cfg, &[port(8443, 443)], &in_use).unwrap();
+        for pm in &first {
+            in_use.insert(
+                (pm.protocol.as_str().to_string(), pm.from),
+                "vm-1".to_string(),
+            );
+        }
+
+        let err = validate_port_map(&pm_cfg, &[port(8080, 80), port(8443, 443)], &in_use)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("host port is already mapped by VM vm-1"),
+            "{err}"
+        );
+        assert!(err.contains(r#""index":1"#), "{err}");
+        // Another protocol on the same port does not conflict
+        let udp = RpcPortMapping {
+            protocol: "udp".into(),
+            ..port(8443, 443)
+        };
+        validate_port_map(&pm_cfg, &[udp], &in_use).unwrap();
+    }
+
+    #[test]
+    fn test_auth_expiry() {
+        let auth: AuthConfig = serde_json::from_value(serde_json::json!({
+            "enabled": true,
+            "tokens": [
+                "admin",
+                { "token": "tenant", "allowed_images": ["dstack-0.3.5"], "expires_at": 1000 },
+                { "token": "ci" },
+            ],
+        }))
+        .unwrap();
+        assert!(auth.authorizes(Some("admin"), u64::MAX));
+        assert!(auth.authorizes(Some("ci"), u64::MAX));
+        assert!(auth.authorizes(Some("tenant"), 999));
+        assert!(!auth.authorizes(Some("tenant"), 1000));
+        assert!(!auth.authorizes(Some("unknown"), 0));
+        assert!(!auth.authorizes(None, 0));
+
+        let disabled = AuthConfig::default();
+        assert!(disabled.authorizes(None, u64::MAX));
+    }
+
+    #[test]
+    fn test_auth_admin() {
+        let auth: AuthConfig = serde_json::from_value(serde_json::json!({
+            "enabled": true,
+            "tokens": [
+                "admin",
+                { "token": "ops", "admin": true, "expires_at": 1000 },
+                { "token": "ci" },
+                { "token": "tenant", "allowed_images": ["dstack-0.3.5"] },
+            ],
+        }))
+        .unwrap();
+        assert!(auth.is_admin("admin"));
+        assert!(auth.is_admin("ops"));
+        // Unrestricted is not the same as admin
+        assert!(!auth.is_admin("ci"));
+        assert!(!auth.is_admin("tenant"));
+        assert!(!auth.is_admin("unknown"));
+        assert!(!auth.is_admin("admi"));
+        assert_eq!(auth.allowed_images("ci"), None);
+        assert_eq!(
+            auth.allowed_images("tenant"),
+            Some(&["dstack-0.3.5".to_string()][..])
+        );
+    }
+}
@@ -0,0 +1,20 @@
+#!/bin/bash
+# Usage: wait-vm.sh <vm-id> [running|stopped|ready] [timeout-secs]
+VM_ID=${1}
+TARGET_STATE=${2:-running}
+TIMEOUT=${3:-60}
+TEEPOD_URL=${TEEPOD_URL:-http://localhost:8080}
+URL=${TEEPOD_URL}/prpc/Teepod.WaitVm?json
+
+if [ -z "$VM_ID" ]; then
+    echo "Usage: $0 <vm-id> [running|stopped|ready] [timeout-secs]"
+    exit 1
+fi
+
+D="{\"id\": \"${VM_ID}\", \"target_state\": \"${TARGET_STATE}\", \"timeout_secs\": ${TIMEOUT}}"
+
+if [ -z "$TEEPOD_API_TOKEN" ]; then
+    curl -s -d "${D}" ${URL}
+else
+    curl -s -H "Authorization: Bearer ${TEEPOD_API_TOKEN}" -d "${D}" ${URL}
+fi
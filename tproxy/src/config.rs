@@ -1,5 +1,6 @@
 use anyhow::{anyhow, bail, Result};
 use ipnet::Ipv4Net;
+use log_config::LoggingConfig;
 use rocket::figment::{
     providers::{Format, Toml},
     Figment,
@@ -131,6 +132,8 @@ pub struct Config {
     pub recycle: RecycleConfig,
     pub state_path: String,
     pub set_ulimit: bool,
+    #[serde(default)]
+    pub logging: LoggingConfig,
 }
 
 pub const CONFIG_FILENAME: &str = "tproxy.toml";
@@ -40,18 +40,13 @@ fn set_max_ulimit() -> Result<()> {
 
 #[rocket::main]
 async fn main() -> Result<()> {
-    {
-        use tracing_subscriber::{fmt, EnvFilter};
-        let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
-        fmt().with_env_filter(filter).init();
-    }
-
     let _ = rustls::crypto::ring::default_provider().install_default();
 
     let args = Args::parse();
     let figment = config::load_config_figment(args.config.as_deref());
 
     let config = figment.focus("core").extract::<Config>()?;
+    config.logging.init()?;
     config::setup_wireguard(&config.wg)?;
 
     #[cfg(unix)]